@@ -0,0 +1,272 @@
+//! An asynchronous counterpart to `Session`, built on tokio.
+//!
+//! Every payload connection is serviced by its own task, so a process which doesn't send anything never holds up the
+//! events of the other processes in the session.
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_core::Stream;
+use tokio::{
+    io::Empty,
+    net::windows::named_pipe::{NamedPipeServer, ServerOptions},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Mutex as AsyncMutex,
+    },
+    task, time,
+};
+
+use asbestos_shared::{
+    named_pipe_name,
+    protocol::{AsyncConnection, Message, ProcessSpawned, ProtocolError, StartupInfo},
+    PipeEnd,
+};
+
+use crate::{
+    process,
+    session::{Event, EventKind, SessionBuilder, SessionConfig, SessionError},
+    tree::ProcessTree,
+};
+
+pub type AsyncPipeConnection = AsyncConnection<NamedPipeServer, NamedPipeServer>;
+
+/// The halves of the connections which asbestos writes to, by pid.
+type Writers = Arc<AsyncMutex<HashMap<u32, AsyncConnection<Empty, NamedPipeServer>>>>;
+
+/// A set of hooked processes, whose `Event`s are delivered as a `Stream`.
+///
+/// The stream ends once every process has disconnected and terminated.
+pub struct AsyncSession {
+    events: UnboundedReceiver<Event>,
+    tree: Arc<Mutex<ProcessTree>>,
+    writers: Writers,
+}
+
+impl AsyncSession {
+    /// Ask the payload in the given process to disable its hooks and unload itself.
+    ///
+    /// The process keeps running. Its payload sends `EventKind::ProcessDetach` once it is about to be unloaded.
+    pub async fn detach(&self, pid: u32) -> Result<(), SessionError> {
+        match self.writers.lock().await.get_mut(&pid) {
+            Some(writer) => Ok(writer.write_message(Message::Detach).await?),
+            None => Err(ProtocolError::Disconnected.into()),
+        }
+    }
+
+    /// Ask every payload to unload itself, returning the processes which couldn't be asked.
+    pub async fn detach_all(&self) -> Vec<(u32, SessionError)> {
+        let mut failed = Vec::new();
+        for (&pid, writer) in self.writers.lock().await.iter_mut() {
+            if let Err(err) = writer.write_message(Message::Detach).await {
+                failed.push((pid, err.into()));
+            }
+        }
+        failed
+    }
+
+    /// A snapshot of the processes in the session, including those which have terminated.
+    ///
+    /// Unlike `Session::tree`, the snapshot may reflect events which haven't been yielded yet.
+    pub fn tree(&self) -> ProcessTree {
+        match self.tree.lock() {
+            Ok(tree) => tree.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+impl SessionBuilder {
+    /// Spawn the target process if need be, and inject the payload into it.
+    ///
+    /// This must be called from within a tokio runtime.
+    pub async fn start_async(self) -> Result<AsyncSession, SessionError> {
+        let (config, root) = self.launch()?;
+        let pid = root.pid;
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let tree = Arc::new(Mutex::new(ProcessTree::new()));
+        let writers = Writers::default();
+        let shared = Arc::new(Shared {
+            config,
+            events: events_tx,
+            tree: tree.clone(),
+            writers: writers.clone(),
+        });
+
+        let (profile, startup_info) = shared.config.startup_info(
+            0,
+            root.main_thread_suspended,
+            0,
+            &root.executable,
+            &root.command_line,
+        );
+        shared.send(pid, EventKind::MappingProfileSelected { profile });
+        let connection = inject_and_connect(&shared, pid, startup_info).await?;
+        tokio::spawn(watch(shared.clone(), pid));
+        tokio::spawn(service(shared, pid, connection));
+
+        Ok(AsyncSession {
+            events,
+            tree,
+            writers,
+        })
+    }
+}
+
+impl Stream for AsyncSession {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// The state shared between the tasks of an `AsyncSession`.
+///
+/// The stream of events ends once the last task drops its `Shared`.
+struct Shared {
+    config: SessionConfig,
+    events: UnboundedSender<Event>,
+    tree: Arc<Mutex<ProcessTree>>,
+    writers: Writers,
+}
+
+impl Shared {
+    fn send(&self, pid: u32, kind: EventKind) {
+        let event = Event { pid, kind };
+        if let Ok(mut tree) = self.tree.lock() {
+            tree.apply(&event);
+        }
+        // The receiving end is only gone if the `AsyncSession` has been dropped, in which case nobody is interested.
+        self.events.send(event).ok();
+    }
+}
+
+/// Report the termination of a process.
+async fn watch(shared: Arc<Shared>, pid: u32) {
+    let exit_code = task::spawn_blocking(move || process::wait_for_exit(pid))
+        .await
+        .ok()
+        .flatten();
+    shared.send(pid, EventKind::ProcessExited { exit_code });
+}
+
+/// Decide whether to hook a subprocess of `parent`, and if so, inject the payload into it and service its connection.
+async fn attach(shared: Arc<Shared>, parent: u32, ps: ProcessSpawned) {
+    let pid = ps.pid;
+    let depth = match shared.tree.lock() {
+        Ok(tree) => tree.depth(parent) + 1,
+        Err(_) => 1,
+    };
+    let verdict = shared.config.verdict(&ps, depth);
+    let hook = verdict.hook;
+    shared.send(pid, EventKind::SubprocessVerdict(verdict));
+
+    if hook {
+        let (profile, startup_info) = shared.config.startup_info(
+            ps.tid,
+            ps.suspended,
+            depth,
+            &ps.executable,
+            &ps.command_line,
+        );
+        shared.send(pid, EventKind::MappingProfileSelected { profile });
+        match inject_and_connect(&shared, pid, startup_info).await {
+            Ok(connection) => service(shared, pid, connection).await,
+            Err(err) => shared.send(pid, EventKind::InjectionFailed(err)),
+        }
+    } else if ps.suspended {
+        if let Err(err) = process::resume_thread(ps.tid) {
+            shared.send(pid, EventKind::ResumeFailed(err));
+        }
+    }
+}
+
+/// Forward the messages from a single payload until it disconnects.
+async fn service(shared: Arc<Shared>, pid: u32, connection: AsyncPipeConnection) {
+    let (mut reader, writer) = connection.split();
+    shared.writers.lock().await.insert(pid, writer);
+
+    loop {
+        match reader.read_message().await {
+            Ok(msg) => {
+                let spawned = match &msg {
+                    Message::ProcessSpawned(ps) => Some(ps.clone()),
+                    _ => None,
+                };
+                let detached = matches!(msg, Message::ProcessDetach);
+                if let Some(kind) = EventKind::from_message(msg) {
+                    shared.send(pid, kind);
+                }
+                // Attached only after the `ProcessSpawned` event has been sent, so that the subprocess's events are
+                // never seen before it is known to be a subprocess.
+                if let Some(ps) = spawned {
+                    tokio::spawn(watch(shared.clone(), ps.pid));
+                    tokio::spawn(attach(shared.clone(), pid, ps));
+                }
+                if detached {
+                    break;
+                }
+            }
+            Err(err) => {
+                let disconnected = matches!(err, ProtocolError::Disconnected);
+                shared.send(pid, EventKind::ProtocolError(err));
+                if disconnected {
+                    break;
+                }
+            }
+        }
+    }
+
+    shared.writers.lock().await.remove(&pid);
+}
+
+async fn inject_and_connect(
+    shared: &Shared,
+    pid: u32,
+    startup_info: StartupInfo,
+) -> Result<AsyncPipeConnection, SessionError> {
+    let dll = shared.config.payload.clone();
+    let timeout_ms = shared.config.options.connect_timeout_ms;
+
+    let server_rx = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(named_pipe_name(pid, PipeEnd::Rx))?;
+    let server_tx = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(named_pipe_name(pid, PipeEnd::Tx))?;
+
+    let injection = task::spawn_blocking(move || {
+        syringe::inject_dll(pid, &dll).map_err(|err| format!("{:?}", err))
+    });
+
+    let connect = async {
+        server_rx.connect().await?;
+        server_tx.connect().await?;
+        Ok::<_, SessionError>(())
+    };
+    time::timeout(Duration::from_millis(timeout_ms.into()), connect)
+        .await
+        .map_err(|_| SessionError::ConnectTimeout { pid, timeout_ms })??;
+
+    let mut connection = AsyncConnection::new(server_rx, server_tx);
+    connection
+        .write_message(Message::StartupInfo(startup_info))
+        .await?;
+    match injection.await {
+        Ok(Ok(_)) => {}
+        Ok(Err(err)) => return Err(SessionError::Injection(err)),
+        Err(_) => {
+            return Err(SessionError::Injection(
+                "The injection task panicked".to_owned(),
+            ))
+        }
+    }
+
+    Ok(connection)
+}
@@ -1 +1,40 @@
+//! Drive asbestos from your own program.
+//!
+//! A `Session` spawns or attaches to a process, injects the payload into it and into any subprocesses it creates,
+//! and yields the `Event`s sent by the payloads. With the `async` feature enabled, `SessionBuilder::start_async`
+//! returns an `AsyncSession` which yields the same events as a `Stream`. Either kind of session keeps track of its
+//! processes in a `ProcessTree`.
+//!
+//! Injecting the payload is only possible on Windows. On Linux, `SessionBuilder::start_ptrace` returns a
+//! `PtraceSession`, which applies the mappings by tracing the system calls of its processes instead.
+//! `SessionBuilder::start_seccomp` returns a `SeccompSession`, which carries out the system calls taking paths on
+//! behalf of the processes it spawns, and stops them far less often.
+
 pub use asbestos_shared as shared;
+
+#[cfg(all(windows, feature = "async"))]
+pub use async_session::AsyncSession;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub use ptrace::PtraceSession;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub use seccomp::SeccompSession;
+#[cfg(windows)]
+pub use session::Session;
+pub use session::{Event, EventKind, SessionBuilder, SessionError, SessionOptions, Target};
+pub use tree::{ProcessNode, ProcessTree};
+
+#[cfg(all(windows, feature = "async"))]
+mod async_session;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod linux;
+#[cfg(any(windows, test))]
+#[cfg_attr(not(windows), allow(dead_code))]
+mod mux;
+#[cfg(windows)]
+mod process;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod ptrace;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod seccomp;
+mod session;
+mod tree;
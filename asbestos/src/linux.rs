@@ -0,0 +1,813 @@
+//! What the Linux backends have in common: keeping track of the processes in a session, reading paths out of their
+//! memory, and the seccomp filter which picks out the system calls taking paths.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::{CString, OsStr, OsString},
+    fs, io, iter, mem,
+    os::{
+        raw::{c_int, c_void},
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
+    path::{Component, Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use asbestos_shared::{
+    archive::ArchiveError,
+    cache::ContentCache,
+    contents::Contents,
+    log_error, log_info,
+    profiles::MappingProfiles,
+    protocol::{LogLevel, LogMessage, Mappings, ProcessInfo, ProcessSpawned},
+    rules::Verdict,
+    vfs::map_path,
+};
+
+use crate::{
+    session::{Event, EventKind, SessionOptions},
+    tree::ProcessTree,
+};
+
+/// The longest path read from a process.
+const PATH_MAX: usize = 4096;
+
+/// System call numbers on x86_64, and the arguments holding the paths they take.
+pub(crate) mod sys {
+    pub const OPEN: i64 = 2;
+    pub const STAT: i64 = 4;
+    pub const LSTAT: i64 = 6;
+    pub const ACCESS: i64 = 21;
+    pub const EXECVE: i64 = 59;
+    pub const TRUNCATE: i64 = 76;
+    pub const CHDIR: i64 = 80;
+    pub const READLINK: i64 = 89;
+    pub const GETXATTR: i64 = 191;
+    pub const LGETXATTR: i64 = 192;
+    pub const LISTXATTR: i64 = 194;
+    pub const LLISTXATTR: i64 = 195;
+    pub const OPENAT: i64 = 257;
+    pub const NEWFSTATAT: i64 = 262;
+    pub const READLINKAT: i64 = 267;
+    pub const FACCESSAT: i64 = 269;
+    pub const EXECVEAT: i64 = 322;
+    pub const STATX: i64 = 332;
+    pub const OPENAT2: i64 = 437;
+    pub const FACCESSAT2: i64 = 439;
+
+    pub const SECCOMP: i64 = 317;
+
+    /// Every system call taking a path.
+    pub const PATHS: [i64; 20] = [
+        OPEN, STAT, LSTAT, ACCESS, EXECVE, TRUNCATE, CHDIR, READLINK, GETXATTR, LGETXATTR,
+        LISTXATTR, LLISTXATTR, OPENAT, NEWFSTATAT, READLINKAT, FACCESSAT, EXECVEAT, STATX, OPENAT2,
+        FACCESSAT2,
+    ];
+
+    /// Where a system call takes its path from: the index of the directory file descriptor argument, if any, and the
+    /// index of the path argument.
+    pub fn path_argument(nr: i64) -> Option<(Option<usize>, usize)> {
+        match nr {
+            OPEN | STAT | LSTAT | ACCESS | EXECVE | TRUNCATE | CHDIR | READLINK | GETXATTR
+            | LGETXATTR | LISTXATTR | LLISTXATTR => Some((None, 0)),
+            OPENAT | NEWFSTATAT | READLINKAT | FACCESSAT | EXECVEAT | STATX | OPENAT2
+            | FACCESSAT2 => Some((Some(0), 1)),
+            _ => None,
+        }
+    }
+}
+
+/// Seccomp filters, which the version of `libc` used here has no definitions for.
+pub(crate) mod filter {
+    #[repr(C)]
+    pub struct SockFilter {
+        pub code: u16,
+        pub jt: u8,
+        pub jf: u8,
+        pub k: u32,
+    }
+
+    #[repr(C)]
+    pub struct SockFprog {
+        pub len: u16,
+        pub filter: *const SockFilter,
+    }
+
+    pub const SET_MODE_FILTER: u32 = 1;
+    pub const FLAG_NEW_LISTENER: u32 = 1 << 3;
+    pub const RET_ALLOW: u32 = 0x7fff_0000;
+    pub const RET_TRACE: u32 = 0x7ff0_0000;
+    pub const RET_USER_NOTIF: u32 = 0x7fc0_0000;
+    const RET_ERRNO: u32 = 0x0005_0000;
+    pub const AUDIT_ARCH_X86_64: u32 = 0xc000_003e;
+    /// Set in the numbers of the system calls of the x32 ABI, which share the architecture of x86-64.
+    pub const X32_SYSCALL_BIT: u64 = 0x4000_0000;
+
+    const LD_W_ABS: u16 = 0x20;
+    const JEQ_K: u16 = 0x15;
+    const JGE_K: u16 = 0x35;
+    const RET_K: u16 = 0x06;
+
+    /// Offsets into `struct seccomp_data`.
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+
+    /// A filter which returns `action` for the given system calls, and lets every other system call through.
+    ///
+    /// System calls of other ABIs, such as those of 32-bit programs, take their paths in other places and fail with
+    /// `ENOSYS` instead, since letting them through would bypass the mappings.
+    pub fn filter(syscalls: &[i64], action: u32) -> Vec<SockFilter> {
+        let n = syscalls.len();
+        let enosys = RET_ERRNO | libc::ENOSYS as u32;
+        let mut filter = vec![
+            statement(LD_W_ABS, ARCH),
+            jump(JEQ_K, AUDIT_ARCH_X86_64, 1, 0),
+            statement(RET_K, enosys),
+            statement(LD_W_ABS, NR),
+            jump(JGE_K, X32_SYSCALL_BIT as u32, 0, 1),
+            statement(RET_K, enosys),
+        ];
+        for (i, nr) in syscalls.iter().enumerate() {
+            // Jumps over the remaining comparisons and the `RET_ALLOW` to the final `action`.
+            filter.push(jump(JEQ_K, *nr as u32, (n - i) as u8, 0));
+        }
+        filter.push(statement(RET_K, RET_ALLOW));
+        filter.push(statement(RET_K, action));
+        filter
+    }
+
+    fn statement(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+}
+
+/// The receiving end of a Linux session, which keeps track of the processes as their events are returned.
+pub(crate) struct EventQueue {
+    events: Receiver<Event>,
+    /// Events which originate in the session itself rather than in the thread supervising the processes.
+    pending: VecDeque<Event>,
+    tree: ProcessTree,
+    /// The processes whose system calls are currently being rewritten.
+    hooked: HashSet<u32>,
+    finished: bool,
+}
+
+impl EventQueue {
+    pub(crate) fn new(events: Receiver<Event>) -> Self {
+        Self {
+            events,
+            pending: VecDeque::new(),
+            tree: ProcessTree::new(),
+            hooked: HashSet::new(),
+            finished: false,
+        }
+    }
+
+    pub(crate) fn push(&mut self, event: Event) {
+        self.pending.push_back(event);
+    }
+
+    pub(crate) fn pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.hooked.iter().copied()
+    }
+
+    pub(crate) fn tree(&self) -> &ProcessTree {
+        &self.tree
+    }
+
+    pub(crate) fn is_finished(&self) -> bool {
+        self.pending.is_empty() && self.finished
+    }
+
+    pub(crate) fn next(&mut self, timeout: Option<Duration>) -> Option<Event> {
+        let event = match self.pending.pop_front() {
+            Some(event) => event,
+            None => {
+                let res = match timeout {
+                    Some(timeout) => self.events.recv_timeout(timeout),
+                    None => self
+                        .events
+                        .recv()
+                        .map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match res {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => return None,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        self.finished = true;
+                        return None;
+                    }
+                }
+            }
+        };
+
+        match &event.kind {
+            EventKind::Initialized => {
+                self.hooked.insert(event.pid);
+            }
+            EventKind::ProcessDetach | EventKind::ProcessExited { .. } => {
+                self.hooked.remove(&event.pid);
+            }
+            _ => {}
+        }
+        self.tree.apply(&event);
+        Some(event)
+    }
+}
+
+struct Process {
+    depth: u32,
+    /// Whether the process's system calls are rewritten, according to `SessionOptions::subprocess_rules`.
+    hooked: bool,
+    profile: Option<String>,
+    mappings: Mappings,
+}
+
+/// The processes of a session, as seen by the thread supervising them. Sends the events describing them.
+pub(crate) struct Processes {
+    mappings: MappingProfiles,
+    /// The archives and patches used by any of the mappings.
+    contents: Contents,
+    /// Why some of the archives couldn't be indexed, which is reported once the first process has been added.
+    archive_errors: Vec<ArchiveError>,
+    options: SessionOptions,
+    events: Sender<Event>,
+    processes: HashMap<u32, Process>,
+}
+
+impl Processes {
+    pub(crate) fn new(
+        mappings: MappingProfiles,
+        options: SessionOptions,
+        events: Sender<Event>,
+    ) -> Self {
+        let (contents, archive_errors) = Contents::index(
+            mappings.mappings.iter().chain(
+                mappings
+                    .profiles
+                    .iter()
+                    .flat_map(|profile| &profile.mappings),
+            ),
+            ContentCache::new(&options.cache),
+        );
+        Self {
+            mappings,
+            contents,
+            archive_errors,
+            options,
+            events,
+            processes: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn contains(&self, pid: u32) -> bool {
+        self.processes.contains_key(&pid)
+    }
+
+    /// Add the process the session started out with, which is always hooked.
+    pub(crate) fn add_root(&mut self, pid: u32) {
+        let (executable, command_line) = describe(pid);
+        let (profile, mappings) = self.mappings.select(&executable, &command_line, 0);
+        let profile = profile.map(str::to_owned);
+        self.send(
+            pid,
+            EventKind::MappingProfileSelected {
+                profile: profile.clone(),
+            },
+        );
+        self.send(
+            pid,
+            EventKind::ProcessInfo(ProcessInfo {
+                executable: executable.into(),
+                command_line,
+            }),
+        );
+        self.send(pid, EventKind::Initialized);
+        let archive_errors = mem::take(&mut self.archive_errors);
+        let mut log = self.log(pid);
+        for err in archive_errors {
+            log_error!(log, "{}", err).ok();
+        }
+        self.processes.insert(
+            pid,
+            Process {
+                depth: 0,
+                hooked: true,
+                profile,
+                mappings,
+            },
+        );
+    }
+
+    /// Add a process created by one of the processes, and decide whether to hook it.
+    pub(crate) fn add_subprocess(&mut self, pid: u32, tid: u32) {
+        let parent = status_field(pid, "PPid:").unwrap_or(0);
+        let (executable, command_line) = describe(pid);
+        self.send(
+            parent,
+            EventKind::ProcessSpawned(ProcessSpawned {
+                pid,
+                tid,
+                executable: executable.clone(),
+                command_line: command_line.clone(),
+                suspended: false,
+            }),
+        );
+        let (depth, profile, mappings) = match self.processes.get(&parent) {
+            // A forked process runs the same program as its parent until it executes another one.
+            Some(parent) => (
+                parent.depth + 1,
+                parent.profile.clone(),
+                parent.mappings.clone(),
+            ),
+            None => {
+                let (profile, mappings) = self.mappings.select(&executable, &command_line, 1);
+                (1, profile.map(str::to_owned), mappings)
+            }
+        };
+        self.processes.insert(
+            pid,
+            Process {
+                depth,
+                hooked: false,
+                profile,
+                mappings,
+            },
+        );
+        self.judge(pid, &executable, &command_line);
+    }
+
+    /// The process has started executing another program.
+    pub(crate) fn exec(&mut self, pid: u32) {
+        let (executable, command_line) = describe(pid);
+        self.send(
+            pid,
+            EventKind::ProcessInfo(ProcessInfo {
+                executable: executable.clone().into(),
+                command_line: command_line.clone(),
+            }),
+        );
+        let depth = match self.processes.get(&pid) {
+            Some(process) => process.depth,
+            None => return,
+        };
+        if depth > 0 {
+            self.judge(pid, &executable, &command_line);
+        } else {
+            self.select_mappings(pid, &executable, &command_line);
+        }
+    }
+
+    /// Remove a process which has terminated.
+    pub(crate) fn exited(&mut self, pid: u32, exit_code: Option<u32>) {
+        if self.processes.remove(&pid).is_some() {
+            self.send(pid, EventKind::ProcessExited { exit_code });
+        }
+    }
+
+    /// Remove a process which is no longer supervised.
+    pub(crate) fn detached(&mut self, pid: u32) {
+        if self.processes.remove(&pid).is_some() {
+            self.send(pid, EventKind::ProcessDetach);
+        }
+    }
+
+    /// Decide whether to rewrite the system calls of a subprocess, and send the verdict.
+    fn judge(&mut self, pid: u32, executable: &str, command_line: &str) {
+        let depth = self.processes[&pid].depth;
+        let verdict = if self.options.dont_hook_subprocesses {
+            Verdict {
+                hook: false,
+                reason: "subprocesses aren't hooked".to_owned(),
+            }
+        } else {
+            self.options
+                .subprocess_rules
+                .evaluate(executable, command_line, depth)
+        };
+        let hook = verdict.hook;
+        self.send(pid, EventKind::SubprocessVerdict(verdict));
+
+        let was_hooked = self.processes[&pid].hooked;
+        self.processes.get_mut(&pid).unwrap().hooked = hook;
+        if hook {
+            self.select_mappings(pid, executable, command_line);
+            if !was_hooked {
+                self.send(pid, EventKind::Initialized);
+            }
+        } else if was_hooked {
+            self.send(pid, EventKind::ProcessDetach);
+        }
+    }
+
+    /// Pick the mappings for a process which has started executing a program, and send the profile if it changed.
+    fn select_mappings(&mut self, pid: u32, executable: &str, command_line: &str) {
+        let depth = self.processes[&pid].depth;
+        let (profile, mappings) = self.mappings.select(executable, command_line, depth);
+        let profile = profile.map(str::to_owned);
+        let process = self.processes.get_mut(&pid).unwrap();
+        process.mappings = mappings;
+        if process.profile != profile {
+            process.profile = profile.clone();
+            self.send(pid, EventKind::MappingProfileSelected { profile });
+        }
+    }
+
+    /// Apply the mappings of a hooked process to a path it passed to a system call.
+    ///
+    /// Relative paths are resolved against `dirfd`, or the working directory of the thread if it's `AT_FDCWD`.
+    /// Returns the path as the process passed it and the path it's redirected to, or `None` if it isn't redirected.
+    pub(crate) fn map(
+        &self,
+        tid: u32,
+        pid: u32,
+        dirfd: c_int,
+        address: u64,
+    ) -> Option<(PathBuf, PathBuf)> {
+        let process = match self.processes.get(&pid) {
+            Some(process) if process.hooked => process,
+            _ => return None,
+        };
+
+        let path = match read_c_string(tid, address) {
+            Ok(path) if !path.is_empty() => PathBuf::from(OsString::from_vec(path)),
+            _ => return None,
+        };
+        let absolute = if path.is_absolute() {
+            normalize(&path)
+        } else {
+            let base = if dirfd == libc::AT_FDCWD {
+                fs::read_link(format!("/proc/{}/cwd", tid))
+            } else {
+                fs::read_link(format!("/proc/{}/fd/{}", tid, dirfd))
+            };
+            normalize(&base.ok()?.join(&path))
+        };
+
+        match map_path(Cow::Borrowed(&absolute), &process.mappings, None) {
+            Ok(mapped) => {
+                let real =
+                    match self
+                        .contents
+                        .real_path(&absolute, mapped.clone(), &process.mappings)
+                    {
+                        Ok(real) => real,
+                        // The file the mappings lead to is still the right one, even if its patches don't fit it.
+                        Err(err) => {
+                            let mut log = self.log(pid);
+                            log_error!(
+                                log,
+                                "Could not patch or merge {}, opening it as it is: {}",
+                                absolute.display(),
+                                err
+                            )
+                            .ok();
+                            match self.contents.unpatched_path(&absolute, mapped.clone()) {
+                                Ok(real) => real,
+                                Err(err) => {
+                                    log_error!(log, "Could not open {}: {}", mapped.display(), err)
+                                        .ok();
+                                    return None;
+                                }
+                            }
+                        }
+                    };
+                if real != absolute {
+                    Some((path, real.into_owned()))
+                } else {
+                    None
+                }
+            }
+            Err(err) => {
+                let mut log = self.log(pid);
+                log_error!(
+                    log,
+                    "Error while redirecting from {}: {}",
+                    path.display(),
+                    err
+                )
+                .ok();
+                None
+            }
+        }
+    }
+
+    /// Report a path which was redirected.
+    pub(crate) fn redirected(&self, pid: u32, from: &Path, to: &Path) {
+        let mut log = self.log(pid);
+        log_info!(
+            log,
+            r#"Redirected "{}" to "{}""#,
+            from.display(),
+            to.display()
+        )
+        .ok();
+    }
+
+    pub(crate) fn send(&self, pid: u32, kind: EventKind) {
+        self.events.send(Event { pid, kind }).ok();
+    }
+
+    pub(crate) fn log(&self, pid: u32) -> Log<'_> {
+        Log {
+            events: &self.events,
+            pid,
+        }
+    }
+}
+
+/// Lets the `log_*` macros send `EventKind::Log` on behalf of a process.
+pub(crate) struct Log<'a> {
+    events: &'a Sender<Event>,
+    pid: u32,
+}
+
+impl Log<'_> {
+    pub(crate) fn write_message(
+        &mut self,
+        message: LogMessage,
+    ) -> Result<(), mpsc::SendError<Event>> {
+        self.events.send(Event {
+            pid: self.pid,
+            kind: EventKind::Log(message),
+        })
+    }
+}
+
+/// A warning about a process, for sessions to queue up with `EventQueue::push`.
+pub(crate) fn warning(pid: u32, message: String) -> Event {
+    Event {
+        pid,
+        kind: EventKind::Log(LogMessage {
+            level: LogLevel::Warn,
+            module_path: module_path!().into(),
+            file: file!().into(),
+            line: line!(),
+            message,
+        }),
+    }
+}
+
+/// The program and its arguments as an `argv` for `execvp`, which has to be built before forking.
+pub(crate) fn c_strings(program: &OsStr, args: &[OsString]) -> io::Result<Vec<CString>> {
+    let to_c_string = |arg: &OsStr| {
+        CString::new(arg.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    };
+    iter::once(program)
+        .chain(args.iter().map(OsString::as_os_str))
+        .map(to_c_string)
+        .collect()
+}
+
+/// Read a null-terminated string from the memory of a process, a page at a time.
+pub(crate) fn read_c_string(tid: u32, mut address: u64) -> io::Result<Vec<u8>> {
+    let mut string = Vec::new();
+    let mut buf = [0u8; 4096];
+    while string.len() < PATH_MAX {
+        let len = 4096 - (address % 4096) as usize;
+        let read = read_memory(tid, address, &mut buf[..len])?;
+        let read = &buf[..read];
+        match read.iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                string.extend_from_slice(&read[..end]);
+                return Ok(string);
+            }
+            None => string.extend_from_slice(read),
+        }
+        address += read.len() as u64;
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "The path is too long",
+    ))
+}
+
+pub(crate) fn read_memory(tid: u32, address: u64, buf: &mut [u8]) -> io::Result<usize> {
+    let local = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut c_void,
+        iov_len: buf.len(),
+    };
+    let read = unsafe { libc::process_vm_readv(tid as libc::pid_t, &local, 1, &remote, 1, 0) };
+    if read <= 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(read as usize)
+    }
+}
+
+pub(crate) fn write_memory(tid: u32, address: u64, bytes: &[u8]) -> io::Result<()> {
+    let local = libc::iovec {
+        iov_base: bytes.as_ptr() as *mut c_void,
+        iov_len: bytes.len(),
+    };
+    let remote = libc::iovec {
+        iov_base: address as *mut c_void,
+        iov_len: bytes.len(),
+    };
+    let written = unsafe { libc::process_vm_writev(tid as libc::pid_t, &local, 1, &remote, 1, 0) };
+    if written == bytes.len() as isize {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Resolve `.` and `..` without touching the file system, so that paths can be compared with the mappings.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// The threads of a process.
+pub(crate) fn tasks(pid: u32) -> Vec<u32> {
+    fs::read_dir(format!("/proc/{}/task", pid))
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The executable and command line of a process.
+fn describe(pid: u32) -> (String, String) {
+    let executable = fs::read_link(format!("/proc/{}/exe", pid))
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let command_line = fs::read(format!("/proc/{}/cmdline", pid))
+        .map(|cmdline| {
+            cmdline
+                .split(|&byte| byte == 0)
+                .filter(|arg| !arg.is_empty())
+                .map(String::from_utf8_lossy)
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .unwrap_or_default();
+    (executable, command_line)
+}
+
+/// When the process with the given id was created.
+pub(crate) fn creation_time(pid: u32) -> Option<SystemTime> {
+    // The fields after the executable name, which may contain spaces and parentheses itself. The start time is the
+    // 22nd field, counted in clock ticks since boot.
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let fields = &stat[stat.rfind(')')? + 1..];
+    let start_ticks: u64 = fields.split_whitespace().nth(19)?.parse().ok()?;
+    let boot_time: u64 = fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime"))?
+        .trim()
+        .parse()
+        .ok()?;
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    let since_boot = Duration::from_secs_f64(start_ticks as f64 / ticks_per_second as f64);
+    Some(UNIX_EPOCH + Duration::from_secs(boot_time) + since_boot)
+}
+
+pub(crate) fn status_field(tid: u32, field: &str) -> Option<u32> {
+    fs::read_to_string(format!("/proc/{}/status", tid))
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix(field))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+// The `W*` macros of `sys/wait.h`.
+
+pub(crate) fn exited(status: c_int) -> bool {
+    status & 0x7f == 0
+}
+
+pub(crate) fn exit_status(status: c_int) -> c_int {
+    (status >> 8) & 0xff
+}
+
+pub(crate) fn signaled(status: c_int) -> bool {
+    ((status & 0x7f) + 1) as i8 >= 2
+}
+
+pub(crate) fn term_signal(status: c_int) -> c_int {
+    status & 0x7f
+}
+
+pub(crate) fn stopped(status: c_int) -> bool {
+    status & 0xff == 0x7f
+}
+
+pub(crate) fn stop_signal(status: c_int) -> c_int {
+    (status >> 8) & 0xff
+}
+
+/// The exit code of a process which has exited or been killed by a signal, like a shell reports it.
+pub(crate) fn exit_code(status: c_int) -> u32 {
+    if exited(status) {
+        exit_status(status) as u32
+    } else {
+        128 + term_signal(status) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{
+        filter::{self, SockFilter},
+        normalize,
+    };
+
+    #[test]
+    fn normalize_resolves_dots() {
+        assert_eq!(normalize(Path::new("/a/./b/../c")), Path::new("/a/c"));
+        assert_eq!(normalize(Path::new("/a/b/../../..")), Path::new("/"));
+        assert_eq!(normalize(Path::new("/a//b/")), Path::new("/a/b"));
+        assert_eq!(normalize(Path::new("a/../../b")), Path::new("b"));
+    }
+
+    /// Run a seccomp filter the way the kernel would, for a system call made on the given architecture.
+    fn run(filter: &[SockFilter], arch: u32, nr: u32) -> u32 {
+        let mut accumulator = 0;
+        let mut pc = 0;
+        loop {
+            let instruction = &filter[pc];
+            pc += 1;
+            match instruction.code {
+                // BPF_LD | BPF_W | BPF_ABS, loading a field of `struct seccomp_data`.
+                0x20 => {
+                    accumulator = match instruction.k {
+                        0 => nr,
+                        4 => arch,
+                        k => panic!("Unexpected offset {}", k),
+                    }
+                }
+                // BPF_JMP | BPF_JEQ | BPF_K
+                0x15 if accumulator == instruction.k => pc += instruction.jt as usize,
+                0x15 => pc += instruction.jf as usize,
+                // BPF_JMP | BPF_JGE | BPF_K
+                0x35 if accumulator >= instruction.k => pc += instruction.jt as usize,
+                0x35 => pc += instruction.jf as usize,
+                // BPF_RET | BPF_K
+                0x06 => return instruction.k,
+                code => panic!("Unexpected instruction {:#x}", code),
+            }
+        }
+    }
+
+    #[test]
+    fn filter_jumps_to_the_action() {
+        const X86_64: u32 = filter::AUDIT_ARCH_X86_64;
+        let syscalls = [2, 4, 257, 437];
+        let filter = filter::filter(&syscalls, filter::RET_TRACE);
+
+        for &nr in &syscalls {
+            assert_eq!(run(&filter, X86_64, nr as u32), filter::RET_TRACE);
+        }
+        for &nr in &[0, 3, 256, 438] {
+            assert_eq!(run(&filter, X86_64, nr), filter::RET_ALLOW);
+        }
+    }
+
+    #[test]
+    fn filter_fails_other_abis() {
+        let filter = filter::filter(&[2], filter::RET_TRACE);
+        let enosys = 0x0005_0000 | libc::ENOSYS as u32;
+
+        // Another architecture's system call numbers mean something else.
+        assert_eq!(run(&filter, 0x4000_0003, 5), enosys);
+        assert_eq!(run(&filter, 0x4000_0003, 0), enosys);
+        // x32 shares the architecture of x86-64, but not its numbers.
+        assert_eq!(run(&filter, filter::AUDIT_ARCH_X86_64, 0x4000_0002), enosys);
+        assert_eq!(run(&filter, filter::AUDIT_ARCH_X86_64, 0x4000_0000), enosys);
+    }
+}
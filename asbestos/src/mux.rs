@@ -0,0 +1,236 @@
+//! Servicing many connections at once.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Read, Write},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use asbestos_shared::protocol::{Connection, Message, ProtocolError};
+
+// Messages are unpacked as soon as they come out of the channel, so boxing them would gain nothing.
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub(crate) enum Received {
+    Message(u32, Result<Message, ProtocolError>),
+    /// The process has terminated, with the given exit code if it could be determined.
+    Exited(u32, Option<u32>),
+}
+
+/// Reads from every connection on a separate thread and funnels the messages into a single channel.
+///
+/// Messages from a single process are received in the order they were sent, while messages from different processes
+/// are received in the order they arrived. A process which doesn't send anything therefore never delays the messages
+/// of other processes.
+///
+/// Processes can also be watched for termination, which is reported alongside their messages.
+pub(crate) struct Multiplexer<W: Write> {
+    tx: Sender<Received>,
+    rx: Receiver<Received>,
+    writers: HashMap<u32, Connection<io::Empty, W>>,
+    watched: HashSet<u32>,
+}
+
+impl<W: Write> Multiplexer<W> {
+    pub(crate) fn new() -> Self {
+        let (tx, rx) = mpsc::channel();
+        Self {
+            tx,
+            rx,
+            writers: HashMap::new(),
+            watched: HashSet::new(),
+        }
+    }
+
+    /// Start servicing `connection`.
+    ///
+    /// Its reading thread stops after it has received `Message::ProcessDetach` or `ProtocolError::Disconnected`.
+    pub(crate) fn add<R: Read + Send + 'static>(&mut self, pid: u32, connection: Connection<R, W>) {
+        let (mut reader, writer) = connection.split();
+        let tx = self.tx.clone();
+        thread::spawn(move || loop {
+            let res = reader.read_message();
+            let last = matches!(
+                res,
+                Ok(Message::ProcessDetach) | Err(ProtocolError::Disconnected)
+            );
+            if tx.send(Received::Message(pid, res)).is_err() || last {
+                break;
+            }
+        });
+        self.writers.insert(pid, writer);
+    }
+
+    /// Report the termination of a process once `wait` returns its exit code.
+    pub(crate) fn watch<F>(&mut self, pid: u32, wait: F)
+    where
+        F: FnOnce() -> Option<u32> + Send + 'static,
+    {
+        if !self.watched.insert(pid) {
+            return;
+        }
+        let tx = self.tx.clone();
+        thread::spawn(move || {
+            tx.send(Received::Exited(pid, wait())).ok();
+        });
+    }
+
+    /// Send a message to the process with the given id.
+    pub(crate) fn send(&mut self, pid: u32, message: Message) -> Result<(), ProtocolError> {
+        match self.writers.get_mut(&pid) {
+            Some(writer) => writer.write_message(message),
+            None => Err(ProtocolError::Disconnected),
+        }
+    }
+
+    /// Stop keeping track of a connection. This should be called once its last message has been received.
+    pub(crate) fn remove(&mut self, pid: u32) {
+        self.writers.remove(&pid);
+    }
+
+    /// The ids of the processes whose connections are being serviced.
+    pub(crate) fn pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.writers.keys().copied()
+    }
+
+    /// Whether no connections are being serviced and no processes are being watched.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.writers.is_empty() && self.watched.is_empty()
+    }
+
+    /// Wait for the next message from any connection, or for a watched process to terminate.
+    ///
+    /// Returns `None` if nothing is being serviced or watched, or if nothing arrived within `timeout`.
+    pub(crate) fn recv(&mut self, timeout: Option<Duration>) -> Option<Received> {
+        if self.is_empty() {
+            return None;
+        }
+        let received = match timeout {
+            Some(timeout) => match self.rx.recv_timeout(timeout) {
+                Ok(received) => received,
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return None
+                }
+            },
+            // `self.tx` is never dropped, so this can't fail.
+            None => self.rx.recv().ok()?,
+        };
+        if let Received::Exited(pid, _) = &received {
+            self.watched.remove(pid);
+        }
+        Some(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor, Read},
+        sync::mpsc::{self, Receiver},
+        time::{Duration, Instant},
+    };
+
+    use asbestos_shared::protocol::{Connection, Message, ProtocolError};
+
+    use super::{Multiplexer, Received};
+
+    /// A reader which blocks until its sender sends it something, like a pipe to a quiet process.
+    struct SilentReader(Receiver<Vec<u8>>);
+
+    impl Read for SilentReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.recv() {
+                Ok(bytes) => {
+                    buf[..bytes.len()].copy_from_slice(&bytes);
+                    Ok(bytes.len())
+                }
+                Err(_) => Ok(0),
+            }
+        }
+    }
+
+    fn encode(messages: Vec<Message>) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        let mut connection = Connection::new(io::empty(), &mut buffer);
+        for message in messages {
+            connection.write_message(message).unwrap();
+        }
+        buffer
+    }
+
+    #[test]
+    fn silent_peer_does_not_delay_others() {
+        let mut mux = Multiplexer::new();
+
+        let (_keep_silent, silent_rx) = mpsc::channel();
+        mux.add(1, Connection::new(SilentReader(silent_rx), io::sink()));
+
+        let messages = (0..100)
+            .map(|n| Message::InitializationFailed(n.to_string()))
+            .chain(std::iter::once(Message::ProcessDetach))
+            .collect();
+        mux.add(
+            2,
+            Connection::new(Cursor::new(encode(messages)), io::sink()),
+        );
+
+        let start = Instant::now();
+        for n in 0..100 {
+            match mux.recv(Some(Duration::from_secs(5))) {
+                Some(Received::Message(2, Ok(Message::InitializationFailed(text)))) => {
+                    assert_eq!(text, n.to_string())
+                }
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+        assert!(matches!(
+            mux.recv(Some(Duration::from_secs(5))),
+            Some(Received::Message(2, Ok(Message::ProcessDetach)))
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        mux.remove(2);
+        assert_eq!(mux.pids().collect::<Vec<_>>(), vec![1]);
+        assert!(mux.recv(Some(Duration::from_millis(50))).is_none());
+    }
+
+    #[test]
+    fn reader_stops_after_disconnect() {
+        let mut mux = Multiplexer::new();
+        mux.add(
+            1,
+            Connection::new(Cursor::new(encode(vec![Message::Initialized])), io::sink()),
+        );
+
+        assert!(matches!(
+            mux.recv(Some(Duration::from_secs(5))),
+            Some(Received::Message(1, Ok(Message::Initialized)))
+        ));
+        assert!(matches!(
+            mux.recv(Some(Duration::from_secs(5))),
+            Some(Received::Message(1, Err(ProtocolError::ConnectionLost)))
+        ));
+        assert!(matches!(
+            mux.recv(Some(Duration::from_secs(5))),
+            Some(Received::Message(1, Err(ProtocolError::Disconnected)))
+        ));
+        assert!(mux.recv(Some(Duration::from_millis(50))).is_none());
+    }
+
+    #[test]
+    fn exit_is_reported_once() {
+        let mut mux = Multiplexer::<io::Sink>::new();
+        mux.watch(1, || Some(3));
+        mux.watch(1, || Some(4));
+        assert!(!mux.is_empty());
+
+        assert!(matches!(
+            mux.recv(Some(Duration::from_secs(5))),
+            Some(Received::Exited(1, Some(3)))
+        ));
+        assert!(mux.is_empty());
+        assert!(mux.recv(Some(Duration::from_millis(50))).is_none());
+    }
+}
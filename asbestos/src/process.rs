@@ -0,0 +1,107 @@
+//! Inspecting, waiting on and resuming processes which aren't children of the current process.
+
+use std::{
+    ffi::OsString,
+    io,
+    os::windows::ffi::OsStringExt,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use winapi::{
+    shared::minwindef::{DWORD, FALSE, FILETIME, MAX_PATH},
+    um::{
+        handleapi::CloseHandle,
+        minwinbase::STILL_ACTIVE,
+        processthreadsapi::{
+            GetExitCodeProcess, GetProcessTimes, OpenProcess, OpenThread, ResumeThread,
+        },
+        synchapi::WaitForSingleObject,
+        winbase::{QueryFullProcessImageNameW, INFINITE, WAIT_OBJECT_0},
+        winnt::{PROCESS_QUERY_LIMITED_INFORMATION, SYNCHRONIZE, THREAD_SUSPEND_RESUME},
+    },
+};
+
+/// Returns the path to the executable of the process with the given id.
+pub(crate) fn executable_path(pid: u32) -> Option<PathBuf> {
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid) };
+    if handle.is_null() {
+        return None;
+    }
+
+    // Paths may be longer than `MAX_PATH` if they're prefixed with `\\?\`.
+    let mut buffer = vec![0u16; MAX_PATH * 4];
+    let mut len = buffer.len() as DWORD;
+    let res = unsafe { QueryFullProcessImageNameW(handle, 0, buffer.as_mut_ptr(), &mut len) };
+    unsafe { CloseHandle(handle) };
+
+    if res == 0 {
+        None
+    } else {
+        Some(OsString::from_wide(&buffer[..len as usize]).into())
+    }
+}
+
+/// Returns when the process with the given id was created.
+pub(crate) fn creation_time(pid: u32) -> Option<SystemTime> {
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid) };
+    if handle.is_null() {
+        return None;
+    }
+
+    let empty = FILETIME {
+        dwLowDateTime: 0,
+        dwHighDateTime: 0,
+    };
+    let (mut creation, mut exit, mut kernel, mut user) = (empty, empty, empty, empty);
+    let res = unsafe { GetProcessTimes(handle, &mut creation, &mut exit, &mut kernel, &mut user) };
+    unsafe { CloseHandle(handle) };
+
+    if res == 0 {
+        return None;
+    }
+    // In 100ns intervals since 1601-01-01.
+    let ticks = (creation.dwHighDateTime as u64) << 32 | creation.dwLowDateTime as u64;
+    let since_unix_epoch = ticks.checked_sub(116_444_736_000_000_000)?;
+    Some(UNIX_EPOCH + Duration::from_nanos(since_unix_epoch * 100))
+}
+
+/// Block until the process with the given id has terminated, and return its exit code.
+///
+/// Returns `None` if the process couldn't be opened, which is usually because it has already terminated.
+pub(crate) fn wait_for_exit(pid: u32) -> Option<u32> {
+    let handle =
+        unsafe { OpenProcess(SYNCHRONIZE | PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid) };
+    if handle.is_null() {
+        return None;
+    }
+
+    let mut exit_code: DWORD = STILL_ACTIVE;
+    let waited = unsafe { WaitForSingleObject(handle, INFINITE) } == WAIT_OBJECT_0;
+    let queried = waited && unsafe { GetExitCodeProcess(handle, &mut exit_code) } != 0;
+    unsafe { CloseHandle(handle) };
+
+    if queried && exit_code != STILL_ACTIVE {
+        Some(exit_code)
+    } else {
+        None
+    }
+}
+
+/// Resume a thread which was created suspended.
+pub(crate) fn resume_thread(tid: u32) -> io::Result<()> {
+    let handle = unsafe { OpenThread(THREAD_SUSPEND_RESUME, FALSE, tid) };
+    if handle.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+
+    let res = unsafe { ResumeThread(handle) };
+    let err = io::Error::last_os_error();
+    unsafe { CloseHandle(handle) };
+
+    if res == DWORD::MAX {
+        Err(err)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,649 @@
+//! Applying mappings on Linux by tracing the system calls of a process and rewriting the paths passed to them.
+//!
+//! This works for statically linked executables and for processes which were already running, neither of which a
+//! preloaded library can reach. Processes spawned by the session get a seccomp filter which only stops them at the
+//! system calls taking paths. Processes the session attached to can't be given a filter after the fact, so they are
+//! stopped at the entry and exit of every system call instead.
+//!
+//! Telling the entry of a system call from its exit needs `PTRACE_GET_SYSCALL_INFO`, which Linux 5.3 introduced.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    io, iter, mem,
+    os::{
+        raw::{c_char, c_int, c_long, c_void},
+        unix::ffi::OsStrExt,
+    },
+    ptr,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use asbestos_shared::{log_error, log_warn, profiles::MappingProfiles};
+
+use crate::{
+    linux::{self, filter, sys, EventQueue, Processes},
+    session::{Event, SessionBuilder, SessionError, SessionOptions, Target},
+    tree::ProcessTree,
+};
+
+/// The area below the stack pointer which the System V ABI lets functions use without adjusting it. Replacement
+/// paths are written below it.
+const RED_ZONE: u64 = 128;
+
+/// Options shared by every tracee.
+const OPTIONS: c_int = libc::PTRACE_O_TRACEFORK
+    | libc::PTRACE_O_TRACEVFORK
+    | libc::PTRACE_O_TRACECLONE
+    | libc::PTRACE_O_TRACEEXEC
+    | libc::PTRACE_O_TRACESYSGOOD;
+
+/// `PTRACE_GET_SYSCALL_INFO` and the kinds of stops it tells apart, which the version of `libc` used here has no
+/// definitions for.
+const PTRACE_GET_SYSCALL_INFO: libc::c_uint = 0x420e;
+const SYSCALL_INFO_ENTRY: u8 = 1;
+const SYSCALL_INFO_EXIT: u8 = 2;
+const SYSCALL_INFO_SECCOMP: u8 = 3;
+
+/// The beginning of `struct ptrace_syscall_info`. The kernel only fills in as much of it as it is asked for.
+#[repr(C)]
+struct SyscallInfo {
+    op: u8,
+    _reserved: u8,
+    _flags: u16,
+    arch: u32,
+}
+
+impl SessionBuilder {
+    /// Spawn the target process or attach to it, and apply the mappings by tracing its system calls.
+    ///
+    /// Only the mappings, `SessionOptions::subprocess_rules` and `SessionOptions::dont_hook_subprocesses` apply to a
+    /// `PtraceSession`. The options concerning the payload are ignored.
+    pub fn start_ptrace(self) -> Result<PtraceSession, SessionError> {
+        let SessionBuilder {
+            target,
+            mappings,
+            options,
+        } = self;
+        let spawned = matches!(target, Target::Command { .. });
+        let (events_tx, events) = mpsc::channel();
+        let (commands, commands_rx) = mpsc::channel();
+        let (started_tx, started) = mpsc::channel();
+
+        // Every ptrace request has to be made by the thread which is tracing the process.
+        thread::spawn(move || {
+            let mut tracer = Tracer::new(mappings, options, events_tx);
+            let root = match tracer.start(target) {
+                Ok(root) => root,
+                Err(err) => {
+                    started_tx.send(Err(err)).ok();
+                    return;
+                }
+            };
+            started_tx.send(Ok(root)).ok();
+            tracer.run(commands_rx);
+        });
+        let root = started
+            .recv()
+            .map_err(|_| io::Error::other("The tracing thread panicked"))??;
+
+        Ok(PtraceSession {
+            root,
+            spawned,
+            commands,
+            events: EventQueue::new(events),
+        })
+    }
+}
+
+/// A set of traced processes, whose system calls are rewritten according to the mappings.
+///
+/// A `PtraceSession` yields the same kinds of events as a `Session`, except for the ones which only a payload can
+/// send. Iterating over it blocks until the next event arrives, and ends once every process has terminated.
+///
+/// Only the system calls of the x86-64 ABI are rewritten. In spawned processes, the system calls of other ABIs, such
+/// as those of 32-bit programs, fail with `ENOSYS`. In processes the session attached to, they are let through
+/// without the mappings.
+pub struct PtraceSession {
+    root: u32,
+    /// Whether the session spawned the root process, rather than attaching to it.
+    spawned: bool,
+    commands: Sender<Command>,
+    events: EventQueue,
+}
+
+impl PtraceSession {
+    /// Start building a session which spawns `program`.
+    pub fn command<S: Into<OsString>>(program: S) -> SessionBuilder {
+        SessionBuilder::new(Target::Command {
+            program: program.into(),
+            args: Vec::new(),
+        })
+    }
+
+    /// Start building a session which attaches to the process with the given id.
+    pub fn pid(pid: u32) -> SessionBuilder {
+        SessionBuilder::new(Target::Pid(pid))
+    }
+
+    /// The id of the process the session started out with.
+    pub fn root_pid(&self) -> u32 {
+        self.root
+    }
+
+    /// The ids of the processes whose system calls are currently being rewritten.
+    pub fn pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.events.pids()
+    }
+
+    /// Stop tracing the given process, which keeps running without its mappings.
+    ///
+    /// This takes effect the next time the process makes a system call, and is followed by
+    /// `EventKind::ProcessDetach`. Processes spawned by the session can't be detached from, since the system calls
+    /// their seccomp filter reports would fail without a tracer. They are killed when the session ends instead.
+    pub fn detach(&mut self, pid: u32) -> Result<(), SessionError> {
+        if self.spawned {
+            return Err(SessionError::Io(io::Error::other(
+                "Processes spawned by a ptrace session can't be detached from",
+            )));
+        }
+        self.commands
+            .send(Command::Detach(pid))
+            .map_err(|_| io::Error::other("The tracing thread has stopped"))?;
+        Ok(())
+    }
+
+    /// Stop tracing every process. Failures are reported as `EventKind::Log`.
+    pub fn detach_all(&mut self) {
+        let pids: Vec<_> = self.pids().collect();
+        for pid in pids {
+            if let Err(err) = self.detach(pid) {
+                self.events
+                    .push(linux::warning(pid, format!("Could not detach: {}", err)));
+            }
+        }
+    }
+
+    /// The processes in the session, including those which have terminated.
+    ///
+    /// The tree reflects every event which has been returned so far.
+    pub fn tree(&self) -> &ProcessTree {
+        self.events.tree()
+    }
+
+    /// Whether every process has terminated or been detached from.
+    pub fn is_finished(&self) -> bool {
+        self.events.is_finished()
+    }
+
+    /// Wait for the next event.
+    ///
+    /// Returns `None` once every process has terminated or been detached from.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.events.next(None)
+    }
+
+    /// Wait for the next event for no longer than `timeout`.
+    ///
+    /// Returns `None` if no event arrived in time, or if every process has terminated or been detached from.
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.events.next(Some(timeout))
+    }
+}
+
+impl Iterator for PtraceSession {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event()
+    }
+}
+
+enum Command {
+    Detach(u32),
+}
+
+/// How tracees are stopped at the system calls which take paths.
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    /// By the seccomp filter installed when the root process was spawned, which its subprocesses inherit.
+    Seccomp,
+    /// At the entry and exit of every system call.
+    Syscall,
+}
+
+struct Thread {
+    /// The id of the process the thread belongs to.
+    pid: u32,
+    /// The index and original value of the argument a redirected path replaced, which is put back once the system
+    /// call returns.
+    replaced: Option<(usize, u64)>,
+}
+
+impl Thread {
+    fn new(pid: u32) -> Self {
+        Self {
+            pid,
+            replaced: None,
+        }
+    }
+}
+
+/// Owns the traced processes, and sends `Event`s about them to a `PtraceSession`.
+struct Tracer {
+    mode: Mode,
+    threads: HashMap<u32, Thread>,
+    processes: Processes,
+    /// Processes which have been asked to be detached from. Their threads are detached at their next stop.
+    detaching: HashSet<u32>,
+}
+
+impl Tracer {
+    fn new(mappings: MappingProfiles, options: SessionOptions, events: Sender<Event>) -> Self {
+        Self {
+            mode: Mode::Seccomp,
+            threads: HashMap::new(),
+            processes: Processes::new(mappings, options, events),
+            detaching: HashSet::new(),
+        }
+    }
+
+    /// Spawn or attach to the target, returning its pid.
+    fn start(&mut self, target: Target) -> Result<u32, SessionError> {
+        let pid = match target {
+            Target::Command { program, args } => {
+                self.mode = Mode::Seccomp;
+                spawn(&program, &args)?
+            }
+            Target::Pid(pid) => {
+                self.mode = Mode::Syscall;
+                attach(pid)?;
+                pid
+            }
+        };
+
+        for tid in linux::tasks(pid) {
+            self.threads.insert(tid, Thread::new(pid));
+        }
+        self.processes.add_root(pid);
+
+        if self.mode == Mode::Seccomp {
+            self.resume(pid, 0);
+        }
+        Ok(pid)
+    }
+
+    /// Handle every stop and exit of the tracees, until there are none left.
+    fn run(mut self, commands: Receiver<Command>) {
+        loop {
+            let mut status = 0;
+            let tid = unsafe { libc::waitpid(-1, &mut status, libc::__WALL) };
+            if tid == -1 {
+                match io::Error::last_os_error().raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    // Every tracee has terminated or been detached from.
+                    _ => return,
+                }
+            }
+            self.handle(tid as u32, status);
+
+            while let Ok(command) = commands.try_recv() {
+                match command {
+                    Command::Detach(pid) => self.request_detach(pid),
+                }
+            }
+        }
+    }
+
+    fn handle(&mut self, tid: u32, status: c_int) {
+        if linux::exited(status) || linux::signaled(status) {
+            self.threads.remove(&tid);
+            self.processes.exited(tid, Some(linux::exit_code(status)));
+            return;
+        }
+        if !linux::stopped(status) {
+            return;
+        }
+
+        if !self.threads.contains_key(&tid) {
+            // The first stop of a thread or process created by a tracee. It may arrive before or after the event
+            // its creator reports it with.
+            self.new_tracee(tid);
+            return;
+        }
+
+        let pid = self.threads[&tid].pid;
+        if self.detaching.contains(&pid) {
+            self.detach(tid, pid, status);
+            return;
+        }
+
+        let signal = linux::stop_signal(status);
+        let event = status >> 16;
+        if signal == libc::SIGTRAP | 0x80
+            || (signal == libc::SIGTRAP && event == libc::PTRACE_EVENT_SECCOMP)
+        {
+            // Asking the kernel rather than counting stops also works for threads which were attached to in the
+            // middle of a system call.
+            match syscall_info(tid) {
+                // Attached processes are stopped at every system call, including those of other ABIs, whose
+                // numbers mean something else. Only spawned processes have a filter to make those fail.
+                Some(info)
+                    if (info.op == SYSCALL_INFO_ENTRY || info.op == SYSCALL_INFO_SECCOMP)
+                        && info.arch == filter::AUDIT_ARCH_X86_64 =>
+                {
+                    if let Some(regs) = registers(tid) {
+                        if regs.orig_rax & filter::X32_SYSCALL_BIT == 0 {
+                            self.rewrite_path(tid, pid, regs);
+                        }
+                    }
+                }
+                Some(info) if info.op == SYSCALL_INFO_EXIT => self.restore_argument(tid),
+                _ => {}
+            }
+            self.resume(tid, 0);
+        } else if signal == libc::SIGTRAP && event == libc::PTRACE_EVENT_EXEC {
+            self.exec(tid);
+            self.resume(tid, 0);
+        } else if signal == libc::SIGTRAP && event != 0 {
+            // Forks, clones and the stops of attached threads. New tracees are handled at their own first stop.
+            self.resume(tid, 0);
+        } else if is_group_stop(tid, signal) {
+            self.resume(tid, 0);
+        } else {
+            self.resume(tid, signal);
+        }
+    }
+
+    fn new_tracee(&mut self, tid: u32) {
+        let pid = linux::status_field(tid, "Tgid:").unwrap_or(tid);
+        self.threads.insert(tid, Thread::new(pid));
+        if !self.processes.contains(pid) {
+            self.processes.add_subprocess(pid, tid);
+        }
+        self.resume(tid, 0);
+    }
+
+    /// The process has started executing another program.
+    fn exec(&mut self, tid: u32) {
+        let pid = tid;
+        // Executing a program from another thread than the main one turns it into the main one.
+        let former = event_message(tid) as u32;
+        if former != tid {
+            self.threads.remove(&former);
+        }
+        self.threads
+            .retain(|thread_id, thread| thread.pid != pid || *thread_id == tid);
+        // The registers belong to the new program now, so there is nothing to put back.
+        if let Some(thread) = self.threads.get_mut(&tid) {
+            thread.replaced = None;
+        }
+        self.processes.exec(pid);
+    }
+
+    /// Apply the mappings to the path the thread is about to make a system call with.
+    fn rewrite_path(&mut self, tid: u32, pid: u32, mut regs: libc::user_regs_struct) {
+        let (dirfd_index, path_index) = match sys::path_argument(regs.orig_rax as i64) {
+            Some(indices) => indices,
+            None => return,
+        };
+        let dirfd = dirfd_index.map_or(libc::AT_FDCWD, |index| argument(&regs, index) as c_int);
+        let (path, mapped) = match self
+            .processes
+            .map(tid, pid, dirfd, argument(&regs, path_index))
+        {
+            Some(paths) => paths,
+            None => return,
+        };
+
+        // The replacement is written below the red zone, which the thread won't touch before the kernel has
+        // copied the path.
+        let mut bytes = mapped.as_os_str().as_bytes().to_vec();
+        bytes.push(0);
+        let address = (regs.rsp - RED_ZONE - bytes.len() as u64) & !0xf;
+        let original = argument(&regs, path_index);
+        set_argument(&mut regs, path_index, address);
+        let res = linux::write_memory(tid, address, &bytes)
+            .and_then(|_| ptrace(libc::PTRACE_SETREGS, tid, 0, &regs as *const _ as usize));
+        if let Err(err) = res {
+            let mut log = self.processes.log(pid);
+            log_error!(log, "Could not redirect {}: {}", path.display(), err).ok();
+            return;
+        }
+
+        if let Some(thread) = self.threads.get_mut(&tid) {
+            thread.replaced = Some((path_index, original));
+        }
+        self.processes.redirected(pid, &path, &mapped);
+    }
+
+    /// Put back the argument a redirected path replaced, now that the system call has returned. The program may
+    /// still use the register, since the ABI doesn't let system calls change it.
+    fn restore_argument(&mut self, tid: u32) {
+        let (index, original) = match self
+            .threads
+            .get_mut(&tid)
+            .and_then(|thread| thread.replaced.take())
+        {
+            Some(replaced) => replaced,
+            None => return,
+        };
+        if let Some(mut regs) = registers(tid) {
+            set_argument(&mut regs, index, original);
+            ptrace(libc::PTRACE_SETREGS, tid, 0, &regs as *const _ as usize).ok();
+        }
+    }
+
+    fn request_detach(&mut self, pid: u32) {
+        if !self.processes.contains(pid) {
+            return;
+        }
+        if self.mode == Mode::Seccomp {
+            let mut log = self.processes.log(pid);
+            log_warn!(
+                log,
+                "Processes spawned by the session can't be detached from"
+            )
+            .ok();
+            return;
+        }
+        self.detaching.insert(pid);
+    }
+
+    /// Detach from a thread of a process which is being detached from, delivering the signal it stopped with.
+    fn detach(&mut self, tid: u32, pid: u32, status: c_int) {
+        let signal = linux::stop_signal(status);
+        let signal = if signal == libc::SIGTRAP | 0x80
+            || signal == libc::SIGTRAP
+            || is_group_stop(tid, signal)
+        {
+            0
+        } else {
+            signal
+        };
+        if linux::stop_signal(status) == libc::SIGTRAP | 0x80 {
+            self.restore_argument(tid);
+        }
+        ptrace(libc::PTRACE_DETACH, tid, 0, signal as usize).ok();
+        self.threads.remove(&tid);
+
+        if !self.threads.values().any(|thread| thread.pid == pid) {
+            self.detaching.remove(&pid);
+            self.processes.detached(pid);
+        }
+    }
+
+    /// Let the thread continue until its next stop. A thread whose path was redirected also stops when the system
+    /// call returns, so that the argument can be put back.
+    fn resume(&self, tid: u32, signal: c_int) {
+        let replaced = self
+            .threads
+            .get(&tid)
+            .is_some_and(|thread| thread.replaced.is_some());
+        let request = match self.mode {
+            Mode::Seccomp if !replaced => libc::PTRACE_CONT,
+            _ => libc::PTRACE_SYSCALL,
+        };
+        ptrace(request, tid, 0, signal as usize).ok();
+    }
+}
+
+/// Spawn `program` with a seccomp filter, stopped until the tracer has set its options.
+fn spawn(program: &OsStr, args: &[OsString]) -> io::Result<u32> {
+    let args = linux::c_strings(program, args)?;
+    let argv: Vec<*const c_char> = args
+        .iter()
+        .map(|arg| arg.as_ptr())
+        .chain(iter::once(ptr::null()))
+        .collect();
+    // Built before forking, since the child may only do async-signal-safe things.
+    let filter = filter::filter(&sys::PATHS, filter::RET_TRACE);
+    let prog = filter::SockFprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+
+    let pid = unsafe { libc::fork() };
+    if pid == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if pid == 0 {
+        unsafe {
+            libc::ptrace(
+                libc::PTRACE_TRACEME,
+                0,
+                ptr::null_mut::<c_void>(),
+                ptr::null_mut::<c_void>(),
+            );
+            // The filter's stops would make the system calls fail until the tracer has asked for them.
+            libc::raise(libc::SIGSTOP);
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            libc::syscall(
+                sys::SECCOMP as c_long,
+                filter::SET_MODE_FILTER,
+                0,
+                &prog as *const filter::SockFprog,
+            );
+            libc::execvp(argv[0], argv.as_ptr());
+            libc::_exit(127);
+        }
+    }
+
+    let pid = pid as u32;
+    let mut status = 0;
+    if unsafe { libc::waitpid(pid as c_int, &mut status, libc::__WALL) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    ptrace(
+        libc::PTRACE_SETOPTIONS,
+        pid,
+        0,
+        (OPTIONS | libc::PTRACE_O_TRACESECCOMP | libc::PTRACE_O_EXITKILL) as usize,
+    )?;
+    Ok(pid)
+}
+
+/// Attach to every thread of a running process, and stop them so that they can be resumed at every system call.
+fn attach(pid: u32) -> io::Result<()> {
+    let tids = linux::tasks(pid);
+    if tids.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("There is no process with the id {}", pid),
+        ));
+    }
+    for tid in tids {
+        ptrace(libc::PTRACE_SEIZE, tid, 0, OPTIONS as usize)?;
+        ptrace(libc::PTRACE_INTERRUPT, tid, 0, 0)?;
+    }
+    Ok(())
+}
+
+fn ptrace(request: libc::c_uint, tid: u32, address: usize, data: usize) -> io::Result<c_long> {
+    let res = unsafe {
+        libc::ptrace(
+            request,
+            tid as libc::pid_t,
+            address as *mut c_void,
+            data as *mut c_void,
+        )
+    };
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+fn registers(tid: u32) -> Option<libc::user_regs_struct> {
+    let mut regs: libc::user_regs_struct = unsafe { mem::zeroed() };
+    ptrace(libc::PTRACE_GETREGS, tid, 0, &mut regs as *mut _ as usize).ok()?;
+    Some(regs)
+}
+
+/// Which kind of system call stop the thread is in, and the system call.
+fn syscall_info(tid: u32) -> Option<SyscallInfo> {
+    let mut info: SyscallInfo = unsafe { mem::zeroed() };
+    ptrace(
+        PTRACE_GET_SYSCALL_INFO,
+        tid,
+        mem::size_of::<SyscallInfo>(),
+        &mut info as *mut _ as usize,
+    )
+    .ok()?;
+    Some(info)
+}
+
+fn event_message(tid: u32) -> u64 {
+    let mut message: libc::c_ulong = 0;
+    ptrace(
+        libc::PTRACE_GETEVENTMSG,
+        tid,
+        0,
+        &mut message as *mut _ as usize,
+    )
+    .ok();
+    message as u64
+}
+
+/// Whether the thread stopped because its process is being stopped, rather than to receive a signal.
+fn is_group_stop(tid: u32, signal: c_int) -> bool {
+    if !matches!(
+        signal,
+        libc::SIGSTOP | libc::SIGTSTP | libc::SIGTTIN | libc::SIGTTOU
+    ) {
+        return false;
+    }
+    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+    ptrace(
+        libc::PTRACE_GETSIGINFO,
+        tid,
+        0,
+        &mut info as *mut _ as usize,
+    )
+    .is_err()
+}
+
+fn argument(regs: &libc::user_regs_struct, index: usize) -> u64 {
+    match index {
+        0 => regs.rdi,
+        1 => regs.rsi,
+        2 => regs.rdx,
+        3 => regs.r10,
+        4 => regs.r8,
+        _ => regs.r9,
+    }
+}
+
+fn set_argument(regs: &mut libc::user_regs_struct, index: usize, value: u64) {
+    match index {
+        0 => regs.rdi = value,
+        1 => regs.rsi = value,
+        2 => regs.rdx = value,
+        3 => regs.r10 = value,
+        4 => regs.r8 = value,
+        _ => regs.r9 = value,
+    }
+}
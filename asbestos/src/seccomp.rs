@@ -0,0 +1,722 @@
+//! Applying mappings on Linux by having a seccomp filter hand the system calls taking paths to the session.
+//!
+//! The session carries out the redirected system calls itself and gives the process their result. Files are opened by
+//! the session and added to the process with `SECCOMP_IOCTL_NOTIF_ADDFD`, so nothing is injected into the process and
+//! it is never stopped the way a tracee is. System calls which aren't redirected are let through unchanged.
+//!
+//! This needs Linux 5.14 or newer. Processes can only be supervised if the session spawned them, since a process has
+//! to install its filter itself.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{CString, OsStr, OsString},
+    fs::{self, File},
+    io, iter, mem,
+    os::{
+        raw::{c_char, c_int, c_long, c_uint, c_void},
+        unix::{
+            ffi::OsStrExt,
+            io::{AsRawFd, FromRawFd, RawFd},
+        },
+    },
+    path::{Path, PathBuf},
+    ptr,
+    sync::mpsc::{self, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+use asbestos_shared::profiles::MappingProfiles;
+
+use crate::{
+    linux::{self, filter, sys, EventQueue, Processes},
+    session::{Event, SessionBuilder, SessionError, SessionOptions, Target},
+    tree::ProcessTree,
+};
+
+/// The system calls handed to the session. Changing the working directory can't be done on behalf of a process, so
+/// `chdir` isn't among them. `execve` is only looked at to notice processes executing other programs.
+const SYSCALLS: [i64; 19] = [
+    sys::OPEN,
+    sys::STAT,
+    sys::LSTAT,
+    sys::ACCESS,
+    sys::EXECVE,
+    sys::TRUNCATE,
+    sys::READLINK,
+    sys::GETXATTR,
+    sys::LGETXATTR,
+    sys::LISTXATTR,
+    sys::LLISTXATTR,
+    sys::OPENAT,
+    sys::NEWFSTATAT,
+    sys::READLINKAT,
+    sys::FACCESSAT,
+    sys::EXECVEAT,
+    sys::STATX,
+    sys::OPENAT2,
+    sys::FACCESSAT2,
+];
+
+/// The largest value and list of names of extended attributes the kernel supports.
+const XATTR_MAX: usize = 65536;
+
+/// How often the session checks whether its processes are still running while none of them are making system calls.
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The user notification API, which the version of `libc` used here has no definitions for.
+mod notif {
+    use std::os::raw::{c_int, c_ulong};
+
+    #[repr(C)]
+    pub struct SeccompData {
+        pub nr: c_int,
+        pub arch: u32,
+        pub instruction_pointer: u64,
+        pub args: [u64; 6],
+    }
+
+    #[repr(C)]
+    pub struct Notif {
+        pub id: u64,
+        pub pid: u32,
+        pub flags: u32,
+        pub data: SeccompData,
+    }
+
+    #[repr(C)]
+    pub struct Resp {
+        pub id: u64,
+        pub val: i64,
+        pub error: i32,
+        pub flags: u32,
+    }
+
+    #[repr(C)]
+    pub struct Addfd {
+        pub id: u64,
+        pub flags: u32,
+        pub srcfd: u32,
+        pub newfd: u32,
+        pub newfd_flags: u32,
+    }
+
+    pub const IOCTL_NOTIF_RECV: c_ulong = 0xc050_2100;
+    pub const IOCTL_NOTIF_SEND: c_ulong = 0xc018_2101;
+    pub const IOCTL_NOTIF_ID_VALID: c_ulong = 0x4008_2102;
+    pub const IOCTL_NOTIF_ADDFD: c_ulong = 0x4018_2103;
+
+    pub const FLAG_CONTINUE: u32 = 1;
+    pub const ADDFD_FLAG_SEND: u32 = 1 << 1;
+}
+
+impl SessionBuilder {
+    /// Spawn the target process with a seccomp filter, and apply the mappings by carrying out the system calls
+    /// taking paths on its behalf.
+    ///
+    /// Only `Target::Command` is supported. Only the mappings, `SessionOptions::subprocess_rules` and
+    /// `SessionOptions::dont_hook_subprocesses` apply to a `SeccompSession`. The options concerning the payload are
+    /// ignored.
+    pub fn start_seccomp(self) -> Result<SeccompSession, SessionError> {
+        let SessionBuilder {
+            target,
+            mappings,
+            options,
+        } = self;
+        let (program, args) = match target {
+            Target::Command { program, args } => (program, args),
+            Target::Pid(_) => {
+                return Err(SessionError::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "A seccomp session can't attach to a running process",
+                )))
+            }
+        };
+        let (events_tx, events) = mpsc::channel();
+        let (started_tx, started) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (root, listener) = match spawn(&program, &args) {
+                Ok(ok) => ok,
+                Err(err) => {
+                    started_tx.send(Err(err)).ok();
+                    return;
+                }
+            };
+            started_tx.send(Ok(root)).ok();
+            Supervisor::new(root, listener, mappings, options, events_tx).run();
+        });
+        let root = started
+            .recv()
+            .map_err(|_| io::Error::other("The supervising thread panicked"))??;
+
+        Ok(SeccompSession {
+            root,
+            events: EventQueue::new(events),
+        })
+    }
+}
+
+/// A set of processes whose system calls taking paths are carried out by the session, according to the mappings.
+///
+/// A `SeccompSession` yields the same kinds of events as a `Session`, except for the ones which only a payload can
+/// send. Subprocesses are reported when they first make one of the system calls, and exit codes are only known for
+/// the root process. Iterating over it blocks until the next event arrives, and ends once every process has
+/// terminated.
+///
+/// Forks aren't among the system calls, so a subprocess isn't known until it first makes one of them. Its parent is
+/// taken from `/proc`, which names `init` (or a subreaper) once the process that forked it has exited; such a
+/// subprocess is then reported as a child of that process, and its mappings are selected as for a child of the root
+/// process instead of being inherited. Subprocesses can't escape the session this way, since the filter is inherited
+/// across forks.
+///
+/// Only the system calls of the x86-64 ABI are carried out by the session. Those of other ABIs, such as the system
+/// calls of 32-bit programs, fail with `ENOSYS`.
+///
+/// The processes can't be detached from, and can't run without the session: once it has ended, their system calls
+/// taking paths fail.
+pub struct SeccompSession {
+    root: u32,
+    events: EventQueue,
+}
+
+impl SeccompSession {
+    /// Start building a session which spawns `program`.
+    pub fn command<S: Into<OsString>>(program: S) -> SessionBuilder {
+        SessionBuilder::new(Target::Command {
+            program: program.into(),
+            args: Vec::new(),
+        })
+    }
+
+    /// The id of the process the session started out with.
+    pub fn root_pid(&self) -> u32 {
+        self.root
+    }
+
+    /// The ids of the processes whose system calls are currently being redirected.
+    pub fn pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.events.pids()
+    }
+
+    /// Always fails, since the processes would be left with a filter nobody answers.
+    pub fn detach(&mut self, _pid: u32) -> Result<(), SessionError> {
+        Err(SessionError::Io(io::Error::other(
+            "Processes in a seccomp session can't be detached from",
+        )))
+    }
+
+    /// Report that no process can be detached from as `EventKind::Log`.
+    pub fn detach_all(&mut self) {
+        let pids: Vec<_> = self.pids().collect();
+        for pid in pids {
+            if let Err(err) = self.detach(pid) {
+                self.events
+                    .push(linux::warning(pid, format!("Could not detach: {}", err)));
+            }
+        }
+    }
+
+    /// The processes in the session, including those which have terminated.
+    ///
+    /// The tree reflects every event which has been returned so far.
+    pub fn tree(&self) -> &ProcessTree {
+        self.events.tree()
+    }
+
+    /// Whether every process has terminated.
+    pub fn is_finished(&self) -> bool {
+        self.events.is_finished()
+    }
+
+    /// Wait for the next event.
+    ///
+    /// Returns `None` once every process has terminated.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.events.next(None)
+    }
+
+    /// Wait for the next event for no longer than `timeout`.
+    ///
+    /// Returns `None` if no event arrived in time, or if every process has terminated.
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.events.next(Some(timeout))
+    }
+}
+
+impl Iterator for SeccompSession {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event()
+    }
+}
+
+/// The result of a system call carried out on behalf of a process.
+enum Reply {
+    /// Let the process make the system call itself.
+    Continue,
+    Value(i64),
+    /// Add the file to the process, and return its descriptor there.
+    File {
+        file: File,
+        cloexec: bool,
+    },
+}
+
+/// Answers the notifications sent by the filter, and sends `Event`s about the processes to a `SeccompSession`.
+struct Supervisor {
+    root: u32,
+    listener: File,
+    processes: Processes,
+    /// The process each thread belongs to.
+    threads: HashMap<u32, u32>,
+    /// The executable and command line of processes which are about to execute another program, to tell whether
+    /// they have by their next system call.
+    executing: HashMap<u32, (Option<PathBuf>, Vec<u8>)>,
+    /// Processes which are still running, according to the last check.
+    running: HashSet<u32>,
+}
+
+impl Supervisor {
+    fn new(
+        root: u32,
+        listener: File,
+        mappings: MappingProfiles,
+        options: SessionOptions,
+        events: Sender<Event>,
+    ) -> Self {
+        let mut processes = Processes::new(mappings, options, events);
+        processes.add_root(root);
+        Self {
+            root,
+            listener,
+            processes,
+            threads: HashMap::new(),
+            executing: HashMap::new(),
+            running: iter::once(root).collect(),
+        }
+    }
+
+    /// Answer notifications until no process is left using the filter.
+    fn run(mut self) {
+        let mut last_check = Instant::now();
+        loop {
+            let mut poll_fd = libc::pollfd {
+                fd: self.listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let res =
+                unsafe { libc::poll(&mut poll_fd, 1, EXIT_POLL_INTERVAL.as_millis() as c_int) };
+            if res > 0 && poll_fd.revents & libc::POLLIN != 0 {
+                if let Some(notif) = self.receive() {
+                    self.handle(&notif);
+                }
+            } else if res > 0 && poll_fd.revents & libc::POLLHUP != 0 {
+                break;
+            }
+
+            if last_check.elapsed() >= EXIT_POLL_INTERVAL {
+                self.check_exits();
+                last_check = Instant::now();
+            }
+        }
+
+        let mut status = 0;
+        if unsafe { libc::waitpid(self.root as c_int, &mut status, 0) } != -1 {
+            self.processes
+                .exited(self.root, Some(linux::exit_code(status)));
+        }
+        for pid in self.running.drain() {
+            self.processes.exited(pid, None);
+        }
+    }
+
+    fn receive(&self) -> Option<notif::Notif> {
+        // The kernel insists on a zeroed buffer.
+        let mut notif: notif::Notif = unsafe { mem::zeroed() };
+        let res = unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                notif::IOCTL_NOTIF_RECV,
+                &mut notif as *mut notif::Notif,
+            )
+        };
+        // Fails if the process was killed before the notification was received.
+        if res == -1 {
+            None
+        } else {
+            Some(notif)
+        }
+    }
+
+    fn handle(&mut self, notif: &notif::Notif) {
+        let tid = notif.pid;
+        let pid = match self.threads.get(&tid) {
+            Some(pid) => *pid,
+            None => {
+                let pid = linux::status_field(tid, "Tgid:").unwrap_or(tid);
+                self.threads.insert(tid, pid);
+                pid
+            }
+        };
+        if !self.processes.contains(pid) {
+            self.running.insert(pid);
+            self.processes.add_subprocess(pid, tid);
+        } else if let Some(before) = self.executing.remove(&pid) {
+            if identify(pid) != before {
+                self.threads.retain(|_, thread_pid| *thread_pid != pid);
+                self.threads.insert(tid, pid);
+                self.processes.exec(pid);
+            }
+        }
+
+        let nr = notif.data.nr as i64;
+        if nr == sys::EXECVE || nr == sys::EXECVEAT {
+            self.executing.insert(pid, identify(pid));
+            self.reply(notif.id, Ok(Reply::Continue));
+            return;
+        }
+
+        let (dirfd_index, path_index) = match sys::path_argument(nr) {
+            Some(indices) => indices,
+            None => return self.reply(notif.id, Ok(Reply::Continue)),
+        };
+        let args = &notif.data.args;
+        let dirfd = dirfd_index.map_or(libc::AT_FDCWD, |index| args[index] as c_int);
+        let (path, mapped) = match self.processes.map(tid, pid, dirfd, args[path_index]) {
+            Some(paths) => paths,
+            None => return self.reply(notif.id, Ok(Reply::Continue)),
+        };
+        // The thread may have been killed, and its id reused, while the path was being read.
+        if !self.is_valid(notif.id) {
+            return;
+        }
+
+        let res = emulate(tid, nr, args, &mapped);
+        if res.is_ok() {
+            self.processes.redirected(pid, &path, &mapped);
+        }
+        self.reply(notif.id, res);
+    }
+
+    fn is_valid(&self, id: u64) -> bool {
+        let res = unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                notif::IOCTL_NOTIF_ID_VALID,
+                &id as *const u64,
+            )
+        };
+        res == 0
+    }
+
+    /// Answer a notification. Failures mean that the thread was killed in the meantime, and are ignored.
+    fn reply(&self, id: u64, res: io::Result<Reply>) {
+        let mut resp = notif::Resp {
+            id,
+            val: 0,
+            error: 0,
+            flags: 0,
+        };
+        match res {
+            Ok(Reply::Continue) => resp.flags = notif::FLAG_CONTINUE,
+            Ok(Reply::Value(val)) => resp.val = val,
+            Ok(Reply::File { file, cloexec }) => {
+                let addfd = notif::Addfd {
+                    id,
+                    flags: notif::ADDFD_FLAG_SEND,
+                    srcfd: file.as_raw_fd() as u32,
+                    newfd: 0,
+                    newfd_flags: if cloexec { libc::O_CLOEXEC as u32 } else { 0 },
+                };
+                let res = unsafe {
+                    libc::ioctl(
+                        self.listener.as_raw_fd(),
+                        notif::IOCTL_NOTIF_ADDFD,
+                        &addfd as *const notif::Addfd,
+                    )
+                };
+                // Adding the file answers the notification, unless it failed.
+                if res != -1 {
+                    return;
+                }
+                resp.error = -io::Error::last_os_error()
+                    .raw_os_error()
+                    .unwrap_or(libc::EIO);
+            }
+            Err(err) => resp.error = -err.raw_os_error().unwrap_or(libc::EIO),
+        }
+        unsafe {
+            libc::ioctl(
+                self.listener.as_raw_fd(),
+                notif::IOCTL_NOTIF_SEND,
+                &mut resp as *mut notif::Resp,
+            );
+        }
+    }
+
+    /// Report the processes which have terminated since the last check.
+    fn check_exits(&mut self) {
+        let mut status = 0;
+        if self.running.contains(&self.root)
+            && unsafe { libc::waitpid(self.root as c_int, &mut status, libc::WNOHANG) }
+                == self.root as c_int
+        {
+            self.running.remove(&self.root);
+            self.processes
+                .exited(self.root, Some(linux::exit_code(status)));
+        }
+
+        let exited: Vec<_> = self
+            .running
+            .iter()
+            .copied()
+            .filter(|pid| *pid != self.root && !is_alive(*pid))
+            .collect();
+        for pid in exited {
+            self.running.remove(&pid);
+            self.threads.retain(|_, thread_pid| *thread_pid != pid);
+            self.executing.remove(&pid);
+            self.processes.exited(pid, None);
+        }
+    }
+}
+
+/// Carry out a system call on behalf of a thread, with `mapped` in place of the path it passed.
+///
+/// The system call is made with the credentials of the session rather than those of the thread.
+fn emulate(tid: u32, nr: i64, args: &[u64; 6], mapped: &Path) -> io::Result<Reply> {
+    let path = CString::new(mapped.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let path = path.as_ptr();
+    let at_fdcwd = libc::AT_FDCWD as c_long;
+
+    match nr {
+        sys::OPEN => open(path, args[1], args[2]),
+        sys::OPENAT => open(path, args[2], args[3]),
+        sys::OPENAT2 => {
+            // The start of a `struct open_how`: its flags and mode.
+            let mut how = [0u8; 16];
+            linux::read_memory(tid, args[2], &mut how)?;
+            let mut flags = [0u8; 8];
+            let mut mode = [0u8; 8];
+            flags.copy_from_slice(&how[..8]);
+            mode.copy_from_slice(&how[8..]);
+            open(path, u64::from_ne_bytes(flags), u64::from_ne_bytes(mode))
+        }
+        sys::STAT | sys::LSTAT | sys::NEWFSTATAT => {
+            let (buf, flags) = match nr {
+                sys::STAT => (args[1], 0),
+                sys::LSTAT => (args[1], libc::AT_SYMLINK_NOFOLLOW as u64),
+                _ => (args[2], args[3]),
+            };
+            let mut stat = [0u8; mem::size_of::<libc::stat>()];
+            check(unsafe {
+                libc::syscall(sys::NEWFSTATAT, at_fdcwd, path, stat.as_mut_ptr(), flags)
+            })?;
+            linux::write_memory(tid, buf, &stat)?;
+            Ok(Reply::Value(0))
+        }
+        sys::STATX => {
+            // The size of `struct statx`.
+            let mut statx = [0u8; 256];
+            check(unsafe {
+                libc::syscall(
+                    sys::STATX,
+                    at_fdcwd,
+                    path,
+                    args[2],
+                    args[3],
+                    statx.as_mut_ptr(),
+                )
+            })?;
+            linux::write_memory(tid, args[4], &statx)?;
+            Ok(Reply::Value(0))
+        }
+        sys::ACCESS => check(unsafe { libc::syscall(sys::FACCESSAT, at_fdcwd, path, args[1]) })
+            .map(Reply::Value),
+        sys::FACCESSAT => check(unsafe { libc::syscall(sys::FACCESSAT, at_fdcwd, path, args[2]) })
+            .map(Reply::Value),
+        sys::FACCESSAT2 => {
+            check(unsafe { libc::syscall(sys::FACCESSAT2, at_fdcwd, path, args[2], args[3]) })
+                .map(Reply::Value)
+        }
+        sys::READLINK | sys::READLINKAT => {
+            let (buf, size) = match nr {
+                sys::READLINK => (args[1], args[2]),
+                _ => (args[2], args[3]),
+            };
+            let mut target = vec![0u8; (size as usize).min(libc::PATH_MAX as usize)];
+            let len = check(unsafe {
+                libc::readlink(path, target.as_mut_ptr() as *mut c_char, target.len()) as c_long
+            })?;
+            linux::write_memory(tid, buf, &target[..len as usize])?;
+            Ok(Reply::Value(len))
+        }
+        sys::GETXATTR | sys::LGETXATTR => {
+            let name = CString::new(linux::read_c_string(tid, args[1])?)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+            let mut value = vec![0u8; (args[3] as usize).min(XATTR_MAX)];
+            let len = check(unsafe {
+                libc::syscall(nr, path, name.as_ptr(), value.as_mut_ptr(), value.len())
+            })?;
+            linux::write_memory(tid, args[2], &value[..len as usize])?;
+            Ok(Reply::Value(len))
+        }
+        sys::LISTXATTR | sys::LLISTXATTR => {
+            let mut list = vec![0u8; (args[2] as usize).min(XATTR_MAX)];
+            let len = check(unsafe { libc::syscall(nr, path, list.as_mut_ptr(), list.len()) })?;
+            linux::write_memory(tid, args[1], &list[..len as usize])?;
+            Ok(Reply::Value(len))
+        }
+        sys::TRUNCATE => check(unsafe { libc::truncate(path, args[1] as libc::off_t) as c_long })
+            .map(Reply::Value),
+        _ => Ok(Reply::Continue),
+    }
+}
+
+fn open(path: *const c_char, flags: u64, mode: u64) -> io::Result<Reply> {
+    let flags = flags as c_int;
+    let fd = check(unsafe { libc::open(path, flags | libc::O_CLOEXEC, mode as c_uint) as c_long })?;
+    Ok(Reply::File {
+        file: unsafe { File::from_raw_fd(fd as RawFd) },
+        cloexec: flags & libc::O_CLOEXEC != 0,
+    })
+}
+
+fn check(res: c_long) -> io::Result<i64> {
+    if res == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+/// The executable and raw command line of a process, which change when it executes another program.
+fn identify(pid: u32) -> (Option<PathBuf>, Vec<u8>) {
+    (
+        fs::read_link(format!("/proc/{}/exe", pid)).ok(),
+        fs::read(format!("/proc/{}/cmdline", pid)).unwrap_or_default(),
+    )
+}
+
+/// Whether the process exists and hasn't terminated. Processes which aren't children of the session can't be waited
+/// for, so they are seen as terminated once they are zombies.
+fn is_alive(pid: u32) -> bool {
+    let stat = match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat,
+        Err(_) => return false,
+    };
+    // The state follows the executable's name, which may contain anything, including parentheses.
+    match stat
+        .rfind(')')
+        .and_then(|end| stat[end + 1..].trim_start().chars().next())
+    {
+        Some(state) => state != 'Z' && state != 'X',
+        None => false,
+    }
+}
+
+/// Spawn `program` with a filter handing the system calls taking paths to the session. Returns its pid and the file
+/// descriptor receiving the filter's notifications.
+fn spawn(program: &OsStr, args: &[OsString]) -> io::Result<(u32, File)> {
+    let args = linux::c_strings(program, args)?;
+    let argv: Vec<*const c_char> = args
+        .iter()
+        .map(|arg| arg.as_ptr())
+        .chain(iter::once(ptr::null()))
+        .collect();
+    // Everything the child needs is prepared before forking, since it may only do async-signal-safe things.
+    let filter = filter::filter(&SYSCALLS, filter::RET_USER_NOTIF);
+    let prog = filter::SockFprog {
+        len: filter.len() as u16,
+        filter: filter.as_ptr(),
+    };
+
+    // The child installs the filter, and sends the listener back over this socket.
+    let mut sockets = [0; 2];
+    if unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            sockets.as_mut_ptr(),
+        )
+    } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    let parent_socket = unsafe { File::from_raw_fd(sockets[0]) };
+    let child_socket = unsafe { File::from_raw_fd(sockets[1]) };
+
+    // An errno, or 0 if the listener is attached.
+    let mut errno: c_int = 0;
+    let mut iov = libc::iovec {
+        iov_base: &mut errno as *mut c_int as *mut c_void,
+        iov_len: mem::size_of::<c_int>(),
+    };
+    // Large and aligned enough for a single `SCM_RIGHTS` message.
+    let mut control = [0u64; 4];
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+    let cmsg = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<c_int>() as c_uint) as _;
+        msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<c_int>() as c_uint) as _;
+        cmsg
+    };
+
+    let pid = unsafe { libc::fork() };
+    if pid == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if pid == 0 {
+        unsafe {
+            libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0);
+            let listener = libc::syscall(
+                sys::SECCOMP as c_long,
+                filter::SET_MODE_FILTER,
+                filter::FLAG_NEW_LISTENER,
+                &prog as *const filter::SockFprog,
+            );
+            if listener == -1 {
+                *(iov.iov_base as *mut c_int) = *libc::__errno_location();
+                msg.msg_control = ptr::null_mut();
+                msg.msg_controllen = 0;
+            } else {
+                ptr::write(libc::CMSG_DATA(cmsg) as *mut c_int, listener as c_int);
+            }
+            libc::sendmsg(child_socket.as_raw_fd(), &msg, 0);
+            if listener == -1 {
+                libc::_exit(127);
+            }
+            libc::close(listener as c_int);
+            libc::execvp(argv[0], argv.as_ptr());
+            libc::_exit(127);
+        }
+    }
+    drop(child_socket);
+
+    let received = unsafe { libc::recvmsg(parent_socket.as_raw_fd(), &mut msg, 0) };
+    let cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    let err = if received == -1 {
+        io::Error::last_os_error()
+    } else if errno != 0 {
+        io::Error::from_raw_os_error(errno)
+    } else if received == 0 || cmsg.is_null() {
+        io::Error::other("The process exited before installing its filter")
+    } else {
+        let listener = unsafe { ptr::read(libc::CMSG_DATA(cmsg) as *const c_int) };
+        return Ok((pid as u32, unsafe { File::from_raw_fd(listener) }));
+    };
+    // The child exits by itself if it couldn't install the filter.
+    unsafe { libc::waitpid(pid, ptr::null_mut(), 0) };
+    Err(err)
+}
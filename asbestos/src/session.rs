@@ -0,0 +1,698 @@
+//! Spawning or attaching to a process, injecting the payload into it and its subprocesses, and receiving the
+//! messages they send.
+
+#[cfg(windows)]
+use std::{
+    collections::VecDeque, env, io::BufReader, iter, os::windows::process::CommandExt,
+    process::Command, thread, time::Duration,
+};
+use std::{error::Error, ffi::OsString, fmt, io, path::PathBuf};
+
+use asbestos_shared::{
+    cache::CacheConfig,
+    profiles::MappingProfiles,
+    protocol::{
+        HookProfile, LogMessage, Mappings, PanicPolicy, PanicReport, ProcessInfo, ProcessSpawned,
+        ProtocolError, TraceEvent,
+    },
+    rules::{SubprocessRules, Verdict},
+};
+#[cfg(windows)]
+use asbestos_shared::{
+    named_pipe::{ConnectingServer, PipeOptions, PipeServer},
+    named_pipe_name,
+    protocol::{Connection, Message, StartupInfo},
+    PipeEnd,
+};
+
+#[cfg(windows)]
+use crate::{
+    mux::{Multiplexer, Received},
+    process,
+    tree::ProcessTree,
+};
+
+#[cfg(windows)]
+const CREATE_SUSPENDED: u32 = 0x00000004;
+#[cfg(windows)]
+const DETACHED_PROCESS: u32 = 0x00000008;
+
+/// The time a payload is given to connect to asbestos after it has been injected.
+const DEFAULT_CONNECT_TIMEOUT_MS: u32 = 3000;
+
+#[cfg(windows)]
+pub type PipeConnection = Connection<BufReader<PipeServer>, PipeServer>;
+
+/// The process a `Session` starts out with.
+#[derive(Clone, Debug)]
+pub enum Target {
+    /// Create a new process with the `CREATE_SUSPENDED` flag. The process is allowed to begin execution once the
+    /// payload has been initialized.
+    Command {
+        program: OsString,
+        args: Vec<OsString>,
+    },
+    /// Inject the payload into a process which is already running.
+    Pid(u32),
+}
+
+#[derive(Clone, Debug)]
+pub struct SessionOptions {
+    /// Don't hook subprocesses created by the hooked processes.
+    pub dont_hook_subprocesses: bool,
+    /// Decides which subprocesses are hooked, unless `dont_hook_subprocesses` is set.
+    pub subprocess_rules: SubprocessRules,
+    /// Immediately show the consoles of the hooked processes.
+    pub show_console: bool,
+    /// Have payloads send a `TraceEvent` for every call to a hooked function.
+    pub trace_hooks: bool,
+    /// Have payloads measure the time spent inside hooks.
+    pub profile_hooks: bool,
+    /// What payloads do after panicking.
+    pub panic_policy: PanicPolicy,
+    /// The payload to inject. Defaults to `asbestos_payload.dll` next to the current executable.
+    pub payload: Option<PathBuf>,
+    pub connect_timeout_ms: u32,
+    /// Where contents which don't exist on disk, such as the members of archives, are put to be opened.
+    pub cache: CacheConfig,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            dont_hook_subprocesses: false,
+            subprocess_rules: SubprocessRules::default(),
+            show_console: false,
+            trace_hooks: false,
+            profile_hooks: false,
+            panic_policy: PanicPolicy::default(),
+            payload: None,
+            connect_timeout_ms: DEFAULT_CONNECT_TIMEOUT_MS,
+            cache: CacheConfig::default(),
+        }
+    }
+}
+
+/// Configures and starts a `Session`.
+///
+// `Session` only exists on Windows.
+#[cfg_attr(windows, doc = "```no_run")]
+#[cfg_attr(not(windows), doc = "```ignore")]
+/// use asbestos::{Session, shared::protocol::Mappings};
+///
+/// let session = Session::command("game.exe")
+///     .arg("--windowed")
+///     .mappings(Mappings::default())
+///     .start()?;
+/// for event in session {
+///     println!("{:?}", event);
+/// }
+/// # Ok::<(), asbestos::SessionError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct SessionBuilder {
+    pub(crate) target: Target,
+    pub(crate) mappings: MappingProfiles,
+    pub(crate) options: SessionOptions,
+}
+
+impl SessionBuilder {
+    pub fn new(target: Target) -> Self {
+        Self {
+            target,
+            mappings: MappingProfiles::default(),
+            options: SessionOptions::default(),
+        }
+    }
+
+    /// Add an argument to the command. Does nothing if the target is a pid.
+    pub fn arg<S: Into<OsString>>(mut self, arg: S) -> Self {
+        if let Target::Command { args, .. } = &mut self.target {
+            args.push(arg.into());
+        }
+        self
+    }
+
+    /// Add arguments to the command. Does nothing if the target is a pid.
+    pub fn args<I, S>(mut self, new_args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        if let Target::Command { args, .. } = &mut self.target {
+            args.extend(new_args.into_iter().map(Into::into));
+        }
+        self
+    }
+
+    /// Give every process the same mappings.
+    pub fn mappings(mut self, mappings: Mappings) -> Self {
+        self.mappings = mappings.into();
+        self
+    }
+
+    /// Give each process the mappings of the profile which applies to it.
+    pub fn mapping_profiles(mut self, mapping_profiles: MappingProfiles) -> Self {
+        self.mappings = mapping_profiles;
+        self
+    }
+
+    pub fn options(mut self, options: SessionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn dont_hook_subprocesses(mut self, dont_hook_subprocesses: bool) -> Self {
+        self.options.dont_hook_subprocesses = dont_hook_subprocesses;
+        self
+    }
+
+    pub fn subprocess_rules(mut self, subprocess_rules: SubprocessRules) -> Self {
+        self.options.subprocess_rules = subprocess_rules;
+        self
+    }
+
+    pub fn show_console(mut self, show_console: bool) -> Self {
+        self.options.show_console = show_console;
+        self
+    }
+
+    pub fn trace_hooks(mut self, trace_hooks: bool) -> Self {
+        self.options.trace_hooks = trace_hooks;
+        self
+    }
+
+    pub fn profile_hooks(mut self, profile_hooks: bool) -> Self {
+        self.options.profile_hooks = profile_hooks;
+        self
+    }
+
+    pub fn panic_policy(mut self, panic_policy: PanicPolicy) -> Self {
+        self.options.panic_policy = panic_policy;
+        self
+    }
+
+    pub fn payload<P: Into<PathBuf>>(mut self, payload: P) -> Self {
+        self.options.payload = Some(payload.into());
+        self
+    }
+
+    pub fn cache(mut self, cache: CacheConfig) -> Self {
+        self.options.cache = cache;
+        self
+    }
+
+    /// Spawn the target process if need be, and inject the payload into it.
+    #[cfg(windows)]
+    pub fn start(self) -> Result<Session, SessionError> {
+        let (config, root) = self.launch()?;
+        let pid = root.pid;
+
+        let mut session = Session {
+            config,
+            root: pid,
+            connections: Multiplexer::new(),
+            pending: VecDeque::new(),
+            tree: ProcessTree::new(),
+        };
+
+        session
+            .connections
+            .watch(pid, move || process::wait_for_exit(pid));
+        let (profile, startup_info) = session.config.startup_info(
+            0,
+            root.main_thread_suspended,
+            0,
+            &root.executable,
+            &root.command_line,
+        );
+        session.pending.push_back(Event {
+            pid,
+            kind: EventKind::MappingProfileSelected { profile },
+        });
+        let connection = session.inject_and_connect(pid, startup_info)?;
+        session.connections.add(pid, connection);
+
+        Ok(session)
+    }
+
+    /// Locate the payload and spawn the target process if need be.
+    #[cfg(windows)]
+    pub(crate) fn launch(self) -> Result<(SessionConfig, Root), SessionError> {
+        let payload = match self.options.payload.clone() {
+            Some(payload) => payload,
+            None => {
+                let mut payload = env::current_exe()?;
+                payload.set_file_name("asbestos_payload");
+                payload.set_extension("dll");
+                payload
+            }
+        };
+
+        let root = match self.target {
+            Target::Command { program, args } => {
+                // TODO: Get hold of the spawned process's main thread's id here.
+                let process = Command::new(&program)
+                    .args(&args)
+                    .creation_flags(CREATE_SUSPENDED | DETACHED_PROCESS)
+                    .spawn()?;
+                let command_line = iter::once(&program)
+                    .chain(args.iter())
+                    .map(|arg| arg.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                Root {
+                    pid: process.id(),
+                    main_thread_suspended: true,
+                    executable: program.to_string_lossy().into_owned(),
+                    command_line,
+                }
+            }
+            Target::Pid(pid) => Root {
+                pid,
+                main_thread_suspended: false,
+                executable: process::executable_path(pid)
+                    .map(|path| path.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                command_line: String::new(),
+            },
+        };
+
+        let config = SessionConfig {
+            mappings: self.mappings,
+            options: self.options,
+            payload,
+        };
+
+        Ok((config, root))
+    }
+}
+
+/// The process a session starts out with, once it is running.
+#[cfg(windows)]
+pub(crate) struct Root {
+    pub(crate) pid: u32,
+    pub(crate) main_thread_suspended: bool,
+    pub(crate) executable: String,
+    /// Empty if the session attached to a running process.
+    pub(crate) command_line: String,
+}
+
+/// Everything needed to hook another process in a session.
+#[cfg(windows)]
+pub(crate) struct SessionConfig {
+    pub(crate) mappings: MappingProfiles,
+    pub(crate) options: SessionOptions,
+    pub(crate) payload: PathBuf,
+}
+
+#[cfg(windows)]
+impl SessionConfig {
+    /// Describe how the payload should set itself up in a process, along with the name of the mapping profile which
+    /// applies to the process.
+    pub(crate) fn startup_info(
+        &self,
+        tid: u32,
+        main_thread_suspended: bool,
+        depth: u32,
+        executable: &str,
+        command_line: &str,
+    ) -> (Option<String>, StartupInfo) {
+        let (profile, mappings) = self.mappings.select(executable, command_line, depth);
+        let startup_info = StartupInfo {
+            main_thread_suspended,
+            dont_hook_subprocesses: self.options.dont_hook_subprocesses,
+            subprocess_rules: self.options.subprocess_rules.clone(),
+            depth,
+            show_console: self.options.show_console,
+            trace_hooks: self.options.trace_hooks,
+            profile_hooks: self.options.profile_hooks,
+            panic_policy: self.options.panic_policy,
+            mappings,
+            cache: self.options.cache.clone(),
+            tid,
+        };
+        (profile.map(str::to_owned), startup_info)
+    }
+
+    /// Decide whether to hook a subprocess at the given depth.
+    pub(crate) fn verdict(&self, ps: &ProcessSpawned, depth: u32) -> Verdict {
+        self.options
+            .subprocess_rules
+            .evaluate(&ps.executable, &ps.command_line, depth)
+    }
+}
+
+/// A set of hooked processes.
+///
+/// Iterating over a `Session` yields the `Event`s sent by its processes, blocking until the next one arrives. The
+/// iterator ends once every process has disconnected and terminated.
+#[cfg(windows)]
+pub struct Session {
+    config: SessionConfig,
+    root: u32,
+    connections: Multiplexer<PipeServer>,
+    pending: VecDeque<Event>,
+    tree: ProcessTree,
+}
+
+#[cfg(windows)]
+impl Session {
+    pub fn builder(target: Target) -> SessionBuilder {
+        SessionBuilder::new(target)
+    }
+
+    /// Start building a session which spawns `program`.
+    pub fn command<S: Into<OsString>>(program: S) -> SessionBuilder {
+        SessionBuilder::new(Target::Command {
+            program: program.into(),
+            args: Vec::new(),
+        })
+    }
+
+    /// Start building a session which attaches to the process with the given id.
+    pub fn pid(pid: u32) -> SessionBuilder {
+        SessionBuilder::new(Target::Pid(pid))
+    }
+
+    /// The id of the process the session started out with.
+    pub fn root_pid(&self) -> u32 {
+        self.root
+    }
+
+    /// The ids of the processes which are currently connected.
+    pub fn pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.connections.pids()
+    }
+
+    /// Ask the payload in the given process to disable its hooks and unload itself.
+    ///
+    /// The process keeps running. Its payload sends `EventKind::ProcessDetach` once it is about to be unloaded.
+    pub fn detach(&mut self, pid: u32) -> Result<(), SessionError> {
+        Ok(self.connections.send(pid, Message::Detach)?)
+    }
+
+    /// Ask every payload to unload itself. Failures are reported as `EventKind::ProtocolError`.
+    pub fn detach_all(&mut self) {
+        let pids: Vec<_> = self.pids().collect();
+        for pid in pids {
+            if let Err(err) = self.connections.send(pid, Message::Detach) {
+                self.pending.push_back(Event {
+                    pid,
+                    kind: EventKind::ProtocolError(err),
+                });
+            }
+        }
+    }
+
+    /// The processes in the session, including those which have terminated.
+    ///
+    /// The tree reflects every event which has been returned so far.
+    pub fn tree(&self) -> &ProcessTree {
+        &self.tree
+    }
+
+    /// Whether every process has disconnected and terminated.
+    pub fn is_finished(&self) -> bool {
+        self.pending.is_empty() && self.connections.is_empty()
+    }
+
+    /// Wait for the next event.
+    ///
+    /// Returns `None` once every process has disconnected and terminated.
+    pub fn next_event(&mut self) -> Option<Event> {
+        self.next_event_impl(None)
+    }
+
+    /// Wait for the next event for no longer than `timeout`.
+    ///
+    /// Returns `None` if no event arrived in time, or if every process has disconnected and terminated.
+    pub fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.next_event_impl(Some(timeout))
+    }
+
+    fn next_event_impl(&mut self, timeout: Option<Duration>) -> Option<Event> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                self.tree.apply(&event);
+                return Some(event);
+            }
+            match self.connections.recv(timeout)? {
+                Received::Message(pid, res) => self.handle(pid, res),
+                Received::Exited(pid, exit_code) => self.pending.push_back(Event {
+                    pid,
+                    kind: EventKind::ProcessExited { exit_code },
+                }),
+            }
+        }
+    }
+
+    fn handle(&mut self, pid: u32, res: Result<Message, ProtocolError>) {
+        match res {
+            Ok(msg) => {
+                let spawned = match &msg {
+                    Message::ProcessSpawned(ps) => Some(ps.clone()),
+                    _ => None,
+                };
+                if let Message::ProcessDetach = &msg {
+                    self.connections.remove(pid);
+                }
+                if let Some(kind) = EventKind::from_message(msg) {
+                    self.pending.push_back(Event { pid, kind });
+                }
+                if let Some(ps) = spawned {
+                    self.spawned(pid, ps);
+                }
+            }
+            Err(err) => {
+                // Nothing more will be read from a connection which is closed, whether or not that was expected.
+                if matches!(
+                    err,
+                    ProtocolError::Disconnected | ProtocolError::ConnectionLost
+                ) {
+                    self.connections.remove(pid);
+                }
+                self.pending.push_back(Event {
+                    pid,
+                    kind: EventKind::ProtocolError(err),
+                });
+            }
+        }
+    }
+
+    /// Decide whether to hook a subprocess of `parent`, and hook it if so.
+    fn spawned(&mut self, parent: u32, ps: ProcessSpawned) {
+        let pid = ps.pid;
+        self.connections
+            .watch(pid, move || process::wait_for_exit(pid));
+
+        let depth = self.tree.depth(parent) + 1;
+        let verdict = self.config.verdict(&ps, depth);
+        let hook = verdict.hook;
+        self.pending.push_back(Event {
+            pid,
+            kind: EventKind::SubprocessVerdict(verdict),
+        });
+
+        if hook {
+            let (profile, startup_info) = self.config.startup_info(
+                ps.tid,
+                ps.suspended,
+                depth,
+                &ps.executable,
+                &ps.command_line,
+            );
+            self.pending.push_back(Event {
+                pid,
+                kind: EventKind::MappingProfileSelected { profile },
+            });
+            match self.inject_and_connect(pid, startup_info) {
+                Ok(connection) => self.connections.add(pid, connection),
+                Err(err) => self.pending.push_back(Event {
+                    pid,
+                    kind: EventKind::InjectionFailed(err),
+                }),
+            }
+        } else if ps.suspended {
+            if let Err(err) = process::resume_thread(ps.tid) {
+                self.pending.push_back(Event {
+                    pid,
+                    kind: EventKind::ResumeFailed(err),
+                });
+            }
+        }
+    }
+
+    fn inject_and_connect(
+        &self,
+        pid: u32,
+        startup_info: StartupInfo,
+    ) -> Result<PipeConnection, SessionError> {
+        let dll = self.config.payload.clone();
+
+        let (connecting_server_rx, connecting_server_tx) = create_connecting_pipe_server_pair(pid)?;
+
+        let injection_thread = thread::spawn(move || {
+            syringe::inject_dll(pid, &dll).map_err(|err| format!("{:?}", err))
+        });
+
+        let (pipe_rx, pipe_tx) = wait_for_pipe_connection_with_timeout_ms(
+            pid,
+            connecting_server_rx,
+            connecting_server_tx,
+            self.config.options.connect_timeout_ms,
+        )?;
+        let mut connection = Connection::new(BufReader::new(pipe_rx), pipe_tx);
+        connection.write_message(Message::StartupInfo(startup_info))?;
+        match injection_thread.join() {
+            Ok(Ok(_)) => {}
+            Ok(Err(err)) => return Err(SessionError::Injection(err)),
+            Err(_) => {
+                return Err(SessionError::Injection(
+                    "The injection thread panicked".to_owned(),
+                ))
+            }
+        }
+
+        Ok(connection)
+    }
+}
+
+#[cfg(windows)]
+impl Iterator for Session {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_event()
+    }
+}
+
+/// Something which happened in one of the processes of a `Session`.
+#[derive(Debug)]
+pub struct Event {
+    /// The id of the process the event concerns.
+    pub pid: u32,
+    pub kind: EventKind,
+}
+
+#[derive(Debug)]
+pub enum EventKind {
+    Log(LogMessage),
+    /// The payload has finished its initalization routine.
+    Initialized,
+    /// The payload encountered an error in its initialization routine.
+    InitializationFailed(String),
+    /// The process created a subprocess. Followed by a `SubprocessVerdict` for the subprocess.
+    ProcessSpawned(ProcessSpawned),
+    /// Whether the subprocess is hooked, according to `SessionOptions::subprocess_rules`. A `PtraceSession` also
+    /// sends it whenever a subprocess executes another program.
+    SubprocessVerdict(Verdict),
+    /// The payload is about to be injected with the mappings of the given profile, or the top-level mappings if no
+    /// profile applies to the process.
+    MappingProfileSelected {
+        profile: Option<String>,
+    },
+    /// A subprocess which isn't hooked could not be allowed to begin execution.
+    ResumeFailed(io::Error),
+    /// The payload described the process it was injected into.
+    ProcessInfo(ProcessInfo),
+    Trace(TraceEvent),
+    HookProfile(HookProfile),
+    /// The payload panicked, and followed `SessionOptions::panic_policy` afterwards.
+    Panic(PanicReport),
+    /// The payload was unloaded from the process.
+    ProcessDetach,
+    ProtocolError(ProtocolError),
+    /// The payload could not be injected into a subprocess.
+    InjectionFailed(SessionError),
+    /// The process terminated. This may arrive before or after its payload's last message.
+    ProcessExited {
+        /// `None` if the exit code could not be determined.
+        exit_code: Option<u32>,
+    },
+}
+
+#[cfg(windows)]
+impl EventKind {
+    pub(crate) fn from_message(message: Message) -> Option<Self> {
+        Some(match message {
+            Message::StartupInfo(_) | Message::Detach => return None,
+            Message::LogMessage(log_message) => Self::Log(log_message),
+            Message::Initialized => Self::Initialized,
+            Message::InitializationFailed(err) => Self::InitializationFailed(err),
+            Message::ProcessSpawned(ps) => Self::ProcessSpawned(ps),
+            Message::ProcessInfo(info) => Self::ProcessInfo(info),
+            Message::TraceEvent(trace_event) => Self::Trace(trace_event),
+            Message::HookProfile(hook_profile) => Self::HookProfile(hook_profile),
+            Message::Panic(report) => Self::Panic(report),
+            Message::ProcessDetach => Self::ProcessDetach,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    Io(io::Error),
+    Protocol(ProtocolError),
+    /// The payload could not be injected into the target.
+    Injection(String),
+    /// The payload didn't connect within `SessionOptions::connect_timeout_ms`.
+    ConnectTimeout {
+        pid: u32,
+        timeout_ms: u32,
+    },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::Protocol(err) => err.fmt(f),
+            Self::Injection(err) => write!(f, "Could not inject the payload: {}", err),
+            Self::ConnectTimeout { pid, timeout_ms } => {
+                write!(f, "{} did not connect within {} ms", pid, timeout_ms)
+            }
+        }
+    }
+}
+
+impl Error for SessionError {}
+
+impl From<io::Error> for SessionError {
+    fn from(from: io::Error) -> Self {
+        Self::Io(from)
+    }
+}
+
+impl From<ProtocolError> for SessionError {
+    fn from(from: ProtocolError) -> Self {
+        Self::Protocol(from)
+    }
+}
+
+#[cfg(windows)]
+fn create_connecting_pipe_server_pair(
+    pid: u32,
+) -> io::Result<(ConnectingServer, ConnectingServer)> {
+    let connecting_server_rx = PipeOptions::new(named_pipe_name(pid, PipeEnd::Rx)).single()?;
+    let connecting_server_tx = PipeOptions::new(named_pipe_name(pid, PipeEnd::Tx)).single()?;
+
+    Ok((connecting_server_rx, connecting_server_tx))
+}
+
+#[cfg(windows)]
+fn wait_for_pipe_connection_with_timeout_ms(
+    pid: u32,
+    connecting_server_rx: ConnectingServer,
+    connecting_server_tx: ConnectingServer,
+    timeout_ms: u32,
+) -> Result<(PipeServer, PipeServer), SessionError> {
+    let pipe_rx = connecting_server_rx
+        .wait_ms(timeout_ms)?
+        .map_err(|_| SessionError::ConnectTimeout { pid, timeout_ms })?;
+    let pipe_tx = connecting_server_tx
+        .wait_ms(timeout_ms)?
+        .map_err(|_| SessionError::ConnectTimeout { pid, timeout_ms })?;
+
+    Ok((pipe_rx, pipe_tx))
+}
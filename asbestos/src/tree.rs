@@ -0,0 +1,225 @@
+//! The parent/child relationships and lifecycles of the processes in a session.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+use crate::linux::creation_time;
+#[cfg(windows)]
+use crate::process::creation_time;
+use crate::session::{Event, EventKind};
+
+/// Everything asbestos knows about a single process.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProcessNode {
+    pub pid: u32,
+    /// The process which created this process, if it was created by a process in the session.
+    pub parent: Option<u32>,
+    /// The processes created by this process, in the order they were created.
+    pub children: Vec<u32>,
+    /// Reported by the payload of the parent when the process is created, and by the process's own payload once it
+    /// has been injected.
+    pub executable: Option<PathBuf>,
+    pub command_line: Option<String>,
+    /// When the process was created, or when asbestos learned about it if that can't be determined, such as when it
+    /// has already terminated.
+    pub started_at: SystemTime,
+    pub exited_at: Option<SystemTime>,
+    pub exit_code: Option<u32>,
+    /// Whether the payload has been initialized in the process.
+    pub hooked: bool,
+}
+
+impl ProcessNode {
+    fn new(pid: u32, parent: Option<u32>) -> Self {
+        Self {
+            pid,
+            parent,
+            children: Vec::new(),
+            executable: None,
+            command_line: None,
+            started_at: creation_time(pid).unwrap_or_else(SystemTime::now),
+            exited_at: None,
+            exit_code: None,
+            hooked: false,
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.exited_at.is_none()
+    }
+
+    /// How long the process ran for, or has been running for if it hasn't exited yet.
+    pub fn running_time(&self) -> Duration {
+        self.exited_at
+            .unwrap_or_else(SystemTime::now)
+            .duration_since(self.started_at)
+            .unwrap_or_default()
+    }
+}
+
+/// The processes of a session, arranged by which process created which.
+///
+/// `Session` keeps one of these up to date. Users of `AsyncSession` can build their own by feeding it every event.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ProcessTree {
+    nodes: BTreeMap<u32, ProcessNode>,
+    roots: Vec<u32>,
+}
+
+impl ProcessTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Update the tree with something which happened in the session.
+    ///
+    /// Events concerning processes which aren't in the tree yet add them as roots.
+    pub fn apply(&mut self, event: &Event) {
+        let pid = event.pid;
+        match &event.kind {
+            EventKind::ProcessSpawned(ps) => {
+                self.node_mut(pid);
+                self.insert_child(pid, ps.pid);
+                if let Some(child) = self.nodes.get_mut(&ps.pid) {
+                    child.executable = Some(PathBuf::from(&ps.executable));
+                    child.command_line = Some(ps.command_line.clone());
+                }
+            }
+            EventKind::ProcessInfo(info) => {
+                let node = self.node_mut(pid);
+                node.executable = Some(info.executable.clone());
+                node.command_line = Some(info.command_line.clone());
+            }
+            EventKind::Initialized => self.node_mut(pid).hooked = true,
+            EventKind::ProcessExited { exit_code } => {
+                let node = self.node_mut(pid);
+                node.exited_at = Some(SystemTime::now());
+                node.exit_code = *exit_code;
+            }
+            _ => {
+                self.node_mut(pid);
+            }
+        }
+    }
+
+    fn insert_child(&mut self, parent: u32, pid: u32) {
+        if self.nodes.contains_key(&pid) {
+            return;
+        }
+        self.nodes.insert(pid, ProcessNode::new(pid, Some(parent)));
+        if let Some(parent) = self.nodes.get_mut(&parent) {
+            parent.children.push(pid);
+        }
+    }
+
+    fn node_mut(&mut self, pid: u32) -> &mut ProcessNode {
+        let roots = &mut self.roots;
+        self.nodes.entry(pid).or_insert_with(|| {
+            roots.push(pid);
+            ProcessNode::new(pid, None)
+        })
+    }
+
+    /// How many processes separate a process from its root. Processes which aren't in the tree are considered roots.
+    pub fn depth(&self, pid: u32) -> u32 {
+        let mut depth = 0;
+        let mut pid = pid;
+        while let Some(parent) = self.nodes.get(&pid).and_then(|node| node.parent) {
+            depth += 1;
+            pid = parent;
+        }
+        depth
+    }
+
+    pub fn get(&self, pid: u32) -> Option<&ProcessNode> {
+        self.nodes.get(&pid)
+    }
+
+    /// The processes which weren't created by another process in the session.
+    pub fn roots(&self) -> impl Iterator<Item = &ProcessNode> + '_ {
+        self.roots.iter().filter_map(move |pid| self.nodes.get(pid))
+    }
+
+    pub fn children(&self, pid: u32) -> impl Iterator<Item = &ProcessNode> + '_ {
+        self.nodes
+            .get(&pid)
+            .into_iter()
+            .flat_map(|node| node.children.iter())
+            .filter_map(move |pid| self.nodes.get(pid))
+    }
+
+    /// Every process in the tree, ordered by pid.
+    pub fn iter(&self) -> impl Iterator<Item = &ProcessNode> + '_ {
+        self.nodes.values()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    fn fmt_node(
+        &self,
+        f: &mut fmt::Formatter,
+        node: &ProcessNode,
+        prefix: &str,
+        last: bool,
+        root: bool,
+    ) -> fmt::Result {
+        let (branch, indent) = match (root, last) {
+            (true, _) => ("", ""),
+            (false, false) => ("├─ ", "│  "),
+            (false, true) => ("└─ ", "   "),
+        };
+
+        write!(f, "{}{}{}", prefix, branch, node.pid)?;
+        match &node.executable {
+            Some(executable) => write!(f, " {}", executable.display())?,
+            None => write!(f, " <unknown>")?,
+        }
+        let running_time = node.running_time().as_secs_f64();
+        match (node.is_running(), node.exit_code) {
+            (true, _) => write!(f, " (running for {:.1}s", running_time)?,
+            (false, Some(exit_code)) => {
+                write!(f, " (exited with {} after {:.1}s", exit_code, running_time)?
+            }
+            (false, None) => write!(f, " (exited after {:.1}s", running_time)?,
+        }
+        if !node.hooked {
+            write!(f, ", not hooked")?;
+        }
+        writeln!(f, ")")?;
+        if let Some(command_line) = &node.command_line {
+            writeln!(f, "{}{}    {}", prefix, indent, command_line)?;
+        }
+
+        let prefix = format!("{}{}", prefix, indent);
+        let count = node.children.len();
+        for (n, child) in self.children(node.pid).enumerate() {
+            self.fmt_node(f, child, &prefix, n + 1 == count, false)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Draws the tree with one or two lines per process.
+impl fmt::Display for ProcessTree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for root in self.roots() {
+            self.fmt_node(f, root, "", true, true)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(windows, all(target_os = "linux", target_arch = "x86_64"))))]
+fn creation_time(_pid: u32) -> Option<SystemTime> {
+    None
+}
@@ -1,82 +1,326 @@
 use std::{
-    collections::HashMap,
-    env,
     fs::File,
-    io::BufReader,
-    os::windows::process::CommandExt,
+    io::{self, BufRead, BufReader, Write},
+    iter,
     path::{Path, PathBuf},
-    process::Command,
     sync::atomic::{AtomicBool, Ordering},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use structopt::StructOpt;
 
-use asbestos::shared::{
-    named_pipe::{ConnectingServer, PipeOptions, PipeServer},
-    named_pipe_name,
-    protocol::{Connection, Mappings, Message, ProtocolError, StartupInfo},
-    PipeEnd,
+use asbestos::{
+    shared::{
+        cache::{CacheConfig, ContentCache},
+        conflicts,
+        contents::Contents,
+        profiles::MappingProfiles,
+        protocol::{Mappings, PanicPolicy},
+        rules::{Rule, SubprocessRules},
+    },
+    EventKind, SessionBuilder, Target,
 };
 
+use crate::{
+    modlist::ModList,
+    output::{Output, OutputFormat},
+    process::ProcessEntry,
+    session::Backend,
+    sink::{LogConfig, Logger, SinkConfig},
+    trace::ChromeTrace,
+};
+
+mod modlist;
+#[cfg(target_os = "linux")]
+mod mount;
+mod output;
+mod process;
+mod profile;
+mod session;
+mod sink;
+mod trace;
+mod tree;
+
 static CTRL_C: AtomicBool = AtomicBool::new(false);
 
-const CREATE_SUSPENDED: u32 = 0x00000004;
-const DETACHED_PROCESS: u32 = 0x00000008;
+/// How long to wait for payloads to unload after Ctrl-C.
+const DETACH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often `inject --wait-for` looks for the process at first. Kept short so that the process doesn't get far before
+/// the payload is injected.
+const WAIT_FOR_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often `inject --wait-for` looks for the process at most. The interval is doubled every time the process isn't
+/// found, since taking a snapshot of every process isn't cheap.
+const WAIT_FOR_MAX_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 fn main() {
     let opts = dbg!(Opts::from_args());
 
-    ctrlc::set_handler(move || CTRL_C.store(true, Ordering::SeqCst))
-        .expect("Error setting Ctrl-C handler");
+    // Unparks the main thread in case it's waiting for Ctrl-C in `failed` or `mount`.
+    let main_thread = thread::current();
+    ctrlc::set_handler(move || {
+        CTRL_C.store(true, Ordering::SeqCst);
+        main_thread.unpark();
+    })
+    .expect("Error setting Ctrl-C handler");
 
     match opts.cmd {
         Cmd::Inject(opts) => inject(opts),
         Cmd::Wrap(opts) => wrap(opts),
+        Cmd::Tree => print_trees(),
+        Cmd::Conflicts(opts) => print_conflicts(opts),
+        #[cfg(target_os = "linux")]
+        Cmd::Mount(opts) => mount(opts),
     }
 }
 
 fn inject(opts: Inject) {
-    let mappings = match load_mappings(&opts.common.mappings) {
-        Ok(ok) => ok,
-        Err(_) => loop {},
-    };
-    inject_impl(
-        opts.pid,
-        InjectOpts {
-            main_thread_suspended: false,
-            dont_hook_subprocesses: opts.common.no_sub_hook,
-            show_console: true,
+    let pid = match (opts.pid, &opts.name, &opts.wait_for) {
+        (Some(pid), _, _) => pid,
+        (None, Some(name), _) => match find_process(name) {
+            Ok(pid) => pid,
+            Err(_) => return,
+        },
+        (None, None, Some(name)) => match wait_for_process(name) {
+            Ok(Some(pid)) => pid,
+            Ok(None) | Err(_) => return,
         },
-        mappings,
+        (None, None, None) => {
+            eprintln!("Pass a pid, --name or --wait-for");
+            return;
+        }
+    };
+    let session = SessionBuilder::new(Target::Pid(pid)).show_console(true);
+    run(session, &opts.common, Until::Interrupted);
+}
+
+/// Find the single running process named `name`, asking the user to choose if there are several.
+fn find_process(name: &str) -> Result<u32, ()> {
+    let mut candidates = process::find_by_name(name).map_err(|err| {
+        eprintln!("Could not enumerate processes: {}", err);
+    })?;
+    match candidates.len() {
+        0 => {
+            eprintln!(r#"No process named "{}" is running"#, name);
+            Err(())
+        }
+        1 => Ok(candidates.remove(0).pid),
+        _ => choose_process(name, &candidates),
+    }
+}
+
+fn choose_process(name: &str, candidates: &[ProcessEntry]) -> Result<u32, ()> {
+    eprintln!(
+        r#"{} processes named "{}" are running:"#,
+        candidates.len(),
+        name
     );
+    for (n, candidate) in candidates.iter().enumerate() {
+        match process::executable_path(candidate.pid) {
+            Some(path) => eprintln!("  [{}] {} {}", n + 1, candidate.pid, path.display()),
+            None => eprintln!("  [{}] {} {}", n + 1, candidate.pid, candidate.name),
+        }
+    }
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        eprint!("Inject into [1-{}]: ", candidates.len());
+        io::stderr().flush().ok();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            // Nobody is there to answer, so the pid has to be given explicitly.
+            Some(Err(_)) | None => {
+                eprintln!();
+                eprintln!("Pass the pid of one of the processes instead of --name");
+                return Err(());
+            }
+        };
+        match line.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= candidates.len() => return Ok(candidates[n - 1].pid),
+            _ => eprintln!("Enter a number between 1 and {}", candidates.len()),
+        }
+    }
+}
+
+/// Wait for a process named `name` to be running, which it may be already. Asks the user to choose if several are
+/// running when this function is called.
+///
+/// Returns `None` if interrupted with Ctrl-C.
+fn wait_for_process(name: &str) -> Result<Option<u32>, ()> {
+    let enumerate = || {
+        process::find_by_name(name).map_err(|err| {
+            eprintln!("Could not enumerate processes: {}", err);
+        })
+    };
+
+    let mut running = enumerate()?;
+    match running.len() {
+        0 => {}
+        1 => return Ok(Some(running.remove(0).pid)),
+        _ => return choose_process(name, &running).map(Some),
+    }
+
+    eprintln!(r#"Waiting for "{}" to start"#, name);
+    let mut interval = WAIT_FOR_POLL_INTERVAL;
+    loop {
+        if let Some(entry) = enumerate()?.into_iter().next() {
+            return Ok(Some(entry.pid));
+        }
+        if CTRL_C.load(Ordering::SeqCst) {
+            return Ok(None);
+        }
+        thread::sleep(interval);
+        interval = (interval * 2).min(WAIT_FOR_MAX_POLL_INTERVAL);
+    }
 }
 
 fn wrap(opts: Wrap) {
-    let mappings = match load_mappings(&opts.common.mappings) {
+    let session = SessionBuilder::new(Target::Command {
+        program: opts.command.into(),
+        args: Vec::new(),
+    })
+    .args(&opts.args)
+    .show_console(opts.show_console);
+    let until = if opts.wait_for_children {
+        Until::TreeExited
+    } else {
+        Until::RootExited
+    };
+    if let Some(exit_code) = run(session, &opts.common, until) {
+        // Exit codes on Windows are `u32`s, which `process::exit` passes on unchanged.
+        std::process::exit(exit_code as i32);
+    }
+}
+
+fn print_trees() {
+    let states = match tree::read_states() {
         Ok(ok) => ok,
-        Err(_) => loop {},
+        Err(err) => {
+            eprintln!("Could not read the process trees: {}", err);
+            return;
+        }
     };
-    // TODO: Get hold of the spawned process's main thread's id here.
-    let process = Command::new(&opts.command)
-        .args(opts.args)
-        .creation_flags(CREATE_SUSPENDED | DETACHED_PROCESS)
-        .spawn()
-        .unwrap();
-    let process_id = process.id();
-    inject_impl(
-        process_id,
-        InjectOpts {
-            main_thread_suspended: true,
-            dont_hook_subprocesses: opts.common.no_sub_hook,
-            show_console: opts.show_console,
-        },
-        mappings,
+    if states.is_empty() {
+        println!("No sessions are running");
+    }
+    for (cli_pid, tree) in states {
+        println!("Session run by {}", cli_pid);
+        print!("{}", tree);
+    }
+}
+
+fn print_conflicts(opts: Conflicts) {
+    let conflicts = match (&opts.mappings, &opts.mod_list) {
+        (Some(path), _) => mapping_conflicts(path, &opts),
+        (None, Some(path)) => mod_list_conflicts(path, &opts),
+        // Ruled out by structopt.
+        (None, None) => unreachable!(),
+    };
+    let conflicts = match conflicts {
+        Ok(ok) => ok,
+        Err(_) => return,
+    };
+
+    for (path, providers) in &conflicts {
+        println!("{}", path.display());
+        // The winner first, marked with an asterisk.
+        for (n, provider) in providers.iter().enumerate() {
+            let marker = if n == 0 { '*' } else { ' ' };
+            println!("  {} {}", marker, provider);
+        }
+    }
+    match conflicts.len() {
+        0 => println!("No file is overridden"),
+        1 => println!("1 file is overridden"),
+        n => println!("{} files are overridden", n),
+    }
+}
+
+/// Overridden files by their virtual path, along with a description of each file provided there. The winner comes
+/// first.
+type ConflictList = Vec<(PathBuf, Vec<String>)>;
+
+fn mapping_conflicts(path: &Path, opts: &Conflicts) -> Result<ConflictList, ()> {
+    let mappings = select_profile(load_mappings(path)?, opts.profile.as_deref())?;
+    Ok(find_conflicts(&mappings, opts)?
+        .into_iter()
+        .map(|conflict| {
+            let winner = match conflict.winning_mapping {
+                Some(mapping) => format!("mapping #{}: {}", mapping + 1, conflict.winner.display()),
+                // A folder which doesn't have the file is mapped over it.
+                None => format!("{} (which no mapping provides)", conflict.winner.display()),
+            };
+            let overridden = conflict.overridden.iter().rev().map(|provider| {
+                format!(
+                    "mapping #{}: {}",
+                    provider.mapping + 1,
+                    provider.path.display()
+                )
+            });
+            (
+                conflict.path,
+                iter::once(winner).chain(overridden).collect(),
+            )
+        })
+        .collect())
+}
+
+fn mod_list_conflicts(path: &Path, opts: &Conflicts) -> Result<ConflictList, ()> {
+    let mod_list = ModList::load(path)?;
+    let describe = |path: &Path| match mod_list.mod_of(path) {
+        Some(source) => format!("{}: {}", source.name(), path.display()),
+        None => path.display().to_string(),
+    };
+    // The mods which lose are in the order of the redirects, which goes from the last mod to the first.
+    Ok(find_conflicts(&mod_list.compile()?, opts)?
+        .into_iter()
+        .map(|conflict| {
+            let overridden = conflict.overridden.iter().map(|provider| &provider.path);
+            let providers = iter::once(&conflict.winner)
+                .chain(overridden)
+                .map(|path| describe(path))
+                .collect();
+            (conflict.path, providers)
+        })
+        .collect())
+}
+
+fn find_conflicts(mappings: &Mappings, opts: &Conflicts) -> Result<Vec<conflicts::Conflict>, ()> {
+    let cache = ContentCache::new(&opts.cache.config());
+    let (contents, errors) = Contents::index(mappings.iter(), cache);
+    for err in errors {
+        eprintln!("{}", err);
+    }
+    conflicts::find_conflicts(mappings, &contents, opts.ignore_identical).map_err(|err| {
+        eprintln!("Could not look for conflicts: {}", err);
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn mount(opts: Mount) {
+    let mappings = match load_mappings(&opts.mappings)
+        .and_then(|profiles| select_profile(profiles, opts.profile.as_deref()))
+    {
+        Ok(ok) => ok,
+        Err(_) => return,
+    };
+
+    println!(
+        "Mounting at {}, press Ctrl-C to unmount",
+        opts.mountpoint.display()
     );
+    if let Err(err) = mount::mount(mappings, &opts.cache.config(), &opts.mountpoint, || {
+        CTRL_C.load(Ordering::SeqCst)
+    }) {
+        eprintln!("Could not mount at {}: {}", opts.mountpoint.display(), err);
+    }
 }
 
-fn load_mappings(path: &Path) -> Result<Mappings, ()> {
+/// Load a mappings file, which is either a plain list of mappings or a set of mapping profiles.
+fn load_mappings(path: &Path) -> Result<MappingProfiles, ()> {
     let file = match File::open(path) {
         Ok(ok) => ok,
         Err(err) => {
@@ -90,6 +334,98 @@ fn load_mappings(path: &Path) -> Result<Mappings, ()> {
     }))
 }
 
+/// The mappings of the profile named `name`, or the top-level mappings if no name is given.
+fn select_profile(profiles: MappingProfiles, name: Option<&str>) -> Result<Mappings, ()> {
+    let mappings = match name {
+        Some(name) => match profiles
+            .profiles
+            .into_iter()
+            .find(|profile| profile.name == name)
+        {
+            Some(profile) => profile.mappings,
+            None => {
+                eprintln!(r#"There is no profile named "{}""#, name);
+                return Err(());
+            }
+        },
+        None => profiles.mappings,
+    };
+    Ok(Mappings::new(mappings))
+}
+
+/// Load the mappings given by `--with-mappings`, or compile those of the mod list given by `--with-mod-list`.
+fn load_common_mappings(opts: &CommonOpts) -> Result<MappingProfiles, ()> {
+    match (&opts.mappings, &opts.mod_list) {
+        (Some(path), _) => load_mappings(path),
+        (None, Some(path)) => Ok(ModList::load(path)?.compile()?.into()),
+        // Ruled out by structopt.
+        (None, None) => unreachable!(),
+    }
+}
+
+fn load_subprocess_rules(opts: &CommonOpts) -> Result<SubprocessRules, ()> {
+    let mut rules = match &opts.subprocess_rules {
+        Some(path) => {
+            let file = File::open(path).map_err(|err| {
+                eprintln!("Could not open {}: {}", path.display(), err);
+            })?;
+            serde_json::from_reader(BufReader::new(file)).map_err(|err| {
+                eprintln!("Could not deserialize subprocess rules file: {}", err);
+            })?
+        }
+        None => SubprocessRules::default(),
+    };
+    rules
+        .include
+        .extend(opts.hook_subprocess.iter().map(|pattern| Rule::Name {
+            pattern: pattern.clone(),
+        }));
+    rules
+        .exclude
+        .extend(opts.skip_subprocess.iter().map(|pattern| Rule::Name {
+            pattern: pattern.clone(),
+        }));
+    if opts.max_depth.is_some() {
+        rules.max_depth = opts.max_depth;
+    }
+    Ok(rules)
+}
+
+fn create_output(opts: &CommonOpts) -> Result<Output, ()> {
+    let mut config = match &opts.log_config {
+        Some(path) => LogConfig::load(path)?,
+        None => LogConfig::default(),
+    };
+    if let Some(path) = &opts.log_file {
+        config.sinks.push(match opts.log_max_size {
+            Some(max_size) => SinkConfig::Rotating {
+                path: path.clone(),
+                max_size,
+                max_files: opts.log_max_files,
+            },
+            None => SinkConfig::File { path: path.clone() },
+        });
+    }
+    if let Some(directory) = &opts.log_dir {
+        config.sinks.push(SinkConfig::PerProcess {
+            directory: directory.clone(),
+        });
+    }
+    if opts.log_stderr && !config.sinks.is_empty() {
+        config.sinks.push(SinkConfig::Stderr);
+    }
+
+    let default_sink = match opts.output {
+        OutputFormat::Text => SinkConfig::Stderr,
+        OutputFormat::Json => SinkConfig::Stdout,
+    };
+    let logger = Logger::new(&config, default_sink).map_err(|err| {
+        eprintln!("Could not open log: {}", err);
+    })?;
+
+    Ok(Output::new(opts.output, logger))
+}
+
 #[derive(Debug, StructOpt)]
 struct Opts {
     #[structopt(subcommand)]
@@ -100,12 +436,27 @@ struct Opts {
 enum Cmd {
     Inject(Inject),
     Wrap(Wrap),
+    /// Print the process trees of the sessions which are currently running
+    Tree,
+    Conflicts(Conflicts),
+    #[cfg(target_os = "linux")]
+    Mount(Mount),
 }
 
-/// Inject the payload into the specified process.
+/// Inject the payload into a running process, given by its pid or by the name of its executable.
 #[derive(Debug, StructOpt)]
 struct Inject {
-    pid: u32,
+    #[structopt(
+        required_unless_one = &["name", "wait-for"],
+        conflicts_with_all = &["name", "wait-for"]
+    )]
+    pid: Option<u32>,
+    /// Inject into the running process with this executable name. Asks which one if several are running
+    #[structopt(long, conflicts_with = "wait-for")]
+    name: Option<String>,
+    /// Inject into the process with this executable name as soon as it's running, waiting for it to start if it isn't
+    #[structopt(long)]
+    wait_for: Option<String>,
     #[structopt(flatten)]
     common: CommonOpts,
 }
@@ -120,6 +471,9 @@ struct Wrap {
     /// Immediately show the console of the wrapped process
     #[structopt(long)]
     show_console: bool,
+    /// Keep running until every subprocess has exited too, instead of exiting as soon as the wrapped process has
+    #[structopt(long)]
+    wait_for_children: bool,
     #[structopt(flatten)]
     common: CommonOpts,
     /// Ignore all arguments beyond this flag
@@ -128,180 +482,286 @@ struct Wrap {
     asbestos_cli_ignore: Vec<String>,
 }
 
+/// List the files which more than one mapping in <mappings> provides, or which are hidden by another mapping. The file
+/// which wins is listed first, and marked with an asterisk.
+#[derive(Debug, StructOpt)]
+struct Conflicts {
+    #[structopt(required_unless = "mod-list")]
+    mappings: Option<PathBuf>,
+    /// Look for files which more than one mod of a mod list provides instead
+    #[structopt(long, conflicts_with_all = &["mappings", "profile"])]
+    mod_list: Option<PathBuf>,
+    /// Use the mappings of this profile instead of the top-level ones
+    #[structopt(long)]
+    profile: Option<String>,
+    /// Leave out files with the same contents as the one which wins. Members of archives are extracted to be compared
+    #[structopt(long)]
+    ignore_identical: bool,
+    #[structopt(flatten)]
+    cache: CacheOpts,
+}
+
+/// Mount the file system seen through <mappings> read-only at <mountpoint>, until Ctrl-C is pressed.
+#[cfg(target_os = "linux")]
+#[derive(Debug, StructOpt)]
+struct Mount {
+    mappings: PathBuf,
+    mountpoint: PathBuf,
+    /// Use the mappings of this profile instead of the top-level ones
+    #[structopt(long)]
+    profile: Option<String>,
+    #[structopt(flatten)]
+    cache: CacheOpts,
+}
+
 #[derive(Debug, StructOpt)]
 struct CommonOpts {
     /// Don't hook subprocesses created by the hooked process
     #[structopt(long)]
     no_sub_hook: bool,
-    #[structopt(long = "with-mappings")]
-    mappings: PathBuf,
+    #[structopt(long = "with-mappings", required_unless = "mod-list")]
+    mappings: Option<PathBuf>,
+    /// Overlay the mods of a mod list instead of using a mappings file
+    #[structopt(long = "with-mod-list", conflicts_with = "mappings")]
+    mod_list: Option<PathBuf>,
+    /// Read the rules deciding which subprocesses to hook from a JSON file
+    #[structopt(long)]
+    subprocess_rules: Option<PathBuf>,
+    /// Only hook subprocesses whose executable's file name matches this glob. May be given multiple times
+    #[structopt(long)]
+    hook_subprocess: Vec<String>,
+    /// Don't hook subprocesses whose executable's file name matches this glob. May be given multiple times
+    #[structopt(long)]
+    skip_subprocess: Vec<String>,
+    /// Don't hook subprocesses which are more than this many levels below the target
+    #[structopt(long)]
+    max_depth: Option<u32>,
+    /// Record every call to a hooked function and write them to <trace> in the Chrome Trace Event Format
+    #[structopt(long)]
+    trace: Option<PathBuf>,
+    /// Measure the time spent inside hooks and print the results periodically and when a payload is unloaded
+    #[structopt(long)]
+    profile_hooks: bool,
+    /// What a payload does after panicking: "disable-hooks" to let the process continue without hooks, "terminate" to
+    /// terminate the process, or "freeze" to stop the panicking thread so that a debugger can be attached
+    #[structopt(long, default_value = "disable-hooks")]
+    on_panic: PanicPolicy,
+    /// The format of the messages received from payloads: "text", or "json" for one JSON object per line. JSON is
+    /// written to stdout unless other log sinks have been specified
+    #[structopt(long, default_value = "text")]
+    output: OutputFormat,
+    /// Read the log sinks to use from a JSON file
+    #[structopt(long)]
+    log_config: Option<PathBuf>,
+    /// Append the messages from all processes to <log-file>
+    #[structopt(long)]
+    log_file: Option<PathBuf>,
+    /// Rotate <log-file> once it grows beyond this many bytes
+    #[structopt(long)]
+    log_max_size: Option<u64>,
+    /// The number of rotated log files to keep
+    #[structopt(long, default_value = sink::DEFAULT_MAX_FILES)]
+    log_max_files: usize,
+    /// Write the messages from each process to a separate file in <log-dir>
+    #[structopt(long)]
+    log_dir: Option<PathBuf>,
+    /// Keep writing to stderr even if other log sinks have been specified
+    #[structopt(long)]
+    log_stderr: bool,
+    /// How the mappings are applied: "payload" to inject the payload (Windows), "ptrace" to rewrite the paths passed
+    /// to system calls (Linux), or "seccomp" to carry out the system calls taking paths on behalf of a wrapped process
+    /// (Linux 5.14 or newer). Tracing and profiling hooks, --on-panic and --show-console only apply to the payload.
+    /// Defaults to "payload" on Windows and "ptrace" on Linux
+    #[structopt(long)]
+    backend: Option<Backend>,
+    #[structopt(flatten)]
+    cache: CacheOpts,
 }
 
-fn inject_impl(pid: u32, inject_opts: InjectOpts, mappings: Mappings) {
-    let mut connections = HashMap::new();
-    if let Ok(connection) = inject_and_connect(pid, 0, &inject_opts, &mappings) {
-        connections.insert(pid, connection);
+#[derive(Debug, StructOpt)]
+struct CacheOpts {
+    /// Where to put contents which don't exist on disk, such as the members of archives, so that they can be opened.
+    /// Defaults to asbestos/cache in the temporary folder
+    #[structopt(long)]
+    cache_dir: Option<PathBuf>,
+    /// Evict the least recently used contents from the cache once it grows beyond this many bytes
+    #[structopt(long)]
+    cache_max_size: Option<u64>,
+}
+
+impl CacheOpts {
+    fn config(&self) -> CacheConfig {
+        CacheConfig {
+            dir: self.cache_dir.clone(),
+            max_size: self.cache_max_size,
+        }
     }
+}
+
+/// When `run` stops servicing the session of its own accord.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Until {
+    /// Only when interrupted with Ctrl-C.
+    Interrupted,
+    /// Once the root process has exited and its payload has disconnected.
+    RootExited,
+    /// Once every process has exited and every payload has disconnected.
+    TreeExited,
+}
 
+/// Service the session until Ctrl-C is pressed or `until` is reached.
+///
+/// Returns the exit code of the root process if `until` was reached.
+fn run(session: SessionBuilder, opts: &CommonOpts, until: Until) -> Option<u32> {
+    let mappings = match load_common_mappings(opts) {
+        Ok(ok) => ok,
+        Err(_) => return failed(until),
+    };
+    let subprocess_rules = match load_subprocess_rules(opts) {
+        Ok(ok) => ok,
+        Err(_) => return failed(until),
+    };
+    let mut output = match create_output(opts) {
+        Ok(ok) => ok,
+        Err(_) => return failed(until),
+    };
+    let mut trace = opts
+        .trace
+        .as_ref()
+        .and_then(|path| match ChromeTrace::create(path) {
+            Ok(trace) => Some(trace),
+            Err(err) => {
+                eprintln!("Could not create {}: {}", path.display(), err);
+                None
+            }
+        });
+
+    let session = session
+        .mapping_profiles(mappings)
+        .dont_hook_subprocesses(opts.no_sub_hook)
+        .subprocess_rules(subprocess_rules)
+        .trace_hooks(opts.trace.is_some())
+        .profile_hooks(opts.profile_hooks)
+        .panic_policy(opts.on_panic)
+        .cache(opts.cache.config());
+    let mut session = match session::start(session, opts.backend.unwrap_or_default()) {
+        Ok(ok) => ok,
+        Err(err) => {
+            eprintln!("Could not start session: {}", err);
+            return failed(until);
+        }
+    };
+
+    let mut summarized = false;
+    let mut exit_code = None;
+    let mut detaching_since = None;
     loop {
-        let mut morgue = Vec::new();
-        let mut nursery = Vec::new();
-        for (pid, connection) in connections.iter_mut() {
-            match connection.read_message() {
-                Ok(msg) => match msg {
-                    Message::StartupInfo(_) => {}
-                    Message::LogMessage(log_message) => {
-                        let prefix = format!(
-                            "{}: [{}:{}] ",
-                            pid,
-                            log_message
-                                .module_path
-                                .trim_start_matches("asbestos_payload::"),
-                            log_message.line,
-                        );
-                        for (n, line) in log_message.message.lines().enumerate() {
-                            if n == 0 {
-                                eprintln!("{}{}", prefix, line);
-                            } else {
-                                eprintln!("{:width$}{}", " ", line, width = prefix.len());
-                            }
-                        }
-                    }
-                    Message::Initialized => eprintln!("{}: Payload initialized", pid),
-                    Message::InitializationFailed(err) => {
-                        eprintln!("{}: Payload initalization failed: {}", pid, err)
+        match session.next_event_timeout(Duration::from_millis(100)) {
+            Some(event) => {
+                output.event(&event);
+                if changes_tree(&event.kind) {
+                    if let Err(err) = tree::write_state(session.tree()) {
+                        eprintln!("Could not write the process tree: {}", err);
                     }
-                    Message::ProcessSpawned(ps) => {
-                        eprintln!("{}: Spawned a new process: {}", pid, ps.pid);
-                        if let Ok(new_connection) =
-                            inject_and_connect(ps.pid, ps.tid, &inject_opts, &mappings)
-                        {
-                            nursery.push((ps.pid, new_connection));
+                }
+                if let EventKind::Trace(trace_event) = &event.kind {
+                    if let Some(trace) = trace.as_mut() {
+                        if let Err(err) = trace.record(event.pid, trace_event) {
+                            eprintln!("Could not write trace event: {}", err);
                         }
                     }
-                    Message::ProcessDetach => {
-                        eprintln!("{}: Payload unloaded", pid);
-                        morgue.push(*pid);
-                    }
-                },
-                Err(err) => {
-                    if matches!(err, ProtocolError::Disconnected) {
-                        morgue.push(*pid);
-                    }
-                    eprintln!("{}: {:?} => {}", pid, err, err)
                 }
             }
-        }
-        for dead_process in morgue {
-            connections.remove(&dead_process);
-        }
-        for (pid, connection) in nursery {
-            connections.insert(pid, connection);
+            None if session.is_finished() => {
+                if !summarized {
+                    output.summary(session.tree());
+                    tree::remove_state();
+                    summarized = true;
+                }
+                thread::sleep(Duration::from_millis(100))
+            }
+            None => {}
         }
 
-        if CTRL_C.load(Ordering::SeqCst) {
-            eprintln!("Ctrl-C");
+        let root = session.root_pid();
+        let root_exited = session
+            .tree()
+            .get(root)
+            .is_some_and(|node| !node.is_running())
+            && session.pids().iter().all(|&pid| pid != root);
+        let reached = match until {
+            Until::Interrupted => false,
+            Until::RootExited => root_exited,
+            Until::TreeExited => root_exited && session.is_finished(),
+        };
+        if reached {
+            // An exit code which couldn't be determined is reported as a failure.
+            exit_code = Some(
+                session
+                    .tree()
+                    .get(root)
+                    .and_then(|node| node.exit_code)
+                    .unwrap_or(1),
+            );
             break;
         }
 
-        if connections.is_empty() {
-            thread::sleep(Duration::from_millis(100));
+        // The first Ctrl-C unloads the payloads, the second stops waiting for them to be unloaded.
+        if CTRL_C.swap(false, Ordering::SeqCst) {
+            if detaching_since.is_some() {
+                eprintln!("Ctrl-C, no longer waiting for payloads to unload");
+                break;
+            }
+            eprintln!("Ctrl-C, unloading payloads (press Ctrl-C again to exit immediately)");
+            session.detach_all();
+            detaching_since = Some(Instant::now());
+        }
+        if let Some(detaching_since) = detaching_since {
+            if session.pids().is_empty() {
+                break;
+            }
+            if detaching_since.elapsed() > DETACH_TIMEOUT {
+                eprintln!("Some payloads did not unload in time");
+                break;
+            }
         }
     }
-}
 
-fn inject_and_connect(
-    pid: u32,
-    tid: u32,
-    inject_opts: &InjectOpts,
-    mappings: &Mappings,
-) -> Result<Connection<impl std::io::Read, impl std::io::Write>, ()> {
-    let current_exe = env::current_exe().expect("asbestos could not locate its own executable");
-    let mut dll = current_exe.clone();
-    dll.set_file_name("asbestos_payload");
-    dll.set_extension("dll");
-
-    let (connecting_server_rx, connecting_server_tx) = create_connecting_pipe_server_pair(pid);
-
-    let injection_thread = thread::spawn(move || {
-        syringe::inject_dll(pid, &dll).unwrap();
-    });
-
-    let (pipe_rx, pipe_tx) = match wait_for_pipe_connection_with_timeout_ms(
-        pid,
-        connecting_server_rx,
-        connecting_server_tx,
-        3000,
-    ) {
-        Ok(ok) => ok,
-        Err(_) => return Err(()),
-    };
-    let mut connection = Connection::new(BufReader::new(pipe_rx), pipe_tx);
-    connection
-        .write_message(Message::StartupInfo(StartupInfo {
-            main_thread_suspended: inject_opts.main_thread_suspended,
-            dont_hook_subprocesses: inject_opts.dont_hook_subprocesses,
-            show_console: inject_opts.show_console,
-            mappings: mappings.clone(),
-            tid,
-        }))
-        .unwrap();
-    injection_thread.join().unwrap();
-
-    Ok(connection)
-}
-
-struct InjectOpts {
-    main_thread_suspended: bool,
-    dont_hook_subprocesses: bool,
-    show_console: bool,
-}
-
-fn create_connecting_pipe_server_pair(pid: u32) -> (ConnectingServer, ConnectingServer) {
-    let connecting_server_rx = PipeOptions::new(named_pipe_name(pid, PipeEnd::Rx))
-        .single()
-        .expect("Could not open named pipe");
+    if !summarized {
+        output.summary(session.tree());
+        tree::remove_state();
+    }
 
-    let connecting_server_tx = PipeOptions::new(named_pipe_name(pid, PipeEnd::Tx))
-        .single()
-        .expect("Could not open named pipe");
+    if let Some(trace) = trace {
+        if let Err(err) = trace.finish() {
+            eprintln!("Could not finish trace: {}", err);
+        }
+    }
 
-    (connecting_server_rx, connecting_server_tx)
+    exit_code
 }
 
-fn wait_for_pipe_connection_with_timeout_ms(
-    pid: u32,
-    connecting_server_rx: ConnectingServer,
-    connecting_server_tx: ConnectingServer,
-    timeout: u32,
-) -> Result<(PipeServer, PipeServer), ()> {
-    eprintln!("Waiting for connection from {}", pid);
-    let pipe_rx = match connecting_server_rx.wait_ms(timeout) {
-        Err(err) => {
-            eprintln!("Platform IO error: {}", err);
-            return Err(());
-        }
-        Ok(ok) => match ok {
-            Err(_) => {
-                eprintln!("{} did not connect within {} ms", pid, timeout);
-                return Err(());
+/// What `run` returns if the session couldn't be started.
+fn failed(until: Until) -> Option<u32> {
+    match until {
+        // Keeps the console open until Ctrl-C so that the error can be read.
+        Until::Interrupted => {
+            while !CTRL_C.load(Ordering::SeqCst) {
+                thread::park();
             }
-            Ok(ok) => ok,
-        },
-    };
-    let pipe_tx = match connecting_server_tx.wait_ms(timeout) {
-        Err(err) => {
-            eprintln!("Platform IO error: {}", err);
-            return Err(());
+            Some(1)
         }
-        Ok(ok) => match ok {
-            Err(_) => {
-                eprintln!("{} did not connect within {} ms", pid, timeout);
-                return Err(());
-            }
-            Ok(ok) => ok,
-        },
-    };
+        _ => Some(1),
+    }
+}
 
-    Ok((pipe_rx, pipe_tx))
+fn changes_tree(kind: &EventKind) -> bool {
+    matches!(
+        kind,
+        EventKind::Initialized
+            | EventKind::ProcessSpawned(_)
+            | EventKind::ProcessInfo(_)
+            | EventKind::ProcessExited { .. }
+            | EventKind::InjectionFailed(_)
+    )
 }
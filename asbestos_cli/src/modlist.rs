@@ -0,0 +1,101 @@
+//! Mod lists: an ordered list of mod folders, compiled into the mappings which overlay them onto a game's folder.
+//!
+//! Each file of an enabled mod appears at the same place below the target folder, or below the mod's own subfolder of
+//! it. When several mods provide the same file, the one furthest down the list wins, like in most mod managers.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use asbestos::shared::{
+    conflicts,
+    protocol::{Mapping, MappingFrom, MappingKind, MappingTo, Mappings},
+};
+
+#[derive(Debug, Deserialize)]
+pub struct ModList {
+    /// The folder the mods are overlaid onto, usually that of the game.
+    pub target: PathBuf,
+    /// Later mods take precedence over earlier ones.
+    pub mods: Vec<Mod>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Mod {
+    /// Defaults to the name of the mod's folder.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub path: PathBuf,
+    #[serde(default = "enabled_by_default")]
+    pub enabled: bool,
+    /// The subfolder of the target the mod's files go in, such as `Data`. The target itself by default.
+    #[serde(default)]
+    pub target: Option<PathBuf>,
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl Mod {
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .path
+                .file_name()
+                .unwrap_or_else(|| self.path.as_os_str())
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+}
+
+impl ModList {
+    pub fn load(path: &Path) -> Result<Self, ()> {
+        let file = File::open(path).map_err(|err| {
+            eprintln!("Could not open {}: {}", path.display(), err);
+        })?;
+        serde_json::from_reader(BufReader::new(file)).map_err(|err| {
+            eprintln!("Could not deserialize mod list: {}", err);
+        })
+    }
+
+    /// The mappings which make every file of the enabled mods appear below the target, as provided by the mod which
+    /// wins.
+    ///
+    /// Every file gets a redirect of its own, since a mounted folder hides whatever isn't in it, while a mod only
+    /// replaces the files it has. `map_path` looks these up by path, so there being one per file doesn't slow it
+    /// down. The first redirect for a file wins, so the mods go from last to first. The redirects of the mods which
+    /// lose are kept, which is how `conflicts::find_conflicts` finds them.
+    pub fn compile(&self) -> Result<Mappings, ()> {
+        let mut mappings = Vec::new();
+        for source in self.mods.iter().rev().filter(|source| source.enabled) {
+            let target = match &source.target {
+                Some(subfolder) => self.target.join(subfolder),
+                None => self.target.clone(),
+            };
+            let files = conflicts::walk(&source.path).map_err(|err| {
+                eprintln!("Could not read mod {}: {}", source.name(), err);
+            })?;
+            mappings.extend(files.into_iter().map(|relative| Mapping {
+                kind: MappingKind::Redirect,
+                from: MappingFrom::File(target.join(&relative)),
+                to: MappingTo::File(source.path.join(relative)),
+            }));
+        }
+        Ok(Mappings::new(mappings))
+    }
+
+    /// The enabled mod which `path` is a file of. If one mod's folder is inside another's, it's the innermost one.
+    pub fn mod_of(&self, path: &Path) -> Option<&Mod> {
+        self.mods
+            .iter()
+            .filter(|source| source.enabled && path.starts_with(&source.path))
+            .max_by_key(|source| source.path.components().count())
+    }
+}
@@ -0,0 +1,428 @@
+//! A read-only view of the virtual file system produced by a set of mappings, mounted with FUSE.
+//!
+//! Paths are resolved with the same code the backends use, so browsing the mount shows what a hooked process would
+//! see.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, File, Metadata},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{FileExt, FileTypeExt, MetadataExt},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, Thread},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use fuser::{
+    FileAttr, FileType, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, Request, FUSE_ROOT_ID,
+};
+
+use asbestos::shared::{
+    cache::{CacheConfig, ContentCache},
+    contents::Contents,
+    protocol::Mappings,
+    vfs,
+};
+
+/// How long the kernel may cache attributes and lookups. The mappings don't change while mounted, but the folders
+/// they point to might.
+const TTL: Duration = Duration::from_secs(1);
+
+/// The inode reported for directory entries which haven't been looked up, like `FUSE_UNKNOWN_INO` in the kernel.
+/// Entries are only given an inode of their own once they are looked up, since only then does the kernel let us know
+/// when it's done with them.
+const UNKNOWN_INO: u64 = 0xffff_ffff;
+
+/// Mount the virtual file system at `mountpoint`, and keep it mounted until `unmount` returns `true` or it is unmounted
+/// from elsewhere.
+///
+/// The calling thread is parked in the meantime, and checks `unmount` whenever it is unparked.
+pub fn mount(
+    mappings: Mappings,
+    cache: &CacheConfig,
+    mountpoint: &Path,
+    mut unmount: impl FnMut() -> bool,
+) -> io::Result<()> {
+    let (contents, errors) = Contents::index(mappings.iter(), ContentCache::new(cache));
+    for err in errors {
+        eprintln!("{}", err);
+    }
+
+    let options = ["-o", "ro", "-o", "fsname=asbestos"].map(OsStr::new);
+    let ended = Arc::new(AtomicBool::new(false));
+    let fs = MappedFs::new(
+        mappings,
+        contents,
+        mountpoint.canonicalize()?,
+        Ended {
+            ended: ended.clone(),
+            waiter: thread::current(),
+        },
+    );
+    let session = fuser::spawn_mount(fs, mountpoint, &options)?;
+    while !unmount() && !ended.load(Ordering::SeqCst) {
+        thread::park();
+    }
+    drop(session);
+    Ok(())
+}
+
+/// Lets the thread which mounted the file system know that the session has ended.
+struct Ended {
+    ended: Arc<AtomicBool>,
+    waiter: Thread,
+}
+
+struct MappedFs {
+    mappings: Mappings,
+    contents: Contents,
+    /// Where the file system is mounted, which is left out of it.
+    mountpoint: PathBuf,
+    /// The inodes the kernel knows about.
+    inodes: HashMap<u64, Inode>,
+    paths: HashMap<PathBuf, u64>,
+    next_ino: u64,
+    files: HashMap<u64, File>,
+    next_fh: u64,
+    /// Set once the kernel ends the session.
+    ended: Ended,
+}
+
+struct Inode {
+    /// The virtual path of the inode.
+    path: PathBuf,
+    /// How many times the inode has been looked up, minus the lookups the kernel has forgotten.
+    lookups: u64,
+}
+
+impl MappedFs {
+    fn new(mappings: Mappings, contents: Contents, mountpoint: PathBuf, ended: Ended) -> Self {
+        let root = PathBuf::from("/");
+        Self {
+            mappings,
+            contents,
+            mountpoint,
+            inodes: vec![(
+                FUSE_ROOT_ID,
+                Inode {
+                    path: root.clone(),
+                    lookups: 1,
+                },
+            )]
+            .into_iter()
+            .collect(),
+            paths: vec![(root, FUSE_ROOT_ID)].into_iter().collect(),
+            next_ino: FUSE_ROOT_ID + 1,
+            files: HashMap::new(),
+            next_fh: 0,
+            ended,
+        }
+    }
+
+    fn path(&self, ino: u64) -> Option<&Path> {
+        self.inodes.get(&ino).map(|inode| inode.path.as_path())
+    }
+
+    /// The inode of a path which has been looked up successfully, which the kernel will `forget` eventually.
+    fn look_up(&mut self, path: PathBuf) -> u64 {
+        if let Some(ino) = self.paths.get(&path) {
+            self.inodes.get_mut(ino).unwrap().lookups += 1;
+            return *ino;
+        }
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.paths.insert(path.clone(), ino);
+        self.inodes.insert(ino, Inode { path, lookups: 1 });
+        ino
+    }
+
+    /// Whether a virtual path is at or below the mountpoint. Looking at it would come back to this file system, which
+    /// can't answer while it's waiting for itself.
+    fn hidden(&self, path: &Path) -> bool {
+        path.starts_with(&self.mountpoint)
+    }
+
+    /// The real path behind a virtual one. Members of archives are only extracted if `extract` is set, and have no
+    /// contents otherwise.
+    fn real_path(&self, path: &Path, extract: bool) -> io::Result<PathBuf> {
+        let mapped = vfs::map_path(Cow::Borrowed(path), &self.mappings, None)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if self.hidden(&mapped) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        let real = if extract {
+            match self
+                .contents
+                .real_path(path, mapped.clone(), &self.mappings)
+            {
+                Ok(real) => real,
+                Err(err) => {
+                    eprintln!(
+                        "Could not patch or merge {}, opening it as it is: {}",
+                        path.display(),
+                        err
+                    );
+                    self.contents.unpatched_path(path, mapped)?
+                }
+            }
+        } else {
+            self.contents.outline_path(path, mapped, &self.mappings)?
+        };
+        Ok(real.into_owned())
+    }
+
+    fn attr(&self, ino: u64, path: &Path) -> io::Result<FileAttr> {
+        if self.hidden(path) {
+            return Err(io::Error::from_raw_os_error(libc::ENOENT));
+        }
+        match fs::symlink_metadata(self.real_path(path, false)?) {
+            Ok(metadata) => Ok(attr_from_metadata(ino, &metadata)),
+            // Folders which only exist because something is mapped below them.
+            Err(err)
+                if err.kind() == io::ErrorKind::NotFound
+                    && vfs::contains_mapped_paths(path, &self.mappings) =>
+            {
+                Ok(synthetic_folder_attr(ino))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl Filesystem for MappedFs {
+    fn destroy(&mut self) {
+        self.ended.ended.store(true, Ordering::SeqCst);
+        self.ended.waiter.unpark();
+    }
+
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let path = match self.path(parent) {
+            Some(parent) => parent.join(name),
+            None => return reply.error(libc::ENOENT),
+        };
+        match self.attr(UNKNOWN_INO, &path) {
+            Ok(mut attr) => {
+                attr.ino = self.look_up(path);
+                reply.entry(&TTL, &attr, 0)
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        if ino == FUSE_ROOT_ID {
+            return;
+        }
+        if let Some(inode) = self.inodes.get_mut(&ino) {
+            inode.lookups = inode.lookups.saturating_sub(nlookup);
+            if inode.lookups == 0 {
+                let inode = self.inodes.remove(&ino).unwrap();
+                self.paths.remove(&inode.path);
+            }
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let path = match self.path(ino) {
+            Some(path) => path.to_owned(),
+            None => return reply.error(libc::ENOENT),
+        };
+        match self.attr(ino, &path) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let target = self
+            .path(ino)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+            .and_then(|path| self.real_path(path, false))
+            .and_then(fs::read_link);
+        match target {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            return reply.error(libc::EROFS);
+        }
+        let file = self
+            .path(ino)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))
+            .and_then(|path| self.real_path(path, true))
+            .and_then(File::open);
+        match file {
+            Ok(file) => {
+                let fh = self.next_fh;
+                self.next_fh += 1;
+                self.files.insert(fh, file);
+                reply.opened(fh, 0);
+            }
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let file = match self.files.get(&fh) {
+            Some(file) => file,
+            None => return reply.error(libc::EBADF),
+        };
+        let mut buf = vec![0; size as usize];
+        match file.read_at(&mut buf, offset as u64) {
+            Ok(read) => reply.data(&buf[..read]),
+            Err(err) => reply.error(errno(&err)),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.files.remove(&fh);
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let path = match self.path(ino) {
+            Some(path) => path.to_owned(),
+            None => return reply.error(libc::ENOENT),
+        };
+        let names = match vfs::read_dir(&path, &self.mappings, &self.contents) {
+            Ok(names) => names,
+            Err(err) => return reply.error(errno(&err)),
+        };
+
+        // The kernel has looked up every ancestor of a folder it reads.
+        let parent = match path.parent() {
+            Some(parent) => self.paths.get(parent).copied().unwrap_or(UNKNOWN_INO),
+            None => ino,
+        };
+        let mut entries = vec![
+            (ino, FileType::Directory, OsStr::new(".").to_owned()),
+            (parent, FileType::Directory, OsStr::new("..").to_owned()),
+        ];
+        for name in names {
+            let child = path.join(&name);
+            if self.hidden(&child) {
+                continue;
+            }
+            let child_ino = self.paths.get(&child).copied().unwrap_or(UNKNOWN_INO);
+            // Entries which can't be looked at are still listed, and fail once they are looked up.
+            let kind = self
+                .attr(child_ino, &child)
+                .map_or(FileType::RegularFile, |attr| attr.kind);
+            entries.push((child_ino, kind, name));
+        }
+
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // The offset of an entry is that of the entry after it.
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn attr_from_metadata(ino: u64, metadata: &Metadata) -> FileAttr {
+    let file_type = metadata.file_type();
+    let kind = if file_type.is_dir() {
+        FileType::Directory
+    } else if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_block_device() {
+        FileType::BlockDevice
+    } else if file_type.is_char_device() {
+        FileType::CharDevice
+    } else if file_type.is_fifo() {
+        FileType::NamedPipe
+    } else if file_type.is_socket() {
+        FileType::Socket
+    } else {
+        FileType::RegularFile
+    };
+    let time = |secs: i64, nsecs: i64| UNIX_EPOCH + Duration::new(secs.max(0) as u64, nsecs as u32);
+
+    FileAttr {
+        ino,
+        size: metadata.size(),
+        blocks: metadata.blocks(),
+        atime: time(metadata.atime(), metadata.atime_nsec()),
+        mtime: time(metadata.mtime(), metadata.mtime_nsec()),
+        ctime: time(metadata.ctime(), metadata.ctime_nsec()),
+        crtime: UNIX_EPOCH,
+        kind,
+        // Read-only, whatever the real file allows.
+        perm: (metadata.mode() & 0o7555) as u16,
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        blksize: metadata.blksize() as u32,
+        flags: 0,
+    }
+}
+
+fn synthetic_folder_attr(ino: u64) -> FileAttr {
+    let now = SystemTime::now();
+    FileAttr {
+        ino,
+        size: 0,
+        blocks: 0,
+        atime: now,
+        mtime: now,
+        ctime: now,
+        crtime: now,
+        kind: FileType::Directory,
+        perm: 0o555,
+        nlink: 2,
+        uid: unsafe { libc::getuid() },
+        gid: unsafe { libc::getgid() },
+        rdev: 0,
+        blksize: 4096,
+        flags: 0,
+    }
+}
+
+fn errno(err: &io::Error) -> i32 {
+    match err.kind() {
+        io::ErrorKind::InvalidData => libc::EINVAL,
+        _ => err.raw_os_error().unwrap_or(libc::EIO),
+    }
+}
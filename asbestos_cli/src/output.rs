@@ -0,0 +1,432 @@
+//! Formatting of the messages received from payloads.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+
+use asbestos::{
+    shared::protocol::{HookTimings, LogLevel, PanicPolicy, ProtocolError},
+    Event, EventKind, ProcessNode, ProcessTree,
+};
+
+use crate::{profile::format_hook_profile, sink::Logger};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Human-readable lines prefixed with the pid of the process they concern.
+    Text,
+    /// One JSON object per line. See `JsonEvent` for the schema.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(r#"Unknown output format "{}""#, s)),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Text => write!(f, "text"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+pub struct Output {
+    format: OutputFormat,
+    logger: Logger,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat, logger: Logger) -> Self {
+        Self { format, logger }
+    }
+
+    pub fn event(&mut self, event: &Event) {
+        match self.format {
+            OutputFormat::Text => self.text_event(event),
+            OutputFormat::Json => {
+                if let Some(json_event) = JsonEvent::from_event(event) {
+                    self.json(event.pid, &json_event);
+                }
+            }
+        }
+    }
+
+    /// Describe every process the session has seen. Written when the session ends.
+    pub fn summary(&mut self, tree: &ProcessTree) {
+        // Routed like the messages of the first process, so that it ends up next to them.
+        let pid = match tree.roots().next() {
+            Some(root) => root.pid,
+            None => return,
+        };
+        match self.format {
+            OutputFormat::Text => self.logger.log(
+                pid,
+                format!("Process tree at the end of the session\n{}", tree).trim_end(),
+            ),
+            OutputFormat::Json => self.json(
+                pid,
+                &JsonEvent::Summary {
+                    processes: tree.iter().map(JsonProcess::from).collect(),
+                },
+            ),
+        }
+    }
+
+    fn text_event(&mut self, event: &Event) {
+        let pid = event.pid;
+        match &event.kind {
+            EventKind::Trace(_) => {}
+            EventKind::Log(log_message) => {
+                let prefix = format!(
+                    "{}: [{}:{}] ",
+                    pid,
+                    log_message
+                        .module_path
+                        .trim_start_matches("asbestos_payload::"),
+                    log_message.line,
+                );
+                for (n, line) in log_message.message.lines().enumerate() {
+                    if n == 0 {
+                        self.logger.log(pid, &format!("{}{}", prefix, line));
+                    } else {
+                        self.logger.log(
+                            pid,
+                            &format!("{:width$}{}", " ", line, width = prefix.len()),
+                        );
+                    }
+                }
+            }
+            EventKind::Initialized => self
+                .logger
+                .log(pid, &format!("{}: Payload initialized", pid)),
+            EventKind::InitializationFailed(err) => self.logger.log(
+                pid,
+                &format!("{}: Payload initalization failed: {}", pid, err),
+            ),
+            EventKind::ProcessSpawned(ps) => self
+                .logger
+                .log(pid, &format!("{}: Spawned a new process: {}", pid, ps.pid)),
+            EventKind::SubprocessVerdict(verdict) => {
+                if verdict.hook {
+                    self.logger
+                        .log(pid, &format!("{}: Hooking since {}", pid, verdict.reason))
+                } else {
+                    self.logger.log(
+                        pid,
+                        &format!("{}: Not hooking since {}", pid, verdict.reason),
+                    )
+                }
+            }
+            EventKind::MappingProfileSelected { profile } => match profile {
+                Some(profile) => self.logger.log(
+                    pid,
+                    &format!(r#"{}: Using the mappings of profile "{}""#, pid, profile),
+                ),
+                None => self
+                    .logger
+                    .log(pid, &format!("{}: Using the top-level mappings", pid)),
+            },
+            EventKind::ResumeFailed(err) => self.logger.log(
+                pid,
+                &format!("{}: Could not resume the main thread: {}", pid, err),
+            ),
+            EventKind::ProcessInfo(info) => self.logger.log(
+                pid,
+                &format!(
+                    "{}: Hooked {} ({})",
+                    pid,
+                    info.executable.display(),
+                    info.command_line
+                ),
+            ),
+            EventKind::HookProfile(hook_profile) => self.logger.log(
+                pid,
+                format!(
+                    "{}: Hook profile\n{}",
+                    pid,
+                    format_hook_profile(hook_profile)
+                )
+                .trim_end(),
+            ),
+            EventKind::Panic(report) => {
+                let action = match report.policy {
+                    PanicPolicy::DisableHooks => "its hooks have been disabled",
+                    PanicPolicy::Terminate => "the process is being terminated",
+                    PanicPolicy::Freeze => "the panicking thread has been frozen",
+                };
+                self.logger.log(
+                    pid,
+                    format!(
+                        "{}: Payload panicked at {}{}: {}, {}\n{}",
+                        pid,
+                        report.location.as_deref().unwrap_or("an unknown location"),
+                        match &report.thread {
+                            Some(thread) => format!(" in thread {}", thread),
+                            None => String::new(),
+                        },
+                        report.message,
+                        action,
+                        report.backtrace
+                    )
+                    .trim_end(),
+                )
+            }
+            EventKind::ProcessDetach => self.logger.log(pid, &format!("{}: Payload unloaded", pid)),
+            EventKind::ProtocolError(err) => self
+                .logger
+                .log(pid, &format!("{}: {:?} => {}", pid, err, err)),
+            EventKind::InjectionFailed(err) => self
+                .logger
+                .log(pid, &format!("{}: Could not hook process: {}", pid, err)),
+            EventKind::ProcessExited { exit_code } => match exit_code {
+                Some(exit_code) => self
+                    .logger
+                    .log(pid, &format!("{}: Exited with code {}", pid, exit_code)),
+                None => self.logger.log(pid, &format!("{}: Exited", pid)),
+            },
+        }
+    }
+
+    fn json(&mut self, pid: u32, event: &JsonEvent) {
+        match serde_json::to_string(&JsonLine { pid, event }) {
+            Ok(line) => self.logger.log(pid, &line),
+            Err(err) => eprintln!("Could not serialize event: {}", err),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    pid: u32,
+    #[serde(flatten)]
+    event: &'a JsonEvent<'a>,
+}
+
+/// The schema of the lines written in `OutputFormat::Json`.
+///
+/// Every line is an object with a `pid` and an `event` field, where `event` is one of the variants below in
+/// `snake_case`. The remaining fields depend on the event. Fields may be added in the future, but existing fields
+/// will not be removed or change meaning.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JsonEvent<'a> {
+    Log {
+        level: &'static str,
+        module_path: &'a str,
+        file: &'a str,
+        line: u32,
+        message: &'a str,
+    },
+    Initialized,
+    InitializationFailed {
+        error: &'a str,
+    },
+    ProcessSpawned {
+        child_pid: u32,
+        child_tid: u32,
+        executable: &'a str,
+        command_line: &'a str,
+    },
+    SubprocessVerdict {
+        hook: bool,
+        reason: &'a str,
+    },
+    MappingProfileSelected {
+        profile: Option<&'a str>,
+    },
+    ResumeFailed {
+        error: String,
+    },
+    ProcessInfo {
+        executable: String,
+        command_line: &'a str,
+    },
+    TraceEvent {
+        name: &'a str,
+        tid: u32,
+        begin_us: u64,
+        end_us: u64,
+        detail: &'a str,
+    },
+    HookProfile {
+        hooks: Vec<JsonHookTimings<'a>>,
+    },
+    Panic {
+        message: &'a str,
+        location: Option<&'a str>,
+        thread: Option<&'a str>,
+        backtrace: &'a str,
+        /// `"disable-hooks"`, `"terminate"` or `"freeze"`.
+        policy: String,
+    },
+    ProcessDetach,
+    ProtocolError {
+        kind: &'static str,
+        error: String,
+    },
+    InjectionFailed {
+        error: String,
+    },
+    ProcessExited {
+        exit_code: Option<u32>,
+    },
+    /// Written once when the session ends, with the `pid` of the first process.
+    Summary {
+        processes: Vec<JsonProcess<'a>>,
+    },
+}
+
+impl<'a> JsonEvent<'a> {
+    fn from_event(event: &'a Event) -> Option<Self> {
+        Some(match &event.kind {
+            EventKind::Log(log_message) => Self::Log {
+                level: match log_message.level {
+                    LogLevel::Error => "error",
+                    LogLevel::Warn => "warn",
+                    LogLevel::Info => "info",
+                    LogLevel::Debug => "debug",
+                    LogLevel::Trace => "trace",
+                },
+                module_path: &log_message.module_path,
+                file: &log_message.file,
+                line: log_message.line,
+                message: &log_message.message,
+            },
+            EventKind::Initialized => Self::Initialized,
+            EventKind::InitializationFailed(err) => Self::InitializationFailed { error: err },
+            EventKind::ProcessSpawned(ps) => Self::ProcessSpawned {
+                child_pid: ps.pid,
+                child_tid: ps.tid,
+                executable: &ps.executable,
+                command_line: &ps.command_line,
+            },
+            EventKind::SubprocessVerdict(verdict) => Self::SubprocessVerdict {
+                hook: verdict.hook,
+                reason: &verdict.reason,
+            },
+            EventKind::MappingProfileSelected { profile } => Self::MappingProfileSelected {
+                profile: profile.as_deref(),
+            },
+            EventKind::ResumeFailed(err) => Self::ResumeFailed {
+                error: err.to_string(),
+            },
+            EventKind::ProcessInfo(info) => Self::ProcessInfo {
+                executable: info.executable.to_string_lossy().into_owned(),
+                command_line: &info.command_line,
+            },
+            EventKind::Trace(trace_event) => Self::TraceEvent {
+                name: &trace_event.name,
+                tid: trace_event.tid,
+                begin_us: trace_event.begin,
+                end_us: trace_event.end,
+                detail: &trace_event.detail,
+            },
+            EventKind::HookProfile(hook_profile) => Self::HookProfile {
+                hooks: hook_profile
+                    .hooks
+                    .iter()
+                    .map(JsonHookTimings::from)
+                    .collect(),
+            },
+            EventKind::Panic(report) => Self::Panic {
+                message: &report.message,
+                location: report.location.as_deref(),
+                thread: report.thread.as_deref(),
+                backtrace: &report.backtrace,
+                policy: report.policy.to_string(),
+            },
+            EventKind::ProcessDetach => Self::ProcessDetach,
+            EventKind::ProtocolError(err) => Self::ProtocolError {
+                kind: match err {
+                    ProtocolError::Io(_) => "io",
+                    ProtocolError::Bincode(_) => "bincode",
+                    ProtocolError::ConnectionLost => "connection_lost",
+                    ProtocolError::Disconnected => "disconnected",
+                },
+                error: err.to_string(),
+            },
+            EventKind::InjectionFailed(err) => Self::InjectionFailed {
+                error: err.to_string(),
+            },
+            EventKind::ProcessExited { exit_code } => Self::ProcessExited {
+                exit_code: *exit_code,
+            },
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct JsonHookTimings<'a> {
+    name: &'a str,
+    count: u64,
+    total_mean_ns: u64,
+    total_max_ns: u64,
+    resolve_mean_ns: u64,
+    call_mean_ns: u64,
+}
+
+impl<'a> From<&'a HookTimings> for JsonHookTimings<'a> {
+    fn from(timings: &'a HookTimings) -> Self {
+        Self {
+            name: &timings.name,
+            count: timings.total.count,
+            total_mean_ns: timings.total.mean_ns(),
+            total_max_ns: timings.total.max_ns,
+            resolve_mean_ns: timings.resolve.mean_ns(),
+            call_mean_ns: timings.call.mean_ns(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct JsonProcess<'a> {
+    pid: u32,
+    parent: Option<u32>,
+    children: &'a [u32],
+    executable: Option<String>,
+    command_line: Option<&'a str>,
+    /// Milliseconds since the Unix epoch.
+    started_at_ms: u64,
+    exited_at_ms: Option<u64>,
+    exit_code: Option<u32>,
+    hooked: bool,
+}
+
+impl<'a> From<&'a ProcessNode> for JsonProcess<'a> {
+    fn from(node: &'a ProcessNode) -> Self {
+        Self {
+            pid: node.pid,
+            parent: node.parent,
+            children: &node.children,
+            executable: node
+                .executable
+                .as_ref()
+                .map(|executable| executable.to_string_lossy().into_owned()),
+            command_line: node.command_line.as_deref(),
+            started_at_ms: unix_ms(node.started_at),
+            exited_at_ms: node.exited_at.map(unix_ms),
+            exit_code: node.exit_code,
+            hooked: node.hooked,
+        }
+    }
+}
+
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
@@ -0,0 +1,143 @@
+//! Information about processes which aren't necessarily hooked.
+
+use std::{io, path::PathBuf};
+
+/// A process which was running when the processes were enumerated.
+#[derive(Clone, Debug)]
+pub struct ProcessEntry {
+    pub pid: u32,
+    /// The file name of the executable, like `game.exe`.
+    pub name: String,
+}
+
+impl ProcessEntry {
+    /// Whether the executable's file name is `name`, ignoring case and an `.exe` extension.
+    pub fn is_named(&self, name: &str) -> bool {
+        fn normalize(name: &str) -> String {
+            let name = name.to_lowercase();
+            match name.strip_suffix(".exe") {
+                Some(stem) => stem.to_owned(),
+                None => name,
+            }
+        }
+
+        normalize(&self.name) == normalize(name)
+    }
+}
+
+/// The running processes whose executable's file name is `name`, ignoring case and an `.exe` extension.
+pub fn find_by_name(name: &str) -> io::Result<Vec<ProcessEntry>> {
+    Ok(processes()?
+        .into_iter()
+        .filter(|entry| entry.is_named(name))
+        .collect())
+}
+
+/// Returns every running process.
+#[cfg(windows)]
+pub fn processes() -> io::Result<Vec<ProcessEntry>> {
+    use std::{ffi::OsString, mem, os::windows::ffi::OsStringExt};
+
+    use winapi::{
+        shared::minwindef::{DWORD, FALSE},
+        um::{
+            handleapi::{CloseHandle, INVALID_HANDLE_VALUE},
+            tlhelp32::{
+                CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+                TH32CS_SNAPPROCESS,
+            },
+        },
+    };
+
+    let snapshot = unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0) };
+    if snapshot == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut processes = Vec::new();
+    let mut entry: PROCESSENTRY32W = unsafe { mem::zeroed() };
+    entry.dwSize = mem::size_of::<PROCESSENTRY32W>() as DWORD;
+    let mut res = unsafe { Process32FirstW(snapshot, &mut entry) };
+    while res != FALSE {
+        let len = entry
+            .szExeFile
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or_else(|| entry.szExeFile.len());
+        processes.push(ProcessEntry {
+            pid: entry.th32ProcessID,
+            name: OsString::from_wide(&entry.szExeFile[..len])
+                .to_string_lossy()
+                .into_owned(),
+        });
+        res = unsafe { Process32NextW(snapshot, &mut entry) };
+    }
+    unsafe { CloseHandle(snapshot) };
+
+    Ok(processes)
+}
+
+/// Returns every running process.
+#[cfg(target_os = "linux")]
+pub fn processes() -> io::Result<Vec<ProcessEntry>> {
+    use std::fs;
+
+    let mut processes = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_str().and_then(|pid| pid.parse().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        // `comm` is truncated to 15 bytes, so the name of the executable is preferred.
+        let executable = executable_path(pid);
+        let name = match executable.as_ref().and_then(|path| path.file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => match fs::read_to_string(entry.path().join("comm")) {
+                Ok(comm) => comm.trim_end().to_owned(),
+                // The process exited while we were looking at it.
+                Err(_) => continue,
+            },
+        };
+        processes.push(ProcessEntry { pid, name });
+    }
+
+    Ok(processes)
+}
+
+/// Returns the path to the executable of the process with the given id.
+#[cfg(windows)]
+pub fn executable_path(pid: u32) -> Option<PathBuf> {
+    use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+
+    use winapi::{
+        shared::minwindef::{DWORD, FALSE, MAX_PATH},
+        um::{
+            handleapi::CloseHandle, processthreadsapi::OpenProcess,
+            winbase::QueryFullProcessImageNameW, winnt::PROCESS_QUERY_LIMITED_INFORMATION,
+        },
+    };
+
+    let handle = unsafe { OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid) };
+    if handle.is_null() {
+        return None;
+    }
+
+    // Paths may be longer than `MAX_PATH` if they're prefixed with `\\?\`.
+    let mut buffer = vec![0u16; MAX_PATH * 4];
+    let mut len = buffer.len() as DWORD;
+    let res = unsafe { QueryFullProcessImageNameW(handle, 0, buffer.as_mut_ptr(), &mut len) };
+    unsafe { CloseHandle(handle) };
+
+    if res == 0 {
+        None
+    } else {
+        Some(OsString::from_wide(&buffer[..len as usize]).into())
+    }
+}
+
+/// Returns the path to the executable of the process with the given id.
+#[cfg(target_os = "linux")]
+pub fn executable_path(pid: u32) -> Option<PathBuf> {
+    std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+}
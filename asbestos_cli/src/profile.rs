@@ -0,0 +1,55 @@
+//! Presentation of the hook timings reported by payloads.
+
+use std::fmt::Write;
+
+use asbestos::shared::protocol::HookProfile;
+
+/// Render a `HookProfile` as a table with one row per hook and phase.
+pub fn format_hook_profile(profile: &HookProfile) -> String {
+    let mut hooks = profile.hooks.iter().collect::<Vec<_>>();
+    hooks.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:<24} {:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "hook", "phase", "count", "mean", "p50", "p99", "max"
+    )
+    .ok();
+    for hook in hooks {
+        for (phase, histogram) in [
+            ("total", &hook.total),
+            ("resolve", &hook.resolve),
+            ("call", &hook.call),
+        ]
+        .iter()
+        {
+            if histogram.count == 0 {
+                continue;
+            }
+            writeln!(
+                out,
+                "{:<24} {:<8} {:>10} {:>10} {:>10} {:>10} {:>10}",
+                hook.name,
+                phase,
+                histogram.count,
+                format_ns(histogram.mean_ns()),
+                format_ns(histogram.percentile_ns(0.5)),
+                format_ns(histogram.percentile_ns(0.99)),
+                format_ns(histogram.max_ns),
+            )
+            .ok();
+        }
+    }
+    out
+}
+
+fn format_ns(ns: u64) -> String {
+    if ns < 1_000 {
+        format!("{}ns", ns)
+    } else if ns < 1_000_000 {
+        format!("{:.1}us", ns as f64 / 1_000.0)
+    } else {
+        format!("{:.1}ms", ns as f64 / 1_000_000.0)
+    }
+}
@@ -0,0 +1,172 @@
+//! Starting a session with either backend, and servicing it the same way regardless.
+
+use std::{fmt, str::FromStr, time::Duration};
+
+use asbestos::{Event, ProcessTree, SessionBuilder};
+
+/// How the mappings are applied to the target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backend {
+    /// Inject the payload, which hooks the file system functions. Windows only.
+    Payload,
+    /// Trace the system calls of the target and rewrite the paths passed to them. Linux only.
+    Ptrace,
+    /// Have a seccomp filter hand the system calls taking paths to the CLI, which carries out the redirected ones.
+    /// Linux only, and only for processes the CLI spawns.
+    Seccomp,
+}
+
+impl Default for Backend {
+    #[cfg(not(target_os = "linux"))]
+    fn default() -> Self {
+        Self::Payload
+    }
+
+    #[cfg(target_os = "linux")]
+    fn default() -> Self {
+        Self::Ptrace
+    }
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "payload" => Ok(Self::Payload),
+            "ptrace" => Ok(Self::Ptrace),
+            "seccomp" => Ok(Self::Seccomp),
+            _ => Err(format!(r#"Unknown backend "{}""#, s)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Payload => write!(f, "payload"),
+            Self::Ptrace => write!(f, "ptrace"),
+            Self::Seccomp => write!(f, "seccomp"),
+        }
+    }
+}
+
+/// What `run` needs from a session, which every kind of session provides.
+pub trait RunningSession {
+    fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event>;
+    fn tree(&self) -> &ProcessTree;
+    fn is_finished(&self) -> bool;
+    fn root_pid(&self) -> u32;
+    fn pids(&self) -> Vec<u32>;
+    fn detach_all(&mut self);
+}
+
+/// Start the session with the given backend, if it's available on this platform.
+pub fn start(session: SessionBuilder, backend: Backend) -> Result<Box<dyn RunningSession>, String> {
+    match backend {
+        #[cfg(windows)]
+        Backend::Payload => match session.start() {
+            Ok(session) => Ok(Box::new(session)),
+            Err(err) => Err(err.to_string()),
+        },
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        Backend::Ptrace => match session.start_ptrace() {
+            Ok(session) => Ok(Box::new(session)),
+            Err(err) => Err(err.to_string()),
+        },
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        Backend::Seccomp => match session.start_seccomp() {
+            Ok(session) => Ok(Box::new(session)),
+            Err(err) => Err(err.to_string()),
+        },
+        #[allow(unreachable_patterns)]
+        _ => {
+            drop(session);
+            Err(format!(
+                "The {} backend isn't available on this platform",
+                backend
+            ))
+        }
+    }
+}
+
+#[cfg(windows)]
+impl RunningSession for asbestos::Session {
+    fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.next_event_timeout(timeout)
+    }
+
+    fn tree(&self) -> &ProcessTree {
+        self.tree()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.is_finished()
+    }
+
+    fn root_pid(&self) -> u32 {
+        self.root_pid()
+    }
+
+    fn pids(&self) -> Vec<u32> {
+        self.pids().collect()
+    }
+
+    fn detach_all(&mut self) {
+        self.detach_all()
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+impl RunningSession for asbestos::PtraceSession {
+    fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.next_event_timeout(timeout)
+    }
+
+    fn tree(&self) -> &ProcessTree {
+        self.tree()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.is_finished()
+    }
+
+    fn root_pid(&self) -> u32 {
+        self.root_pid()
+    }
+
+    fn pids(&self) -> Vec<u32> {
+        self.pids().collect()
+    }
+
+    fn detach_all(&mut self) {
+        self.detach_all()
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+impl RunningSession for asbestos::SeccompSession {
+    fn next_event_timeout(&mut self, timeout: Duration) -> Option<Event> {
+        self.next_event_timeout(timeout)
+    }
+
+    fn tree(&self) -> &ProcessTree {
+        self.tree()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.is_finished()
+    }
+
+    fn root_pid(&self) -> u32 {
+        self.root_pid()
+    }
+
+    fn pids(&self) -> Vec<u32> {
+        self.pids().collect()
+    }
+
+    fn detach_all(&mut self) {
+        self.detach_all()
+    }
+}
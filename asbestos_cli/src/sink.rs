@@ -0,0 +1,222 @@
+//! Destinations for the messages received from payloads.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::process;
+
+/// How many rotated log files are kept unless configured otherwise. Also the default of `--log-max-files`, which is why
+/// it's a string.
+pub const DEFAULT_MAX_FILES: &str = "5";
+
+/// The contents of a file passed with `--log-config`.
+#[derive(Debug, Default, Deserialize)]
+pub struct LogConfig {
+    pub sinks: Vec<SinkConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum SinkConfig {
+    Stdout,
+    Stderr,
+    /// Append everything to a single file.
+    File {
+        path: PathBuf,
+    },
+    /// Write to `path` until it grows beyond `max_size` bytes, at which point it is renamed to `path.1`, `path.1` is
+    /// renamed to `path.2` and so on. No more than `max_files` old files are kept.
+    Rotating {
+        path: PathBuf,
+        max_size: u64,
+        #[serde(default = "default_max_files")]
+        max_files: usize,
+    },
+    /// Write the messages from each process to a separate file in `directory`, named after the process's
+    /// executable and its pid.
+    PerProcess {
+        directory: PathBuf,
+    },
+}
+
+fn default_max_files() -> usize {
+    DEFAULT_MAX_FILES.parse().unwrap()
+}
+
+impl LogConfig {
+    pub fn load(path: &Path) -> Result<Self, ()> {
+        let file = match File::open(path) {
+            Ok(ok) => ok,
+            Err(err) => {
+                eprintln!("Could not open {}: {}", path.display(), err);
+                return Err(());
+            }
+        };
+        serde_json::from_reader(io::BufReader::new(file)).map_err(|err| {
+            eprintln!("Could not deserialize log config file: {}", err);
+        })
+    }
+}
+
+/// Writes lines to every configured sink.
+pub struct Logger {
+    sinks: Vec<Sink>,
+}
+
+impl Logger {
+    /// Open every sink in `config`, or only `default` if `config` doesn't specify any sinks.
+    pub fn new(config: &LogConfig, default: SinkConfig) -> io::Result<Self> {
+        let sinks = if config.sinks.is_empty() {
+            vec![Sink::open(&default)?]
+        } else {
+            config
+                .sinks
+                .iter()
+                .map(Sink::open)
+                .collect::<io::Result<_>>()?
+        };
+        Ok(Self { sinks })
+    }
+
+    /// Write a line which concerns the process `pid`.
+    pub fn log(&mut self, pid: u32, line: &str) {
+        for sink in self.sinks.iter_mut() {
+            if let Err(err) = sink.write_line(pid, line) {
+                eprintln!("Could not write to log: {}", err);
+            }
+        }
+    }
+}
+
+enum Sink {
+    Stdout,
+    Stderr,
+    File(LineWriter<File>),
+    Rotating(RotatingFile),
+    PerProcess(PerProcessFiles),
+}
+
+impl Sink {
+    fn open(config: &SinkConfig) -> io::Result<Self> {
+        Ok(match config {
+            SinkConfig::Stdout => Self::Stdout,
+            SinkConfig::Stderr => Self::Stderr,
+            SinkConfig::File { path } => Self::File(LineWriter::new(open_append(path)?)),
+            SinkConfig::Rotating {
+                path,
+                max_size,
+                max_files,
+            } => Self::Rotating(RotatingFile::open(path.clone(), *max_size, *max_files)?),
+            SinkConfig::PerProcess { directory } => {
+                fs::create_dir_all(directory)?;
+                Self::PerProcess(PerProcessFiles {
+                    directory: directory.clone(),
+                    files: HashMap::new(),
+                })
+            }
+        })
+    }
+
+    fn write_line(&mut self, pid: u32, line: &str) -> io::Result<()> {
+        match self {
+            Self::Stdout => {
+                println!("{}", line);
+                Ok(())
+            }
+            Self::Stderr => {
+                eprintln!("{}", line);
+                Ok(())
+            }
+            Self::File(file) => writeln!(file, "{}", line),
+            Self::Rotating(file) => file.write_line(line),
+            Self::PerProcess(files) => writeln!(files.get(pid)?, "{}", line),
+        }
+    }
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: LineWriter<File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file: LineWriter::new(file),
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size != 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            self.file = LineWriter::new(File::create(&self.path)?);
+        } else {
+            fs::remove_file(self.rotated_path(self.max_files)).ok();
+            for n in (1..self.max_files).rev() {
+                fs::rename(self.rotated_path(n), self.rotated_path(n + 1)).ok();
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+            self.file = LineWriter::new(open_append(&self.path)?);
+        }
+        self.size = 0;
+
+        Ok(())
+    }
+
+    fn rotated_path(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+}
+
+struct PerProcessFiles {
+    directory: PathBuf,
+    files: HashMap<u32, LineWriter<File>>,
+}
+
+impl PerProcessFiles {
+    fn get(&mut self, pid: u32) -> io::Result<&mut LineWriter<File>> {
+        if !self.files.contains_key(&pid) {
+            let name = process::executable_path(pid)
+                .and_then(|path| {
+                    path.file_stem()
+                        .map(|stem| stem.to_string_lossy().into_owned())
+                })
+                .unwrap_or_else(|| "unknown".to_owned());
+            let path = self.directory.join(format!("{}-{}.log", name, pid));
+            self.files.insert(pid, LineWriter::new(open_append(&path)?));
+        }
+        Ok(self.files.get_mut(&pid).unwrap())
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
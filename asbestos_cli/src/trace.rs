@@ -0,0 +1,65 @@
+//! Export of `TraceEvent`s in the Chrome Trace Event Format.
+//!
+//! The resulting file can be opened in `chrome://tracing` or in Perfetto. Events are grouped by process and thread
+//! since every event carries the pid of the payload that sent it and the tid of the thread which made the call.
+//!
+//! See https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU for the format.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use serde_json::json;
+
+use asbestos::shared::protocol::TraceEvent;
+
+pub struct ChromeTrace {
+    out: BufWriter<File>,
+    empty: bool,
+}
+
+impl ChromeTrace {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        write!(out, "[")?;
+        Ok(Self { out, empty: true })
+    }
+
+    pub fn record(&mut self, pid: u32, event: &TraceEvent) -> io::Result<()> {
+        let event = json!({
+            "name": event.name,
+            "cat": "hook",
+            "ph": "X",
+            "ts": event.begin,
+            "dur": event.end.saturating_sub(event.begin),
+            "pid": pid,
+            "tid": event.tid,
+            "args": {
+                "detail": event.detail,
+            },
+        });
+        self.write_event(&event)
+    }
+
+    fn write_event(&mut self, event: &serde_json::Value) -> io::Result<()> {
+        if !self.empty {
+            writeln!(self.out, ",")?;
+        } else {
+            writeln!(self.out)?;
+        }
+        self.empty = false;
+        serde_json::to_writer(&mut self.out, event)?;
+        Ok(())
+    }
+
+    /// Terminate the JSON array.
+    ///
+    /// The array format allows the closing bracket to be missing, so a trace is still usable if asbestos is
+    /// terminated before getting to call this.
+    pub fn finish(mut self) -> io::Result<()> {
+        writeln!(self.out, "\n]")?;
+        self.out.flush()
+    }
+}
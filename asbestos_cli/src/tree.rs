@@ -0,0 +1,66 @@
+//! Sharing the process tree of a running session with `asbestos_cli tree`.
+//!
+//! Every running session writes its tree to `asbestos_cli-<pid>.tree.json` in the temporary directory, where `<pid>`
+//! is the id of the `asbestos_cli` process running the session. The file is removed when the session ends.
+
+use std::{
+    env, fs,
+    io::{self, BufReader},
+    path::PathBuf,
+    process,
+};
+
+use asbestos::ProcessTree;
+
+const PREFIX: &str = "asbestos_cli-";
+const SUFFIX: &str = ".tree.json";
+
+fn state_path(cli_pid: u32) -> PathBuf {
+    env::temp_dir().join(format!("{}{}{}", PREFIX, cli_pid, SUFFIX))
+}
+
+/// Publish the tree of the session run by this process.
+pub fn write_state(tree: &ProcessTree) -> io::Result<()> {
+    let path = state_path(process::id());
+    // Written to a separate file first so that readers never see a partially written tree.
+    let partial = path.with_extension("partial");
+    let json = serde_json::to_vec(tree).map_err(io::Error::other)?;
+    fs::write(&partial, json)?;
+    fs::rename(&partial, &path)
+}
+
+pub fn remove_state() {
+    fs::remove_file(state_path(process::id())).ok();
+}
+
+/// Read the trees of every running session, along with the ids of the processes running them.
+pub fn read_states() -> io::Result<Vec<(u32, ProcessTree)>> {
+    let mut states = Vec::new();
+    for entry in fs::read_dir(env::temp_dir())? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let cli_pid = match file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(PREFIX))
+            .and_then(|name| name.strip_suffix(SUFFIX))
+            .and_then(|pid| pid.parse().ok())
+        {
+            Some(cli_pid) => cli_pid,
+            None => continue,
+        };
+        // Left behind by a session which didn't end cleanly.
+        if crate::process::executable_path(cli_pid).is_none() {
+            fs::remove_file(entry.path()).ok();
+            continue;
+        }
+        let file = match fs::File::open(entry.path()) {
+            Ok(file) => file,
+            Err(_) => continue,
+        };
+        if let Ok(tree) = serde_json::from_reader(BufReader::new(file)) {
+            states.push((cli_pid, tree));
+        }
+    }
+    states.sort_by_key(|(cli_pid, _)| *cli_pid);
+    Ok(states)
+}
@@ -92,13 +92,24 @@ use winapi::{
 
 use asbestos_shared::log_error;
 
-use super::decl_detour;
+use super::{call_real, decl_detour};
 
 use crate::{
     missing_from_winapi::{PFILE_BASIC_INFORMATION, PIO_STATUS_BLOCK},
+    profile::{self, Phase},
     vfs,
 };
 
+/// Read `ObjectAttributes.ObjectName` for the sake of diagnostics.
+fn object_name_lossy(object_attributes: POBJECT_ATTRIBUTES) -> String {
+    NonNull::new(object_attributes)
+        .and_then(|object_attributes| NonNull::new(unsafe { object_attributes.as_ref() }.ObjectName))
+        .map(|object_name| unsafe { object_name.as_ref() }.Buffer)
+        .filter(|buffer| !buffer.is_null())
+        .map(|buffer| unsafe { U16CStr::from_ptr_str(buffer) }.to_string_lossy())
+        .unwrap_or_default()
+}
+
 decl_detour!(
     "ntdll.dll",
     ntcreatefile,
@@ -116,7 +127,7 @@ decl_detour!(
         ULONG              EaLength
     ) {
         let mut conn = crate::get_conn();
-        let conn = conn.as_mut().unwrap();
+        let conn = &mut *conn;
 
         if let Some(mut object_attributes) = NonNull::new(ObjectAttributes) {
             let object_attributes = unsafe { object_attributes.as_mut() };
@@ -140,7 +151,7 @@ decl_detour!(
 
                     let os_object_name_2 = object_name_2.to_os_string();
                     let utf8_object_name_2 = os_object_name_2.to_string_lossy();
-                    match vfs::resolve_path(Some(conn), os_object_name_2.as_ref()) {
+                    match profile::timed(NAME, Phase::Resolve, || vfs::resolve_path(Some(&mut *conn), os_object_name_2.as_ref())) {
                         Err(err) => {
                             log_error!(conn, "Error while redirecting from {}: {}", utf8_object_name_2, err).ok();
                         }
@@ -169,7 +180,7 @@ decl_detour!(
                                     ..*object_attributes
                                 };
 
-                                let res = unsafe {
+                                let res = call_real(conn, NAME, || utf8_object_name_2.to_string(), || unsafe {
                                     Hook.call(
                                         FileHandle,
                                         DesiredAccess,
@@ -183,7 +194,7 @@ decl_detour!(
                                         EaBuffer,
                                         EaLength,
                                     )
-                                };
+                                });
 
                                 // Update the fields of `ObjectAttributes` just in case the call to `NtCreateFile`
                                 // mutated anything. While it is very unlikely that `ObjectAttributes` will be mutated
@@ -220,7 +231,7 @@ decl_detour!(
 
         // If any of the inputs contain null pointers or if there's no need to redirect the call to `NtCreateFile` then
         // we pass through all the unmodified arguments to `NtCreateFile`.
-        unsafe {
+        call_real(conn, NAME, || object_name_lossy(ObjectAttributes), || unsafe {
             Hook.call(
                 FileHandle,
                 DesiredAccess,
@@ -234,7 +245,7 @@ decl_detour!(
                 EaBuffer,
                 EaLength,
             )
-        }
+        })
     }
 );
 
@@ -246,7 +257,7 @@ decl_detour!(
         PFILE_BASIC_INFORMATION FileInformation
     ) {
         let mut conn = crate::get_conn();
-        let conn = conn.as_mut().unwrap();
+        let conn = &mut *conn;
 
         if let Some(mut object_attributes) = NonNull::new(ObjectAttributes) {
             let object_attributes = unsafe { object_attributes.as_mut() };
@@ -270,7 +281,7 @@ decl_detour!(
 
                     let os_object_name_2 = object_name_2.to_os_string();
                     let utf8_object_name_2 = os_object_name_2.to_string_lossy();
-                    match vfs::resolve_path(Some(conn), os_object_name_2.as_ref()) {
+                    match profile::timed(NAME, Phase::Resolve, || vfs::resolve_outline_path(Some(&mut *conn), os_object_name_2.as_ref())) {
                         Err(err) => {
                             log_error!(conn, "Error while redirecting from {}: {}", utf8_object_name_2, err).ok();
                         }
@@ -299,12 +310,12 @@ decl_detour!(
                                     ..*object_attributes
                                 };
 
-                                let res = unsafe {
+                                let res = call_real(conn, NAME, || utf8_object_name_2.to_string(), || unsafe {
                                     Hook.call(
                                         &mut new_object_attributes,
                                         FileInformation,
                                     )
-                                };
+                                });
 
                                 // Update the fields of `ObjectAttributes` just in case the call to `NtQueryAttributesFile`
                                 // mutated anything. While it is very unlikely that `ObjectAttributes` will be mutated
@@ -341,11 +352,11 @@ decl_detour!(
 
         // If any of the inputs contain null pointers or if there's no need to redirect the call to `NtCreateFile` then
         // we pass through all the unmodified arguments to `NtCreateFile`.
-        unsafe {
+        call_real(conn, NAME, || object_name_lossy(ObjectAttributes), || unsafe {
             Hook.call(
                 ObjectAttributes,
                 FileInformation,
             )
-        }
+        })
     }
 );
@@ -1,4 +1,16 @@
-use std::{error::Error, fmt};
+use std::{
+    cell::Cell,
+    error::Error,
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    profile::{self, Phase},
+    trace, Link,
+};
 
 pub mod file;
 pub mod ntdll;
@@ -14,12 +26,31 @@ macro_rules! _decl_detour {
 
             use asbestos_shared::{log_info, log_trace};
 
+            #[allow(dead_code)]
+            pub(crate) const NAME: &str = stringify!($name);
+
                 static_detour! {
-                    static Hook: unsafe extern "system" fn($($arg_type),*) -> $ret;
+                    static Detour: unsafe extern "system" fn($($arg_type),*) -> $ret;
+                }
+
+                std::thread_local! {
+                    static REAL_RESULT: std::cell::Cell<Option<$ret>> = std::cell::Cell::new(None);
                 }
 
-                pub unsafe fn hook<R: std::io::Read, W: std::io::Write>(
-                    conn: &mut crate::Connection<R, W>,
+                /// Calls the real function, remembering its result in case the detour panics afterwards.
+                struct Hook;
+
+                impl Hook {
+                    #[allow(non_snake_case)]
+                    unsafe fn call(&self, $($arg_name: $arg_type),*) -> $ret {
+                        let ret = Detour.call($($arg_name),*);
+                        REAL_RESULT.with(|result| result.set(Some(ret)));
+                        ret
+                    }
+                }
+
+                pub unsafe fn hook(
+                    conn: &mut crate::Link,
                 ) -> Result<(), Box<dyn std::error::Error>> {
                     log_trace!(
                     conn,
@@ -36,7 +67,7 @@ macro_rules! _decl_detour {
                     conn,
                     concat!("Initalizing ", stringify!($name), "'s hook")
                 )?;
-                Hook.initialize(target, detour)?.enable()?;
+                Detour.initialize(target, detour)?.enable()?;
                 log_info!(
                     conn,
                     concat!(stringify!($name), "'s hook has been initialized")
@@ -44,9 +75,38 @@ macro_rules! _decl_detour {
                 Ok(())
             }
 
+            /// Disable the hook, so that calls go straight to the real function again.
+            pub unsafe fn unhook() -> Result<(), Box<dyn std::error::Error>> {
+                if Detour.is_enabled() {
+                    Detour.disable()?;
+                }
+                Ok(())
+            }
+
             #[allow(non_snake_case)]
             pub fn detour($($arg_name: $arg_type),*) -> $ret {
-                $detour_body
+                if crate::hooks::Bypass::active() {
+                    return unsafe { Detour.call($($arg_name),*) };
+                }
+                let _active = crate::hooks::ActiveCall::enter();
+                let _timer = crate::profile::Timer::start(NAME, crate::profile::Phase::Total);
+
+                // The detour may be reentered by the calls it makes, so the outer call's result is put back after.
+                let outer = REAL_RESULT.with(|result| result.take());
+                let ret = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| $detour_body)) {
+                    Ok(ret) => ret,
+                    // The panic is reported now that the connection has been let go of. Unless the panic happened after
+                    // the real function was called, the call is let through as if there was no hook.
+                    Err(_) => {
+                        crate::report_pending_panic();
+                        match REAL_RESULT.with(|result| result.get()) {
+                            Some(ret) => ret,
+                            None => unsafe { Detour.call($($arg_name),*) },
+                        }
+                    }
+                };
+                REAL_RESULT.with(|result| result.set(outer));
+                ret
             }
         }
     };
@@ -54,6 +114,104 @@ macro_rules! _decl_detour {
 
 pub(crate) use _decl_detour as decl_detour;
 
+/// The number of threads currently inside one of our detours.
+static ACTIVE_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts the current thread as being inside a detour for as long as the guard lives.
+pub(crate) struct ActiveCall(());
+
+impl ActiveCall {
+    pub(crate) fn enter() -> Self {
+        ACTIVE_CALLS.fetch_add(1, Ordering::SeqCst);
+        DETOUR_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Self(())
+    }
+
+    /// Whether the current thread is inside a detour.
+    pub(crate) fn inside() -> bool {
+        DETOUR_DEPTH.with(Cell::get) != 0
+    }
+}
+
+impl Drop for ActiveCall {
+    fn drop(&mut self) {
+        ACTIVE_CALLS.fetch_sub(1, Ordering::SeqCst);
+        DETOUR_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+thread_local! {
+    static BYPASS: Cell<bool> = Cell::new(false);
+    /// The number of detours the current thread is inside of.
+    static DETOUR_DEPTH: Cell<usize> = Cell::new(0);
+}
+
+/// Makes calls from the current thread go straight to the real functions for as long as it lives.
+///
+/// Used when the payload itself touches the file system while holding the connection, which a hook would otherwise
+/// wait for forever.
+pub(crate) struct Bypass(bool);
+
+impl Bypass {
+    pub(crate) fn enter() -> Self {
+        Self(BYPASS.with(|bypass| bypass.replace(true)))
+    }
+
+    pub(crate) fn active() -> bool {
+        BYPASS.with(Cell::get)
+    }
+}
+
+impl Drop for Bypass {
+    fn drop(&mut self) {
+        BYPASS.with(|bypass| bypass.set(self.0));
+    }
+}
+
+/// Disable every hook. Returns the names of the hooks which couldn't be disabled, along with the reason.
+pub(crate) unsafe fn unhook_all() -> Vec<(&'static str, Box<dyn Error>)> {
+    let unhooks: [(&'static str, unsafe fn() -> Result<(), Box<dyn Error>>); 3] = [
+        (file::ntcreatefile::NAME, file::ntcreatefile::unhook),
+        (
+            file::ntqueryattributesfile::NAME,
+            file::ntqueryattributesfile::unhook,
+        ),
+        (
+            process::createprocessinternalw::NAME,
+            process::createprocessinternalw::unhook,
+        ),
+    ];
+    let mut failed = Vec::new();
+    for (name, unhook) in unhooks.iter() {
+        if let Err(err) = unhook() {
+            failed.push((*name, err));
+        }
+    }
+    failed
+}
+
+/// Wait for the threads inside our detours to leave them, so that the payload can be unloaded without pulling the
+/// code out from under them. Returns `false` if some were still inside once `timeout` had passed.
+pub(crate) fn wait_for_active_calls(timeout: Duration) -> bool {
+    let start = Instant::now();
+    while ACTIVE_CALLS.load(Ordering::SeqCst) != 0 {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    true
+}
+
+/// Call the real function behind a hook, tracing and timing the call if that has been requested.
+pub(crate) fn call_real<T, D, F>(conn: &mut Link, name: &'static str, detail: D, f: F) -> T
+where
+    D: FnOnce() -> String,
+    F: FnOnce() -> T,
+{
+    trace::traced(conn, name, detail, || profile::timed(name, Phase::Call, f))
+}
+
 #[derive(Debug)]
 enum HookError {
     SymbolAddressNotFound {
@@ -3,7 +3,7 @@
 //! `CreateProcessAsUser`, `CreateProcessWithLogon` and `CreateProcessWithToken` are currently not
 //! hooked since they're probably not used much in games.
 
-use std::{ffi::OsStr, iter, mem, os::windows::ffi::OsStrExt};
+use std::{ffi::OsStr, iter, mem, os::windows::ffi::OsStrExt, sync::atomic::Ordering};
 
 use widestring::U16CStr;
 use winapi::{
@@ -23,12 +23,28 @@ use winapi::{
 use asbestos_shared::{
     log_error,
     protocol::{Message, ProcessSpawned},
+    rules::executable_from_command_line,
 };
 
-use crate::{get_conn, vfs};
+use crate::{
+    get_conn,
+    profile::{self, Phase},
+    trace, vfs, DEPTH, SUBPROCESS_RULES,
+};
 
 use super::decl_detour;
 
+/// Describe the process being created for the sake of diagnostics.
+fn application_name_lossy(application_name: LPCWSTR, command_line: LPWSTR) -> String {
+    if !application_name.is_null() {
+        unsafe { U16CStr::from_ptr_str(application_name) }.to_string_lossy()
+    } else if !command_line.is_null() {
+        unsafe { U16CStr::from_ptr_str(command_line) }.to_string_lossy()
+    } else {
+        String::new()
+    }
+}
+
 // This is called by both `CreateProcessA` and `CreateProcessW`
 decl_detour!(
     "KernelBase.dll",
@@ -47,18 +63,41 @@ decl_detour!(
         LPPROCESS_INFORMATION lpProcessInformation,
         PHANDLE               hNewToken
     )  {
+        let command_line = if lpCommandLine.is_null() {
+            String::new()
+        } else {
+            unsafe { U16CStr::from_ptr_str(lpCommandLine) }.to_string_lossy()
+        };
+        let executable = if lpApplicationName.is_null() {
+            executable_from_command_line(&command_line).to_owned()
+        } else {
+            unsafe { U16CStr::from_ptr_str(lpApplicationName) }.to_string_lossy()
+        };
+        let verdict = SUBPROCESS_RULES.lock().unwrap().evaluate(
+            &executable,
+            &command_line,
+            DEPTH.load(Ordering::SeqCst) + 1,
+        );
+        // Only the main thread of a subprocess which is going to be hooked needs to wait for the payload.
+        let (creation_flags, suspended) = if verdict.hook && dwCreationFlags & CREATE_SUSPENDED == 0 {
+            (dwCreationFlags | CREATE_SUSPENDED, true)
+        } else {
+            (dwCreationFlags, false)
+        };
+
         let mut result = None;
+        let mut span = None;
 
         if !lpApplicationName.is_null() {
             let mut conn_lock = get_conn();
-            let conn = conn_lock.as_mut().unwrap();
+            let conn = &mut *conn_lock;
 
             let os_file_name = unsafe { U16CStr::from_ptr_str(lpApplicationName) }.to_os_string();
             let utf8_file_name = os_file_name.to_string_lossy();
 
             log_info!(conn, "CreateProcessInternalW(lpApplicationName: {})", utf8_file_name).ok();
 
-            match vfs::resolve_path(Some(conn), os_file_name.as_ref()) {
+            match profile::timed(NAME, Phase::Resolve, || vfs::resolve_path(Some(&mut *conn), os_file_name.as_ref())) {
                 Err(err) => {
                     log_error!(conn, "Error while redirecting from {}: {}", utf8_file_name, err).ok();
                 }
@@ -70,7 +109,8 @@ decl_detour!(
 
                         mem::drop(conn_lock);
 
-                        let res = unsafe {
+                        span = trace::Span::begin(NAME, || utf8_file_name.to_string());
+                        let res = profile::timed(NAME, Phase::Call, || unsafe {
                             Hook.call(
                                 hUserToken,
                                 redirected_object_name.as_ptr(),
@@ -78,14 +118,14 @@ decl_detour!(
                                 lpProcessAttributes,
                                 lpThreadAttributes,
                                 bInheritHandles,
-                                dwCreationFlags | CREATE_SUSPENDED,
+                                creation_flags,
                                 lpEnvironment,
                                 lpCurrentDirectory,
                                 lpStartupInfo,
                                 lpProcessInformation,
                                 hNewToken,
                             )
-                        };
+                        });
 
                         result = Some(res);
                     }
@@ -94,7 +134,8 @@ decl_detour!(
         }
 
         if result.is_none() {
-            let res = unsafe {
+            span = trace::Span::begin(NAME, || application_name_lossy(lpApplicationName, lpCommandLine));
+            let res = profile::timed(NAME, Phase::Call, || unsafe {
                 Hook.call(
                     hUserToken,
                     lpApplicationName,
@@ -102,20 +143,26 @@ decl_detour!(
                     lpProcessAttributes,
                     lpThreadAttributes,
                     bInheritHandles,
-                    dwCreationFlags | CREATE_SUSPENDED,
+                    creation_flags,
                     lpEnvironment,
                     lpCurrentDirectory,
                     lpStartupInfo,
                     lpProcessInformation,
                     hNewToken,
                 )
-            };
+            });
 
             result = Some(res);
         }
 
+        let trace_event = span.map(trace::Span::finish);
+
         let mut conn = get_conn();
-        let conn = conn.as_mut().unwrap();
+        let conn = &mut *conn;
+
+        if let Some(trace_event) = trace_event {
+            conn.write_message(trace_event).ok();
+        }
 
         let res = result.unwrap();
 
@@ -127,10 +174,20 @@ decl_detour!(
             return res;
         }
 
+        // TODO: Figure out what to do if `lpProcessInformation` is null.
+        let pid = unsafe { *lpProcessInformation }.dwProcessId;
+        if verdict.hook {
+            log_info!(conn, "Hooking {} ({}) since {}", pid, executable, verdict.reason).ok();
+        } else {
+            log_info!(conn, "Not hooking {} ({}) since {}", pid, executable, verdict.reason).ok();
+        }
+
         conn.write_message(Message::ProcessSpawned(ProcessSpawned {
-            // TODO: Figure out what to do if `lpProcessInformation` is null.
-            pid: unsafe { *lpProcessInformation }.dwProcessId,
+            pid,
             tid: unsafe { *lpProcessInformation }.dwThreadId,
+            executable,
+            command_line,
+            suspended,
         }))
         .ok();
 
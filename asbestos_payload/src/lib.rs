@@ -1,22 +1,27 @@
 use std::{
+    cell::RefCell,
     env,
     error::Error,
-    io::BufReader,
-    panic, process,
+    io::{self, BufReader},
+    mem, panic, process,
     sync::{
-        atomic::{AtomicBool, Ordering},
+        atomic::{AtomicU32, AtomicUsize, Ordering},
         Mutex, MutexGuard, TryLockError,
     },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use backtrace::Backtrace;
 use lazy_static::lazy_static;
+use widestring::U16CStr;
 use winapi::{
-    shared::minwindef::{BOOL, DWORD, FALSE, HINSTANCE, LPVOID, TRUE},
+    shared::minwindef::{BOOL, DWORD, FALSE, HINSTANCE, HMODULE, LPVOID, TRUE},
     um::{
         consoleapi::AllocConsole,
         handleapi::CloseHandle,
+        libloaderapi::FreeLibraryAndExitThread,
+        processenv::GetCommandLineW,
         processthreadsapi::{GetCurrentThreadId, OpenThread, ResumeThread},
         wincon::GetConsoleWindow,
         winnt::{DLL_PROCESS_ATTACH, DLL_PROCESS_DETACH, THREAD_SUSPEND_RESUME},
@@ -25,14 +30,23 @@ use winapi::{
 };
 
 use asbestos_shared::{
+    cache::ContentCache,
+    contents::Contents,
+    log_error, log_warn,
     named_pipe::PipeClient,
     named_pipe_name,
-    protocol::{Connection, Mappings, Message},
+    protocol::{Connection, Mappings, Message, PanicPolicy, PanicReport, ProcessInfo, StartupInfo},
+    rules::SubprocessRules,
     PipeEnd,
 };
 
+use link::Link;
+
 mod hooks;
+mod link;
 mod missing_from_winapi;
+mod profile;
+mod trace;
 mod util;
 pub mod vfs;
 
@@ -43,41 +57,65 @@ macro_rules! c_str {
     };
 }
 
-type PipeConnection = Connection<BufReader<PipeClient>, PipeClient>;
+/// How long the payload waits for threads to leave its hooks before unloading itself anyway.
+const DETACH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often `control` checks whether a new instance of asbestos wants to connect after losing the connection.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the panic hook waits for the connection before giving up on reporting the panic.
+const PANIC_REPORT_TIMEOUT: Duration = Duration::from_secs(1);
 
 lazy_static! {
-    static ref CONN: Mutex<Option<PipeConnection>> = Mutex::new(None);
+    static ref CONN: Mutex<Link> = Mutex::new(Link::new());
     static ref MAPPINGS: Mutex<Mappings> = Mutex::new(Mappings::default());
+    static ref CONTENTS: Mutex<Contents> = Mutex::new(Contents::default());
+    static ref SUBPROCESS_RULES: Mutex<SubprocessRules> = Mutex::new(SubprocessRules::default());
+    static ref PANIC_POLICY: Mutex<PanicPolicy> = Mutex::new(PanicPolicy::default());
+}
+
+/// The depth of this process in the process tree of the session.
+static DEPTH: AtomicU32 = AtomicU32::new(0);
+
+/// The `HMODULE` of the payload, which is needed to unload it.
+static MODULE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The report of a panic inside a detour, which is sent once the detour has caught the panic.
+    static PENDING_PANIC_REPORT: RefCell<Option<PanicReport>> = RefCell::new(None);
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub unsafe extern "system" fn DllMain(
-    _module: HINSTANCE,
+    module: HINSTANCE,
     call_reason: DWORD,
     _reserved: LPVOID,
 ) -> BOOL {
     if call_reason == DLL_PROCESS_ATTACH {
+        MODULE.store(module as usize, Ordering::SeqCst);
         install_panic_hook();
 
         match init_payload() {
             Ok(_) => {
-                let mut conn = CONN.lock().unwrap();
-                let conn = conn.as_mut().unwrap();
-                conn.write_message(Message::Initialized).ok();
+                get_conn().write_message(Message::Initialized).ok();
                 TRUE
             }
             Err(err) => {
-                let mut conn = CONN.lock().unwrap().take().unwrap();
+                let mut conn = get_conn();
                 conn.write_message(Message::InitializationFailed(err.to_string()))
                     .ok();
+                conn.disconnect();
                 FALSE
             }
         }
     } else if call_reason == DLL_PROCESS_DETACH {
         let f: fn() -> Result<(), Box<dyn Error>> = || {
-            if let Some(mut connection) = CONN.lock()?.take() {
-                connection.write_message(Message::ProcessDetach).ok();
+            let mut conn = CONN.lock()?;
+            if conn.connected() {
+                profile::report(&mut conn);
+                conn.write_message(Message::ProcessDetach).ok();
+                conn.disconnect();
             }
             Ok(())
         };
@@ -87,32 +125,28 @@ pub unsafe extern "system" fn DllMain(
     }
 }
 
-fn get_conn() -> MutexGuard<'static, Option<PipeConnection>> {
+fn get_conn() -> MutexGuard<'static, Link> {
     loop {
         let res = CONN.try_lock();
         match res {
             Ok(pipe) => return pipe,
-            Err(err) => {
-                if let TryLockError::Poisoned(_) = err {
-                    unreachable!("This should never happen. Ever.")
-                }
-            }
+            // A panic inside a hook leaves the link as usable as it was.
+            Err(TryLockError::Poisoned(err)) => return err.into_inner(),
+            Err(TryLockError::WouldBlock) => {}
         }
     }
 }
 
 fn init_payload() -> Result<(), Box<dyn Error>> {
-    let mut conn = Connection::new(
-        BufReader::new(PipeClient::connect_ms(
-            named_pipe_name(process::id(), PipeEnd::Tx),
-            500,
-        )?),
-        PipeClient::connect_ms(named_pipe_name(process::id(), PipeEnd::Rx), 500)?,
-    );
+    let mut conn = connect(500)?;
     let startup_info = match dbg!(conn.read_message()?) {
         Message::StartupInfo(si) => si,
         _ => Default::default(),
     };
+    let (reader, writer) = conn.split();
+    let mut conn = get_conn();
+    conn.connect(writer);
+    send_process_info(&mut conn);
 
     unsafe { AllocConsole() };
     let handle = unsafe { GetConsoleWindow() };
@@ -134,17 +168,143 @@ fn init_payload() -> Result<(), Box<dyn Error>> {
             hooks::process::createprocessinternalw::hook(&mut conn)?;
         }
     }
+    mem::drop(conn);
 
-    *MAPPINGS.lock().unwrap() = startup_info.mappings;
-    *CONN.lock().unwrap() = Some(conn);
+    let main_thread_suspended = startup_info.main_thread_suspended;
+    let tid = startup_info.tid;
+    apply_startup_info(startup_info);
+    thread::spawn(move || control(reader));
 
-    if startup_info.main_thread_suspended {
-        resume_main_thread(startup_info.tid);
+    if main_thread_suspended {
+        resume_main_thread(tid);
     }
 
     Ok(())
 }
 
+/// Connect to the pipes asbestos creates for this process, waiting up to `timeout_ms` for each of them.
+fn connect(timeout_ms: u32) -> io::Result<Connection<BufReader<PipeClient>, PipeClient>> {
+    Ok(Connection::new(
+        BufReader::new(PipeClient::connect_ms(
+            named_pipe_name(process::id(), PipeEnd::Tx),
+            timeout_ms,
+        )?),
+        PipeClient::connect_ms(named_pipe_name(process::id(), PipeEnd::Rx), timeout_ms)?,
+    ))
+}
+
+fn send_process_info(conn: &mut Link) {
+    conn.write_message(ProcessInfo {
+        executable: env::current_exe().unwrap_or_default(),
+        command_line: unsafe { U16CStr::from_ptr_str(GetCommandLineW()) }.to_string_lossy(),
+    })
+    .ok();
+}
+
+/// Apply the parts of the startup info which can change after the hooks have been installed.
+fn apply_startup_info(startup_info: StartupInfo) {
+    // A new instance of asbestos may not want what the previous one did.
+    trace::set_enabled(startup_info.trace_hooks);
+    profile::set_enabled(startup_info.profile_hooks);
+
+    // Indexed before taking the lock, since the hooks need it to resolve the paths of the archives being read.
+    let (contents, errors) = {
+        let _bypass = hooks::Bypass::enter();
+        let cache = ContentCache::new(&startup_info.cache);
+        Contents::index(startup_info.mappings.iter(), cache)
+    };
+    if !errors.is_empty() {
+        let mut conn = get_conn();
+        for err in errors {
+            log_error!(conn, "{}", err).ok();
+        }
+    }
+    *CONTENTS.lock().unwrap() = contents;
+    *MAPPINGS.lock().unwrap() = startup_info.mappings;
+    *SUBPROCESS_RULES.lock().unwrap() = startup_info.subprocess_rules;
+    *PANIC_POLICY.lock().unwrap() = startup_info.panic_policy;
+    DEPTH.store(startup_info.depth, Ordering::SeqCst);
+}
+
+/// Carry out the commands sent by asbestos.
+///
+/// If the connection is lost, the hooks keep running with the mappings they already have, and messages are kept by
+/// the `Link` until an instance of asbestos injects the payload into this process again, which makes it connect anew.
+fn control(mut reader: Connection<BufReader<PipeClient>, io::Sink>) {
+    loop {
+        match reader.read_message() {
+            Ok(Message::Detach) => {
+                detach();
+                return;
+            }
+            Ok(_) => {}
+            Err(_) => {
+                get_conn().disconnect();
+                reader = reconnect();
+            }
+        }
+    }
+}
+
+/// Wait for a new instance of asbestos, and let it know what it missed.
+fn reconnect() -> Connection<BufReader<PipeClient>, io::Sink> {
+    loop {
+        thread::sleep(RECONNECT_INTERVAL);
+        // Opening the pipe would otherwise go through the file hooks.
+        let connected = {
+            let _bypass = hooks::Bypass::enter();
+            connect(RECONNECT_INTERVAL.as_millis() as u32)
+        };
+        let mut conn = match connected {
+            Ok(conn) => conn,
+            Err(_) => continue,
+        };
+        let startup_info = match conn.read_message() {
+            Ok(Message::StartupInfo(si)) => si,
+            _ => continue,
+        };
+        apply_startup_info(startup_info);
+
+        let (reader, writer) = conn.split();
+        let mut conn = get_conn();
+        conn.connect(writer);
+        send_process_info(&mut conn);
+        conn.write_message(Message::Initialized).ok();
+        return reader;
+    }
+}
+
+/// Disable every hook, let asbestos know that we're leaving, and unload the payload.
+///
+/// Unloading the payload while a thread is still inside one of its detours would crash the process, so if some don't
+/// leave them in time the payload stays loaded, with its hooks disabled, and this returns.
+fn detach() {
+    let failed = unsafe { hooks::unhook_all() };
+    let calls_finished = hooks::wait_for_active_calls(DETACH_TIMEOUT);
+    profile::stop_reporting();
+
+    let mut conn = get_conn();
+    for (name, err) in failed {
+        log_error!(conn, "Could not disable {}'s hook: {}", name, err).ok();
+    }
+    if !calls_finished {
+        log_warn!(
+            conn,
+            "Some threads are still inside hooks, so the payload stays loaded with its hooks disabled"
+        )
+        .ok();
+    }
+    profile::report(&mut conn);
+    conn.write_message(Message::ProcessDetach).ok();
+    conn.disconnect();
+    mem::drop(conn);
+
+    if calls_finished {
+        unsafe { FreeLibraryAndExitThread(MODULE.load(Ordering::SeqCst) as HMODULE, 0) };
+        unreachable!("FreeLibraryAndExitThread returned")
+    }
+}
+
 fn resume_main_thread(tid: u32) {
     let tid = {
         if tid == 0 {
@@ -168,27 +328,90 @@ fn resume_main_thread(tid: u32) {
             unsafe { CloseHandle(handle) };
         }
 
+/// Report panics to asbestos, and then follow `PANIC_POLICY`.
+///
+/// Panics inside detours are caught by them, so that with `PanicPolicy::DisableHooks` the panicking call carries on
+/// as if it had never been hooked. Detours hold the connection for as long as they run, so a panic inside one is only
+/// reported by `report_pending_panic` once the detour has caught it, and `PanicPolicy::Terminate` waits until then.
 fn install_panic_hook() {
     let default_panic_hook = panic::take_hook();
     panic::set_hook(Box::new(move |panic_info| {
-        static FIRST_PANIC: AtomicBool = AtomicBool::new(true);
-        if FIRST_PANIC.swap(false, Ordering::SeqCst) {
-            env::set_var("RUST_BACKTRACE", "full");
-            if let Ok(_) = ensure_console_window() {
+        let policy = PANIC_POLICY
+            .lock()
+            .map(|policy| *policy)
+            .unwrap_or_default();
+        let message = match panic_info.payload().downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match panic_info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "Box<dyn Any>".to_owned(),
+            },
+        };
+        let report = PanicReport {
+            message,
+            location: panic_info.location().map(ToString::to_string),
+            thread: thread::current().name().map(str::to_owned),
+            backtrace: format!("{:?}", Backtrace::new()),
+            policy,
+        };
+        let in_detour = hooks::ActiveCall::inside();
+        if in_detour {
+            PENDING_PANIC_REPORT.with(|pending| *pending.borrow_mut() = Some(report));
+        } else {
+            report_panic(report);
+        }
+
+        match policy {
+            PanicPolicy::DisableHooks => {
+                unsafe { hooks::unhook_all() };
+            }
+            PanicPolicy::Terminate if in_detour => {}
+            PanicPolicy::Terminate => process::abort(),
+            // Inside a detour, the report is never sent, but the console window shows the panic.
+            PanicPolicy::Freeze => {
+                env::set_var("RUST_BACKTRACE", "full");
+                ensure_console_window().ok();
                 default_panic_hook(panic_info);
                 loop {
                     thread::sleep(Duration::from_millis(100));
                 }
             }
-        } else {
-            default_panic_hook(panic_info);
-            loop {
-                thread::sleep(Duration::from_millis(100));
-            }
         }
     }));
 }
 
+/// Send the report of a panic inside a detour, if there was one, and terminate the process if that's the policy.
+///
+/// Called by detours after catching a panic, when they no longer hold the connection.
+pub(crate) fn report_pending_panic() {
+    if let Some(report) = PENDING_PANIC_REPORT.with(|pending| pending.borrow_mut().take()) {
+        let terminate = report.policy == PanicPolicy::Terminate;
+        report_panic(report);
+        if terminate {
+            process::abort();
+        }
+    }
+}
+
+/// Send a panic report, unless the connection doesn't become available in time. Some other thread could be stuck
+/// while holding it.
+fn report_panic(report: PanicReport) {
+    let start = Instant::now();
+    while start.elapsed() < PANIC_REPORT_TIMEOUT {
+        match CONN.try_lock() {
+            Ok(mut conn) => {
+                conn.write_message(report).ok();
+                return;
+            }
+            Err(TryLockError::Poisoned(err)) => {
+                err.into_inner().write_message(report).ok();
+                return;
+            }
+            Err(TryLockError::WouldBlock) => thread::sleep(Duration::from_millis(1)),
+        }
+    }
+}
+
 fn ensure_console_window() -> Result<(), ()> {
     let handle = {
         let handle = unsafe { GetConsoleWindow() };
@@ -0,0 +1,173 @@
+//! The payload's end of its connection to asbestos, which outlives the connection itself.
+//!
+//! If asbestos goes away, the hooks keep working: messages are kept in a backlog and warnings, errors and panics are
+//! also written to a fallback file, until `control` connects to a new instance of asbestos and the backlog is sent to
+//! it.
+
+use std::{
+    collections::VecDeque,
+    env,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    mem,
+    path::PathBuf,
+    process,
+};
+
+use asbestos_shared::{
+    named_pipe::PipeClient,
+    protocol::{Connection, LogLevel, LogMessage, Message, ProtocolError},
+};
+
+use crate::hooks::Bypass;
+
+/// The number of messages kept while disconnected. The oldest messages are dropped first.
+const BACKLOG_LIMIT: usize = 10_000;
+
+/// The size in bytes past which nothing more is written to the fallback file.
+const FALLBACK_LIMIT: u64 = 1024 * 1024;
+
+/// Public only because it appears in the signature of `vfs::_resolve_path`.
+pub struct Link {
+    conn: Option<Connection<io::Empty, PipeClient>>,
+    backlog: VecDeque<Message>,
+    /// The number of messages dropped from the backlog since the last connection was lost.
+    dropped: usize,
+    /// Opened once the first warning, error or panic report arrives while disconnected.
+    fallback: Option<File>,
+    /// The size of the fallback file.
+    fallback_len: u64,
+}
+
+impl Link {
+    pub(crate) fn new() -> Self {
+        Self {
+            conn: None,
+            backlog: VecDeque::new(),
+            dropped: 0,
+            fallback: None,
+            fallback_len: 0,
+        }
+    }
+
+    pub(crate) fn connected(&self) -> bool {
+        self.conn.is_some()
+    }
+
+    /// Start sending messages over `conn`, beginning with the ones kept while disconnected.
+    pub(crate) fn connect(&mut self, conn: Connection<io::Empty, PipeClient>) {
+        self.conn = Some(conn);
+        if self.dropped != 0 {
+            let dropped = self.dropped;
+            self.dropped = 0;
+            self.write_message(log_message(format!(
+                "{} messages were dropped while disconnected",
+                dropped
+            )))
+            .ok();
+        }
+        for message in mem::take(&mut self.backlog) {
+            self.write_message(message).ok();
+        }
+    }
+
+    /// Stop using the current connection, if any. Messages are kept from now on.
+    pub(crate) fn disconnect(&mut self) {
+        self.conn = None;
+    }
+
+    /// Send a message to asbestos, or keep it until asbestos reconnects.
+    ///
+    /// Never fails. The `Result` mirrors `Connection::write_message` so that the `log_*` macros work with a `Link`.
+    pub(crate) fn write_message<T: Into<Message>>(
+        &mut self,
+        value: T,
+    ) -> Result<(), ProtocolError> {
+        let message = value.into();
+        if let Some(conn) = self.conn.as_mut() {
+            if conn.write_message_ref(&message).is_ok() {
+                return Ok(());
+            }
+            self.conn = None;
+        }
+
+        self.write_fallback(&message);
+        if self.backlog.len() == BACKLOG_LIMIT {
+            self.backlog.pop_front();
+            self.dropped += 1;
+        }
+        self.backlog.push_back(message);
+        Ok(())
+    }
+
+    /// Write warnings, errors and panic reports to the fallback file, so that they can be read even if asbestos never
+    /// reconnects. Anything less severe is only kept in the backlog.
+    fn write_fallback(&mut self, message: &Message) {
+        if self.fallback_len >= FALLBACK_LIMIT {
+            return;
+        }
+        let line = match message {
+            Message::LogMessage(log_message)
+                if matches!(log_message.level, LogLevel::Error | LogLevel::Warn) =>
+            {
+                format!(
+                    "[{:?}] [{}:{}] {}",
+                    log_message.level,
+                    log_message
+                        .module_path
+                        .trim_start_matches("asbestos_payload::"),
+                    log_message.line,
+                    log_message.message
+                )
+            }
+            Message::Panic(report) => format!(
+                "[Panic] [{}] {}\n{}",
+                report.location.as_deref().unwrap_or("unknown location"),
+                report.message,
+                report.backtrace
+            ),
+            _ => return,
+        };
+
+        if self.fallback.is_none() {
+            let _bypass = Bypass::enter();
+            self.fallback = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(fallback_path())
+                .ok();
+            self.fallback_len = self
+                .fallback
+                .as_ref()
+                .and_then(|fallback| fallback.metadata().ok())
+                .map_or(0, |metadata| metadata.len());
+        }
+        if let Some(fallback) = self.fallback.as_mut() {
+            if writeln!(fallback, "{}", line).is_ok() {
+                self.fallback_len += line.len() as u64 + 1;
+                if self.fallback_len >= FALLBACK_LIMIT {
+                    writeln!(
+                        fallback,
+                        "[Warn] This file is full, nothing more is written to it"
+                    )
+                    .ok();
+                }
+            }
+        }
+    }
+}
+
+/// Where log messages and panic reports are written while no instance of asbestos is connected.
+fn fallback_path() -> PathBuf {
+    env::temp_dir().join(format!("asbestos_payload-{}.log", process::id()))
+}
+
+fn log_message(message: String) -> LogMessage {
+    LogMessage {
+        level: LogLevel::Warn,
+        module_path: module_path!().into(),
+        file: file!().into(),
+        line: line!(),
+        message,
+    }
+}
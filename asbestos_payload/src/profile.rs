@@ -0,0 +1,142 @@
+//! Measurements of the time spent inside our hooks.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use lazy_static::lazy_static;
+
+use asbestos_shared::protocol::{HookProfile, HookTimings};
+
+use crate::Link;
+
+/// How often the collected timings are sent to asbestos.
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STOP_REPORTING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref TIMINGS: Mutex<HashMap<&'static str, HookTimings>> = Mutex::new(HashMap::new());
+    static ref REPORTER: Mutex<Option<JoinHandle<()>>> = Mutex::new(None);
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Phase {
+    Total,
+    Resolve,
+    Call,
+}
+
+/// Start or stop measuring hooks, as asked by the instance of asbestos which is connected.
+pub(crate) fn set_enabled(enabled: bool) {
+    if enabled {
+        enable();
+    } else {
+        disable();
+    }
+}
+
+/// Start measuring hooks and periodically report the results. Does nothing if they are already being measured.
+fn enable() {
+    if ENABLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    STOP_REPORTING.store(false, Ordering::SeqCst);
+
+    let reporter = thread::spawn(|| loop {
+        thread::park_timeout(REPORT_INTERVAL);
+        if STOP_REPORTING.load(Ordering::SeqCst) {
+            break;
+        }
+        report(&mut crate::get_conn());
+    });
+    if let Ok(mut slot) = REPORTER.lock() {
+        *slot = Some(reporter);
+    }
+}
+
+/// Stop the periodic reports, and wait for the reporting thread to exit so that the payload can be unloaded.
+pub(crate) fn stop_reporting() {
+    STOP_REPORTING.store(true, Ordering::SeqCst);
+    let reporter = REPORTER.lock().ok().and_then(|mut slot| slot.take());
+    if let Some(reporter) = reporter {
+        reporter.thread().unpark();
+        reporter.join().ok();
+    }
+}
+
+/// Stop measuring hooks, and forget the timings collected so far. Does nothing if they aren't being measured.
+fn disable() {
+    if !ENABLED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    stop_reporting();
+    if let Ok(mut timings) = TIMINGS.lock() {
+        timings.clear();
+    }
+}
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Send the timings collected so far.
+pub(crate) fn report(conn: &mut Link) {
+    if !enabled() {
+        return;
+    }
+
+    let hooks = match TIMINGS.lock() {
+        Ok(timings) => timings.values().cloned().collect(),
+        Err(_) => return,
+    };
+    conn.write_message(HookProfile { hooks }).ok();
+}
+
+/// Run `f` and, if profiling is enabled, record how long it took.
+pub(crate) fn timed<T, F: FnOnce() -> T>(name: &'static str, phase: Phase, f: F) -> T {
+    let _timer = Timer::start(name, phase);
+    f()
+}
+
+/// Records the time between its creation and its destruction.
+pub(crate) struct Timer {
+    name: &'static str,
+    phase: Phase,
+    start: Option<Instant>,
+}
+
+impl Timer {
+    pub(crate) fn start(name: &'static str, phase: Phase) -> Self {
+        Self {
+            name,
+            phase,
+            start: if enabled() { Some(Instant::now()) } else { None },
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            let ns = start.elapsed().as_nanos() as u64;
+            if let Ok(mut timings) = TIMINGS.lock() {
+                let timings = timings
+                    .entry(self.name)
+                    .or_insert_with(|| HookTimings::new(self.name));
+                match self.phase {
+                    Phase::Total => timings.total.record(ns),
+                    Phase::Resolve => timings.resolve.record(ns),
+                    Phase::Call => timings.call.record(ns),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Timestamps around calls to the real functions behind our hooks.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use winapi::um::processthreadsapi::GetCurrentThreadId;
+
+use asbestos_shared::protocol::TraceEvent;
+
+use crate::Link;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// A call to a hooked function which is currently in progress.
+///
+/// A `Span` is only created if tracing has been enabled through `StartupInfo::trace_hooks`.
+pub(crate) struct Span {
+    name: &'static str,
+    detail: String,
+    begin: u64,
+}
+
+impl Span {
+    pub(crate) fn begin<F: FnOnce() -> String>(name: &'static str, detail: F) -> Option<Self> {
+        if ENABLED.load(Ordering::Relaxed) {
+            Some(Self {
+                name,
+                detail: detail(),
+                begin: now(),
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Mark the end of the call.
+    pub(crate) fn finish(self) -> TraceEvent {
+        TraceEvent {
+            name: self.name.into(),
+            tid: unsafe { GetCurrentThreadId() },
+            begin: self.begin,
+            end: now(),
+            detail: self.detail,
+        }
+    }
+}
+
+/// Run `f` and, if tracing is enabled, send a `TraceEvent` covering its duration.
+pub(crate) fn traced<T, D, F>(conn: &mut Link, name: &'static str, detail: D, f: F) -> T
+where
+    D: FnOnce() -> String,
+    F: FnOnce() -> T,
+{
+    let span = Span::begin(name, detail);
+    let res = f();
+    if let Some(span) = span {
+        conn.write_message(span.finish()).ok();
+    }
+    res
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or(0)
+}
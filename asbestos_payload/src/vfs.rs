@@ -8,34 +8,51 @@ use std::{
 };
 
 use asbestos_shared::{
-    log_trace,
-    protocol::{MappingFrom, MappingKind, MappingTo, Mappings},
+    contents::Contents,
+    log_error, log_trace,
+    protocol::Mappings,
+    vfs::{map_path, InvalidMapping},
 };
 
-use super::{PipeConnection, MAPPINGS};
+use super::{hooks::Bypass, Link, CONTENTS, MAPPINGS};
 
 /// Turn a 'virtual' path into a real one, as determined by `MAPPINGS`.
 ///
 /// `path` should be a canonical path. This is in part because `Path`'s `PartialEq` does a component-wise comparison
 ///  and because the path resolving algorithm shouldn't have to deal with relative path components.
 pub(crate) fn resolve_path<'a>(
-    conn: Option<&mut PipeConnection>,
+    conn: Option<&mut Link>,
     path: &'a Path,
 ) -> Result<Cow<'a, Path>, PathResolveError> {
     let mappings = MAPPINGS.lock().unwrap();
+    let contents = CONTENTS.lock().unwrap();
 
-    let path = _resolve_path(conn, path, &mappings);
+    let path = _resolve_path(conn, path, &mappings, &contents, true);
 
     path
 }
 
+/// Like `resolve_path`, except that the contents of archive members may be missing from the real path. This is enough
+/// for querying attributes, and spares extracting anything.
+pub(crate) fn resolve_outline_path<'a>(
+    conn: Option<&mut Link>,
+    path: &'a Path,
+) -> Result<Cow<'a, Path>, PathResolveError> {
+    let mappings = MAPPINGS.lock().unwrap();
+    let contents = CONTENTS.lock().unwrap();
+
+    _resolve_path(conn, path, &mappings, &contents, false)
+}
+
 /// The *real* path resolution function.
 ///
 /// This is separtated out for the sake of testability.
 pub fn _resolve_path<'a>(
-    conn: Option<&mut PipeConnection>,
+    mut conn: Option<&mut Link>,
     path: &'a Path,
     mappings: &Mappings,
+    contents: &Contents,
+    extract: bool,
 ) -> Result<Cow<'a, Path>, PathResolveError> {
     let mut is_nt_wierd = false;
     let mut is_simplified = false;
@@ -64,82 +81,57 @@ pub fn _resolve_path<'a>(
         }
     };
 
-    let mut current_path = Cow::Borrowed(simplified_path);
-
     let mut trace = String::new();
 
     if conn.is_some() {
         write!(
             trace,
             r#"Determining redirect for "{}""#,
-            current_path.display()
+            simplified_path.display()
         )
         .ok();
     }
 
-    for mapping in mappings.iter() {
-        match mapping.kind {
-            MappingKind::Redirect => match (&mapping.from, &mapping.to) {
-                (MappingFrom::File(from), MappingTo::File(to)) => {
-                    if &current_path == from {
-                        current_path = to.to_owned().into();
-                    }
-                }
-                (MappingFrom::File(from), MappingTo::Folder(to)) => {
-                    if current_path == *from {
-                        if let Some(name) = current_path.file_name() {
-                            current_path = to.join(name).into();
-                        } else {
-                            return Err(PathResolveError::InvalidMapping);
-                        }
-                    }
-                }
-                (MappingFrom::Folder(from), MappingTo::Folder(to)) => {
-                    if current_path.ancestors().any(|anc| anc == from) {
-                        let relative = current_path.strip_prefix(&from).unwrap();
-                        current_path = to.join(relative).into();
-                    }
-                }
-                (MappingFrom::Folder(_), MappingTo::File(_)) => {
-                    return Err(PathResolveError::InvalidMapping);
-                }
-            },
-            MappingKind::Mount => match (&mapping.from, &mapping.to) {
-                (MappingFrom::File(from), MappingTo::Folder(to)) => {
-                    if current_path.file_name() == from.file_name()
-                        && current_path.parent() == Some(to)
-                    {
-                        current_path = from.to_owned().into();
-                    }
-                }
-                (MappingFrom::Folder(from), MappingTo::Folder(to)) => {
-                    if current_path.ancestors().any(|anc| anc == to) {
-                        let relative = current_path.strip_prefix(&to).unwrap();
-                        current_path = from.join(relative).into();
-                    }
-                }
-                (MappingFrom::File(_), MappingTo::File(_))
-                | (MappingFrom::Folder(_), MappingTo::File(_)) => {
-                    return Err(PathResolveError::InvalidMapping);
-                }
-            },
-        }
-
+    let current_path = map_path(
+        Cow::Borrowed(simplified_path),
+        mappings,
         if conn.is_some() {
-            write!(
-                trace,
-                r#"{}current_path = "{}""#,
-                "\n",
-                current_path.display()
-            )
-            .ok();
-        }
-    }
+            Some(&mut trace)
+        } else {
+            None
+        },
+    )?;
 
-    if let Some(conn) = conn {
+    if let Some(conn) = conn.as_deref_mut() {
         log_trace!(conn, "{}", trace).ok();
     }
 
+    // Members of archives are extracted and patches applied while the hook calling this holds the connection, so the
+    // file system calls this makes must not be hooked.
+    let current_path = {
+        let _bypass = Bypass::enter();
+        if extract {
+            match contents.real_path(simplified_path, current_path.clone(), mappings) {
+                Ok(real) => real,
+                // The file the mappings lead to is still the right one, even if its patches don't fit it.
+                Err(err) => {
+                    if let Some(conn) = conn {
+                        log_error!(
+                            conn,
+                            "Could not patch or merge {}, opening it as it is: {}",
+                            simplified_path.display(),
+                            err
+                        )
+                        .ok();
+                    }
+                    contents.unpatched_path(simplified_path, current_path)?
+                }
+            }
+        } else {
+            contents.outline_path(simplified_path, current_path, mappings)?
+        }
+    };
+
     if is_nt_wierd {
         let mut out = OsString::from(r"\??\");
         out.push(current_path.as_ref());
@@ -176,6 +168,12 @@ impl From<detour::Error> for PathResolveError {
     }
 }
 
+impl From<InvalidMapping> for PathResolveError {
+    fn from(_: InvalidMapping) -> Self {
+        Self::InvalidMapping
+    }
+}
+
 impl From<io::Error> for PathResolveError {
     fn from(from: io::Error) -> Self {
         Self::Io(from)
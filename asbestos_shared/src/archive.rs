@@ -0,0 +1,439 @@
+//! Zip archives used as the source of a mapping.
+//!
+//! `map_path` makes the members of an archive appear below the path of the archive itself, as if it was a folder:
+//! mounting `mods/a.zip` with the prefix `Data` turns `<to>/b.dds` into `mods/a.zip/Data/b.dds`. Nothing exists at
+//! such a path, so `Archives` gives it a real counterpart by extracting the member into the content cache the first
+//! time its contents are needed.
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    fs::{self, File},
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use filetime::FileTime;
+use zip::ZipArchive;
+
+use crate::{
+    cache::ContentCache,
+    protocol::{Mapping, MappingFrom},
+};
+
+/// The archives used by a set of mappings, indexed once so that finding a member doesn't mean reading the archive.
+///
+/// Members are looked up by their exact path inside the archive, so lookups are case-sensitive even on Windows:
+/// `mods/a.zip/data/a.txt` doesn't find the member `Data/a.txt`. The same goes for the path of the archive itself.
+#[derive(Default)]
+pub struct Archives {
+    archives: HashMap<PathBuf, Archive>,
+    cache: Arc<ContentCache>,
+}
+
+impl Archives {
+    /// Index every archive used by `mappings`, which extracts members into `cache`. Archives which can't be read are
+    /// left out, which makes their members appear to be missing.
+    pub fn index<'a>(
+        mappings: impl IntoIterator<Item = &'a Mapping>,
+        cache: Arc<ContentCache>,
+    ) -> (Self, Vec<ArchiveError>) {
+        let mut archives = HashMap::new();
+        let mut errors = Vec::new();
+        for mapping in mappings {
+            if let MappingFrom::Archive { path, .. } = &mapping.from {
+                if archives.contains_key(path) {
+                    continue;
+                }
+                match Archive::open(path, cache.dir()) {
+                    Ok(archive) => {
+                        archives.insert(path.clone(), archive);
+                    }
+                    Err(source) => errors.push(ArchiveError {
+                        path: path.clone(),
+                        source,
+                    }),
+                }
+            }
+        }
+        (Self { archives, cache }, errors)
+    }
+
+    /// A real path with the contents of `path`, if it lies inside one of the archives. Files are extracted if they
+    /// haven't been already.
+    pub fn extract(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        match self.find(path) {
+            Some((archive, member)) => match archive.members.get(member) {
+                Some(Member::File { index, crc32, .. }) => archive
+                    .extract(&self.cache, member, *index, *crc32)
+                    .map(Some),
+                _ => archive
+                    .outline(&self.cache)
+                    .map(|tree| Some(tree.join(member))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// A real path with the same metadata as `path`, if it lies inside one of the archives. Nothing is extracted, so
+    /// the contents of files are missing.
+    pub fn outline(&self, path: &Path) -> io::Result<Option<PathBuf>> {
+        match self.find(path) {
+            Some((archive, member)) => archive
+                .outline(&self.cache)
+                .map(|tree| Some(tree.join(member))),
+            None => Ok(None),
+        }
+    }
+
+    /// The paths of the files below `path`, relative to it, if it lies inside one of the archives.
+    pub fn files(&self, path: &Path) -> Vec<PathBuf> {
+        let (archive, folder) = match self.find(path) {
+            Some(found) => found,
+            None => return Vec::new(),
+        };
+        archive
+            .members
+            .iter()
+            .filter(|(_, member)| matches!(member, Member::File { .. }))
+            .filter_map(|(name, _)| name.strip_prefix(folder).ok())
+            .filter(|relative| relative != &Path::new(""))
+            .map(Path::to_owned)
+            .collect()
+    }
+
+    fn find<'a>(&self, path: &'a Path) -> Option<(&Archive, &'a Path)> {
+        path.ancestors().find_map(|ancestor| {
+            self.archives
+                .get(ancestor)
+                .map(|archive| (archive, path.strip_prefix(ancestor).unwrap()))
+        })
+    }
+}
+
+struct Archive {
+    /// Identifies this version of the archive.
+    key: String,
+    /// Where the outline of this version of the archive is created.
+    outline_dir: PathBuf,
+    /// Every member by its path inside the archive, including folders which only exist because of the members in them.
+    members: BTreeMap<PathBuf, Member>,
+    zip: Mutex<ZipArchive<File>>,
+    /// Whether this process has used the outline of the archive yet.
+    outline_used: Mutex<bool>,
+}
+
+enum Member {
+    Folder,
+    File { index: usize, size: u64, crc32: u32 },
+}
+
+impl Archive {
+    fn open(path: &Path, cache_dir: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mut zip = ZipArchive::new(File::open(path)?)?;
+
+        let mut members = BTreeMap::new();
+        members.insert(PathBuf::new(), Member::Folder);
+        for index in 0..zip.len() {
+            let file = zip.by_index_raw(index)?;
+            // Members which would end up outside of the archive's folder are left out.
+            let name = match file.enclosed_name() {
+                Some(name) => name.to_owned(),
+                None => continue,
+            };
+            for folder in name.ancestors().skip(1) {
+                members.insert(folder.to_owned(), Member::Folder);
+            }
+            let member = if file.is_dir() {
+                Member::Folder
+            } else {
+                Member::File {
+                    index,
+                    size: file.size(),
+                    crc32: file.crc32(),
+                }
+            };
+            members.insert(name, member);
+        }
+
+        // A new version of the archive gets a new key, instead of being served stale members.
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        metadata.modified().ok().hash(&mut hasher);
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let key = format!("{}-{:016x}", stem, hasher.finish());
+
+        Ok(Self {
+            outline_dir: cache_dir.join("outlines").join(&key),
+            key,
+            members,
+            zip: Mutex::new(zip),
+            outline_used: Mutex::new(false),
+        })
+    }
+
+    /// Extract a file into the cache, unless it's there already.
+    fn extract(
+        &self,
+        cache: &ContentCache,
+        member: &Path,
+        index: usize,
+        crc32: u32,
+    ) -> io::Result<PathBuf> {
+        let key = format!("archive\0{}\0{}\0{:08x}", self.key, member.display(), crc32);
+        cache.get_or_insert(&key, |out| {
+            let mut zip = self.zip.lock().unwrap();
+            let mut file = zip.by_index(index)?;
+            io::copy(&mut file, out)?;
+            Ok(())
+        })
+    }
+
+    /// Create a folder tree mirroring the archive, where every file has the right length but no contents, and return
+    /// its path. This is what directory listings and metadata queries are served from.
+    ///
+    /// The outline lives in the content cache, and is evicted along with its contents. The modification time of its
+    /// marker is its last use.
+    fn outline(&self, cache: &ContentCache) -> io::Result<PathBuf> {
+        let tree = self.outline_dir.join("tree");
+        let marker = self.outline_dir.join(OUTLINE_MARKER);
+        let mut used = self.outline_used.lock().unwrap();
+        if marker.exists() {
+            if !*used {
+                filetime::set_file_mtime(&marker, FileTime::now()).ok();
+            }
+        } else {
+            // Parents come before their members, since paths are ordered component-wise.
+            for (member, kind) in &self.members {
+                let path = tree.join(member);
+                match kind {
+                    Member::Folder => fs::create_dir_all(&path)?,
+                    Member::File { size, .. } => {
+                        let file = File::create(&path)?;
+                        // Only the length is set, which takes next to no time, and no space in a sparse file. File
+                        // systems without sparse files, like FAT, allocate the space regardless.
+                        #[cfg(windows)]
+                        set_sparse(&file).ok();
+                        file.set_len(*size)?;
+                    }
+                }
+            }
+            File::create(marker)?;
+            cache.added(&self.outline_dir).ok();
+        }
+        *used = true;
+        Ok(tree)
+    }
+}
+
+/// The file which marks an outline as complete, in the folder of the outline.
+pub(crate) const OUTLINE_MARKER: &str = "tree.complete";
+
+/// Mark a file as sparse, so that NTFS doesn't allocate the space which setting its length would otherwise take.
+#[cfg(windows)]
+fn set_sparse(file: &File) -> io::Result<()> {
+    use std::{os::windows::io::AsRawHandle, ptr};
+    use winapi::um::{ioapiset::DeviceIoControl, winioctl::FSCTL_SET_SPARSE};
+
+    let mut returned = 0;
+    let succeeded = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as _,
+            FSCTL_SET_SPARSE,
+            ptr::null_mut(),
+            0,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+    if succeeded == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+/// An archive couldn't be indexed.
+#[derive(Debug)]
+pub struct ArchiveError {
+    pub path: PathBuf,
+    pub source: io::Error,
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Could not read archive {}: {}",
+            self.path.display(),
+            self.source
+        )
+    }
+}
+
+impl Error for ArchiveError {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env,
+        io::{Cursor, Write},
+        process,
+    };
+
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+    use crate::{
+        cache::CacheConfig,
+        protocol::{MappingKind, MappingTo},
+    };
+
+    /// A folder with an archive of a mod, and a cache for it.
+    fn setup(name: &str) -> (PathBuf, Archives) {
+        let dir = env::temp_dir().join(format!("asbestos-archive-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("Data/a.txt", FileOptions::default())
+            .unwrap();
+        zip.write_all(b"the contents of a").unwrap();
+        // The folders of this member are only implied by its path.
+        zip.start_file("Data/sub/b.txt", FileOptions::default())
+            .unwrap();
+        zip.write_all(b"b").unwrap();
+        zip.add_directory("Data/empty", FileOptions::default())
+            .unwrap();
+        zip.start_file("../escaped.txt", FileOptions::default())
+            .unwrap();
+        fs::write(dir.join("mod.zip"), zip.finish().unwrap().into_inner()).unwrap();
+        let archives = index(&dir);
+        (dir, archives)
+    }
+
+    /// Index the archive in `dir` anew, the way another process would.
+    fn index(dir: &Path) -> Archives {
+        let mapping = Mapping {
+            kind: MappingKind::Mount,
+            from: MappingFrom::Archive {
+                path: dir.join("mod.zip"),
+                prefix: None,
+            },
+            to: MappingTo::Folder(dir.join("game")),
+        };
+        let cache = ContentCache::new(&CacheConfig {
+            dir: Some(dir.join("cache")),
+            max_size: None,
+        });
+        let (archives, errors) = Archives::index(&[mapping], Arc::new(cache));
+        assert!(errors.is_empty());
+        archives
+    }
+
+    #[test]
+    fn members_are_indexed() {
+        let (dir, archives) = setup("index");
+        let archive = &archives.archives[&dir.join("mod.zip")];
+        let members: Vec<_> = archive
+            .members
+            .iter()
+            .map(|(path, member)| (path.as_path(), matches!(member, Member::File { .. })))
+            .collect();
+        let expected = [
+            ("", false),
+            ("Data", false),
+            ("Data/a.txt", true),
+            ("Data/empty", false),
+            ("Data/sub", false),
+            ("Data/sub/b.txt", true),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|&(path, file)| (Path::new(path), file))
+            .collect();
+        assert_eq!(members, expected);
+
+        let mut files = archives.files(&dir.join("mod.zip/Data"));
+        files.sort();
+        assert_eq!(files, [Path::new("a.txt"), Path::new("sub/b.txt")]);
+        // Lookups are case-sensitive, like the paths of the members.
+        assert!(archives.files(&dir.join("mod.zip/data")).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn files_are_extracted_into_the_cache() {
+        let (dir, archives) = setup("extract");
+        let extracted = archives
+            .extract(&dir.join("mod.zip/Data/a.txt"))
+            .unwrap()
+            .unwrap();
+        assert!(extracted.starts_with(dir.join("cache")));
+        assert_eq!(fs::read(&extracted).unwrap(), b"the contents of a");
+
+        let again = archives
+            .extract(&dir.join("mod.zip/Data/a.txt"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(again, extracted);
+        // Paths outside of every archive are left alone.
+        assert_eq!(archives.extract(&dir.join("other/a.txt")).unwrap(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn outline_mirrors_the_archive() {
+        let (dir, archives) = setup("outline");
+        let a = archives
+            .outline(&dir.join("mod.zip/Data/a.txt"))
+            .unwrap()
+            .unwrap();
+        let tree = a.ancestors().nth(2).unwrap().to_owned();
+        assert!(tree.join("Data/empty").is_dir());
+        assert_eq!(fs::read_dir(&tree).unwrap().count(), 1);
+
+        // Every file has the length of its member, and none of its contents.
+        let metadata = fs::metadata(&a).unwrap();
+        assert_eq!(metadata.len(), 17);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            assert_eq!(metadata.blocks(), 0);
+        }
+        assert_eq!(fs::read(&a).unwrap(), [0; 17]);
+        assert_eq!(fs::metadata(tree.join("Data/sub/b.txt")).unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn outline_is_recreated_without_its_marker() {
+        let (dir, archives) = setup("marker");
+        let b = archives
+            .outline(&dir.join("mod.zip/Data/sub/b.txt"))
+            .unwrap()
+            .unwrap();
+        let outline_dir = b.ancestors().nth(4).unwrap().to_owned();
+        let marker = outline_dir.join(OUTLINE_MARKER);
+        assert!(marker.is_file());
+
+        // A complete outline is used as it is.
+        fs::remove_file(&b).unwrap();
+        index(&dir).outline(&dir.join("mod.zip/Data")).unwrap();
+        assert!(!b.exists());
+
+        // One which was interrupted before it was marked complete is created anew.
+        fs::remove_file(&marker).unwrap();
+        index(&dir).outline(&dir.join("mod.zip/Data")).unwrap();
+        assert!(b.is_file());
+        assert!(marker.is_file());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,457 @@
+//! A cache of file contents which don't exist on disk, such as the members of archives, so that they can be opened like
+//! any other file.
+//!
+//! Contents are stored under the SHA-256 hash of their bytes in `objects`, which lets identical contents share a file.
+//! Each is found through a key describing where it came from, stored under the hash of the key in `keys`. The
+//! modification time of an object is its last use, which decides what is evicted first once the cache grows beyond
+//! its size limit. The outlines of archives in `outlines` are evicted the same way.
+//!
+//! Measuring the cache means going through all of it, so each process only does so once it thinks the cache has
+//! outgrown its limit, going by what it has added since it last measured. What other processes add in the meantime
+//! is only noticed then, which lets the cache overshoot its limit for a while.
+
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::archive::OUTLINE_MARKER;
+
+/// Where the cache is and how large it may grow.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CacheConfig {
+    /// Defaults to `asbestos/cache` in the temporary folder.
+    pub dir: Option<PathBuf>,
+    /// The size in bytes beyond which the least recently used contents are evicted. Unlimited by default.
+    pub max_size: Option<u64>,
+}
+
+pub struct ContentCache {
+    dir: PathBuf,
+    max_size: Option<u64>,
+    /// The objects whose contents have been checked against their names by this process.
+    verified: Mutex<HashSet<String>>,
+    /// The size of the cache when it was last measured, plus what has been added since. `None` until it has been
+    /// measured.
+    size: Mutex<Option<u64>>,
+    /// Tells apart the temporary files of different threads.
+    next_temporary: AtomicUsize,
+}
+
+impl ContentCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            dir: config.dir.clone().unwrap_or_else(default_dir),
+            max_size: config.max_size,
+            verified: Mutex::new(HashSet::new()),
+            size: Mutex::new(None),
+            next_temporary: AtomicUsize::new(0),
+        }
+    }
+
+    /// The folder the cache lives in, for things other than contents which should live there too.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// The path of the contents described by `key`, which are written by `write` if they aren't cached yet.
+    ///
+    /// The contents must only depend on what `key` describes. Cached contents are checked against their hash the
+    /// first time they are reused by a process, and written anew if they have been tampered with.
+    pub fn get_or_insert<F>(&self, key: &str, write: F) -> io::Result<PathBuf>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    {
+        let key_path = self.dir.join("keys").join(hash(key.as_bytes()));
+        if let Ok(object) = fs::read_to_string(&key_path) {
+            let object = object.trim();
+            if let Some(path) = self.reuse(object)? {
+                return Ok(path);
+            }
+        }
+
+        let object = self.insert(write)?;
+        let path = self.dir.join("objects").join(&object);
+        self.write_atomically(&key_path, object.as_bytes())?;
+        self.added(&path).ok();
+        Ok(path)
+    }
+
+    /// The path of an object which is intact, and now the most recently used.
+    fn reuse(&self, object: &str) -> io::Result<Option<PathBuf>> {
+        // Key files are only ever written by the cache, but are read from a folder anyone can write to.
+        if object.len() != 64 || !object.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Ok(None);
+        }
+        let path = self.dir.join("objects").join(object);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        // Other threads may go on using the cache while the object is hashed.
+        let verified = self.verified.lock().unwrap().contains(object);
+        if !verified {
+            if hash_file(&path)? != object {
+                fs::remove_file(&path).ok();
+                return Ok(None);
+            }
+            self.verified.lock().unwrap().insert(object.to_owned());
+        }
+        // An object which is in use can't always be touched, in which case it's simply evicted sooner.
+        filetime::set_file_mtime(&path, FileTime::now()).ok();
+        Ok(Some(path))
+    }
+
+    /// Store the contents produced by `write`, returning the name of their object.
+    fn insert<F>(&self, write: F) -> io::Result<String>
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()>,
+    {
+        let temporary = self.temporary_path()?;
+        let result = (|| {
+            // The file is closed before it's moved, which Windows insists on.
+            let object = {
+                let mut writer = HashingWriter {
+                    file: File::create(&temporary)?,
+                    hasher: Sha256::new(),
+                };
+                write(&mut writer)?;
+                writer.file.sync_all()?;
+                format!("{:x}", writer.hasher.finalize())
+            };
+
+            let path = self.dir.join("objects").join(&object);
+            fs::create_dir_all(self.dir.join("objects"))?;
+            // Another process may have stored the same contents in the meantime, which is just as good.
+            if let Err(err) = fs::rename(&temporary, &path) {
+                if !path.is_file() {
+                    return Err(err);
+                }
+            }
+            self.verified.lock().unwrap().insert(object.clone());
+            Ok(object)
+        })();
+        fs::remove_file(&temporary).ok();
+        result
+    }
+
+    /// Write a whole file, such that other processes either see all of it or none of it.
+    fn write_atomically(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temporary = self.temporary_path()?;
+        let result = fs::write(&temporary, contents).and_then(|()| fs::rename(&temporary, path));
+        if result.is_err() {
+            fs::remove_file(&temporary).ok();
+        }
+        result
+    }
+
+    fn temporary_path(&self) -> io::Result<PathBuf> {
+        let dir = self.dir.join("tmp");
+        fs::create_dir_all(&dir)?;
+        let n = self.next_temporary.fetch_add(1, Ordering::SeqCst);
+        Ok(dir.join(format!("{}-{}", process::id(), n)))
+    }
+
+    /// Account for an object or outline which has just been added, and evict others if the cache may have outgrown
+    /// its size limit.
+    pub(crate) fn added(&self, path: &Path) -> io::Result<()> {
+        let max_size = match self.max_size {
+            Some(max_size) => max_size,
+            None => return Ok(()),
+        };
+
+        // Held while evicting, so that threads don't measure and evict alongside each other.
+        let mut size = self.size.lock().unwrap();
+        if let Some(known) = *size {
+            let metadata = fs::metadata(path)?;
+            let len = if metadata.is_dir() {
+                disk_usage(path)?
+            } else {
+                metadata.len()
+            };
+            if known + len <= max_size {
+                *size = Some(known + len);
+                return Ok(());
+            }
+        }
+        // Measured anew if eviction fails partway.
+        *size = None;
+        // Evicting somewhat more than needed spares doing it again for each of the next few additions.
+        *size = Some(self.evict(path, max_size - max_size / 10)?);
+        Ok(())
+    }
+
+    /// Remove the least recently used objects and outlines until the cache is no larger than `target` again, except
+    /// for `keep`, and return its size. Those which are in use may not be removable, and are skipped. Keys are
+    /// removed along with their objects.
+    fn evict(&self, keep: &Path, target: u64) -> io::Result<u64> {
+        let mut size = 0;
+        let mut keys: HashMap<_, Vec<_>> = HashMap::new();
+        for entry in read_dir_if_exists(&self.dir.join("keys"))? {
+            let entry = entry?;
+            let len = entry.metadata()?.len();
+            size += len;
+            let object = fs::read_to_string(entry.path()).unwrap_or_default();
+            keys.entry(self.dir.join("objects").join(object.trim()))
+                .or_default()
+                .push((entry.path(), len));
+        }
+
+        let mut entries = Vec::new();
+        for entry in read_dir_if_exists(&self.dir.join("objects"))? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            size += metadata.len();
+            entries.push((
+                FileTime::from_last_modification_time(&metadata),
+                metadata.len(),
+                entry.path(),
+            ));
+        }
+        for entry in read_dir_if_exists(&self.dir.join("outlines"))? {
+            let path = entry?.path();
+            // Outlines which are still being created have no marker yet.
+            let used = match fs::metadata(path.join(OUTLINE_MARKER)) {
+                Ok(marker) => FileTime::from_last_modification_time(&marker),
+                Err(_) => continue,
+            };
+            let len = disk_usage(&path)?;
+            size += len;
+            entries.push((used, len, path));
+        }
+        entries.sort();
+
+        // Keys which lead to an object which is gone, or nowhere at all, are of no use.
+        let existing: HashSet<_> = entries.iter().map(|(_, _, path)| path.clone()).collect();
+        keys.retain(|object, object_keys| {
+            if existing.contains(object) {
+                return true;
+            }
+            for (key, len) in object_keys.drain(..) {
+                if fs::remove_file(key).is_ok() {
+                    size -= len;
+                }
+            }
+            false
+        });
+
+        for (_, len, path) in entries {
+            if size <= target {
+                break;
+            }
+            if path != keep && remove_entry(&path).is_ok() {
+                size -= len;
+                for (key, len) in keys.remove(&path).into_iter().flatten() {
+                    if fs::remove_file(key).is_ok() {
+                        size -= len;
+                    }
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// The entries of a folder, of which there are none if it doesn't exist.
+fn read_dir_if_exists(dir: &Path) -> io::Result<impl Iterator<Item = io::Result<fs::DirEntry>>> {
+    match fs::read_dir(dir) {
+        Ok(entries) => Ok(Some(entries).into_iter().flatten()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None.into_iter().flatten()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Remove an object, or an outline. The marker of an outline goes first, so that a partly removed outline is created
+/// anew rather than used.
+fn remove_entry(path: &Path) -> io::Result<()> {
+    if path.is_dir() {
+        fs::remove_file(path.join(OUTLINE_MARKER))?;
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// The space taken up by the files in `dir` and below, which is less than their length for sparse files.
+fn disk_usage(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() {
+            disk_usage(&entry.path())?
+        } else {
+            allocated_len(&entry.path(), &metadata)
+        };
+    }
+    Ok(size)
+}
+
+#[cfg(unix)]
+fn allocated_len(_path: &Path, metadata: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.blocks() * 512
+}
+
+#[cfg(windows)]
+fn allocated_len(path: &Path, metadata: &fs::Metadata) -> u64 {
+    use std::{iter, os::windows::ffi::OsStrExt};
+    use winapi::um::fileapi::{GetCompressedFileSizeW, INVALID_FILE_SIZE};
+
+    let path: Vec<u16> = path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect();
+    let mut high = 0;
+    let low = unsafe { GetCompressedFileSizeW(path.as_ptr(), &mut high) };
+    if low == INVALID_FILE_SIZE && io::Error::last_os_error().raw_os_error() != Some(0) {
+        return metadata.len();
+    }
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn allocated_len(_path: &Path, metadata: &fs::Metadata) -> u64 {
+    metadata.len()
+}
+
+impl Default for ContentCache {
+    fn default() -> Self {
+        Self::new(&CacheConfig::default())
+    }
+}
+
+/// Where the cache lives unless configured otherwise.
+pub fn default_dir() -> PathBuf {
+    env::temp_dir().join("asbestos").join("cache")
+}
+
+fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+pub(crate) fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            read => hasher.update(&buf[..read]),
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hashes everything written to a file.
+struct HashingWriter {
+    file: File,
+    hasher: Sha256,
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.file.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(name: &str, max_size: Option<u64>) -> ContentCache {
+        let dir = env::temp_dir().join(format!("asbestos-cache-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        ContentCache::new(&CacheConfig {
+            dir: Some(dir),
+            max_size,
+        })
+    }
+
+    fn insert(cache: &ContentCache, key: &str, contents: &[u8]) -> PathBuf {
+        cache
+            .get_or_insert(key, |out| out.write_all(contents))
+            .unwrap()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let cache = cache("evict", Some(2500));
+        let a = insert(&cache, "a", &[b'a'; 1000]);
+        let b = insert(&cache, "b", &[b'b'; 1000]);
+        filetime::set_file_mtime(&a, FileTime::from_unix_time(100, 0)).unwrap();
+        filetime::set_file_mtime(&b, FileTime::from_unix_time(200, 0)).unwrap();
+
+        // Reusing `a` makes `b` the least recently used.
+        let reused = cache
+            .get_or_insert("a", |_| panic!("`a` should be cached"))
+            .unwrap();
+        assert_eq!(reused, a);
+        let c = insert(&cache, "c", &[b'c'; 1000]);
+
+        assert!(a.is_file());
+        assert!(!b.is_file());
+        assert!(c.is_file());
+        let keys = fs::read_dir(cache.dir().join("keys")).unwrap().count();
+        assert_eq!(keys, 2);
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn evicts_below_the_limit() {
+        let cache = cache("batch", Some(4000));
+        for (i, name) in ["a", "b", "c", "d", "e", "f", "g", "h"].iter().enumerate() {
+            let path = insert(&cache, name, &[i as u8; 500]);
+            filetime::set_file_mtime(&path, FileTime::from_unix_time(100 * i as i64, 0)).unwrap();
+        }
+
+        // Evicting just enough would have left seven objects, making every later insertion evict again.
+        let objects = fs::read_dir(cache.dir().join("objects")).unwrap().count();
+        assert_eq!(objects, 6);
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+
+    #[test]
+    fn corrupted_objects_are_written_anew() {
+        let path = insert(&cache("corrupted", None), "key", b"contents");
+        fs::write(&path, b"tampered").unwrap();
+
+        // Another process, which hasn't checked the object yet.
+        let cache = ContentCache::new(&CacheConfig {
+            dir: Some(path.parent().unwrap().parent().unwrap().to_owned()),
+            max_size: None,
+        });
+        let mut written = false;
+        let rewritten = cache
+            .get_or_insert("key", |out| {
+                written = true;
+                out.write_all(b"contents")
+            })
+            .unwrap();
+        assert!(written);
+        assert_eq!(fs::read(&rewritten).unwrap(), b"contents");
+        fs::remove_dir_all(cache.dir()).unwrap();
+    }
+}
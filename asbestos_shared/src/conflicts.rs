@@ -0,0 +1,278 @@
+//! Finding the files which more than one mapping provides, of which processes only ever see one.
+//!
+//! Every file in the sources of the mappings is given the virtual path the mapping makes it appear at. Whichever file
+//! `map_path` resolves that path to wins, and the others are reported as overridden. Mounting a folder hides every
+//! file below its target which isn't in it, so the winner doesn't necessarily exist.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cache,
+    contents::Contents,
+    protocol::{Mapping, MappingFrom, MappingKind, MappingTo, Mappings},
+    vfs::map_path,
+};
+
+/// A virtual file which more than one mapping provides, or which is hidden by another mapping.
+#[derive(Debug)]
+pub struct Conflict {
+    /// The virtual path of the file.
+    pub path: PathBuf,
+    /// What the mappings resolve `path` to.
+    pub winner: PathBuf,
+    /// The index of the mapping which provides `winner`, if any does.
+    pub winning_mapping: Option<usize>,
+    /// The files which the other mappings provide at `path`, in the order of the mappings.
+    pub overridden: Vec<Provider>,
+}
+
+/// A file which a mapping makes appear at a virtual path.
+#[derive(Debug)]
+pub struct Provider {
+    /// The index of the mapping in `Mappings`.
+    pub mapping: usize,
+    /// The path of the file, which may lie inside an archive.
+    pub path: PathBuf,
+}
+
+/// Find every file in the sources of `mappings` which is overridden by another. The files in archives are listed with
+/// `contents`. If `ignore_identical` is set, files with the same contents as the winner aren't reported, which means
+/// extracting and hashing every file involved.
+pub fn find_conflicts(
+    mappings: &Mappings,
+    contents: &Contents,
+    ignore_identical: bool,
+) -> io::Result<Vec<Conflict>> {
+    let mut providers: BTreeMap<PathBuf, Vec<Provider>> = BTreeMap::new();
+    for (index, mapping) in mappings.iter().enumerate() {
+        for (path, source) in provided_files(mapping, contents)? {
+            providers.entry(path).or_default().push(Provider {
+                mapping: index,
+                path: source,
+            });
+        }
+    }
+
+    let mut conflicts = Vec::new();
+    for (path, providers) in providers {
+        let winner = map_path(Cow::Borrowed(&path), mappings, None)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?
+            .into_owned();
+        let winning_mapping = providers
+            .iter()
+            .find(|provider| provider.path == winner)
+            .map(|provider| provider.mapping);
+        let mut overridden: Vec<_> = providers
+            .into_iter()
+            .filter(|provider| provider.path != winner)
+            .collect();
+
+        if ignore_identical && !overridden.is_empty() {
+            let winner = contents.source_path(Cow::Borrowed(&winner))?;
+            let mut different = Vec::new();
+            for provider in overridden {
+                let source = contents.source_path(Cow::Borrowed(&provider.path))?;
+                if !identical(&winner, &source)? {
+                    different.push(provider);
+                }
+            }
+            overridden = different;
+        }
+
+        if !overridden.is_empty() {
+            conflicts.push(Conflict {
+                path,
+                winner,
+                winning_mapping,
+                overridden,
+            });
+        }
+    }
+    Ok(conflicts)
+}
+
+/// Whether two files have the same contents. A missing file is different from any other.
+fn identical(a: &Path, b: &Path) -> io::Result<bool> {
+    let (a_len, b_len) = match (fs::metadata(a), fs::metadata(b)) {
+        (Ok(a), Ok(b)) => (a.len(), b.len()),
+        (Err(err), _) | (_, Err(err)) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+        (Err(err), _) | (_, Err(err)) => return Err(err),
+    };
+    Ok(a_len == b_len && cache::hash_file(a)? == cache::hash_file(b)?)
+}
+
+/// The virtual paths of the files a mapping provides, along with the paths of the files themselves.
+fn provided_files(mapping: &Mapping, contents: &Contents) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut files = Vec::new();
+    match (&mapping.kind, &mapping.from, &mapping.to) {
+        (MappingKind::Redirect, MappingFrom::File(from), MappingTo::File(to)) => {
+            files.push((from.clone(), to.clone()));
+        }
+        (MappingKind::Redirect, MappingFrom::File(from), MappingTo::Folder(to)) => {
+            if let Some(name) = from.file_name() {
+                files.push((from.clone(), to.join(name)));
+            }
+        }
+        (MappingKind::Redirect, MappingFrom::Folder(from), MappingTo::Folder(to)) => {
+            for relative in walk_if_exists(to)? {
+                files.push((from.join(&relative), to.join(&relative)));
+            }
+        }
+        (MappingKind::Mount, MappingFrom::File(from), MappingTo::Folder(to)) => {
+            if let Some(name) = from.file_name() {
+                files.push((to.join(name), from.clone()));
+            }
+        }
+        (MappingKind::Mount, MappingFrom::Folder(from), MappingTo::Folder(to)) => {
+            for relative in walk_if_exists(from)? {
+                files.push((to.join(&relative), from.join(&relative)));
+            }
+        }
+        (MappingKind::Mount, MappingFrom::Archive { path, prefix }, MappingTo::Folder(to)) => {
+            let mut source = path.clone();
+            if let Some(prefix) = prefix {
+                source.push(prefix);
+            }
+            for relative in contents.archive_files(&source) {
+                files.push((to.join(&relative), source.join(&relative)));
+            }
+        }
+        // Invalid mappings, which `map_path` reports, and mappings which only change the contents of files.
+        _ => {}
+    }
+    Ok(files)
+}
+
+/// The paths of the files in `dir` and below, relative to it, following symbolic links. Links to a folder which is
+/// being walked already are skipped, since they would be followed around in circles.
+pub fn walk(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    fn walk_into(
+        dir: &Path,
+        relative: &Path,
+        ancestors: &mut Vec<PathBuf>,
+        files: &mut Vec<PathBuf>,
+    ) -> io::Result<()> {
+        let canonical = fs::canonicalize(dir)?;
+        if ancestors.contains(&canonical) {
+            return Ok(());
+        }
+        ancestors.push(canonical);
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let relative = relative.join(entry.file_name());
+            if fs::metadata(&path)?.is_dir() {
+                walk_into(&path, &relative, ancestors, files)?;
+            } else {
+                files.push(relative);
+            }
+        }
+        ancestors.pop();
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk_into(dir, Path::new(""), &mut Vec::new(), &mut files)?;
+    Ok(files)
+}
+
+/// Like `walk`, except that a folder which doesn't exist has no files.
+fn walk_if_exists(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match walk(dir) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound && !dir.exists() => Ok(Vec::new()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// A folder with the given files in it, each containing its own path.
+    fn folder(test: &str, name: &str, files: &[&str]) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("asbestos-conflicts-{}-{}", test, process::id()))
+            .join(name);
+        for file in files {
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join(file), format!("{}/{}", name, file)).unwrap();
+        }
+        dir
+    }
+
+    fn mappings(mappings: Vec<(MappingKind, MappingFrom, MappingTo)>) -> Mappings {
+        Mappings::new(
+            mappings
+                .into_iter()
+                .map(|(kind, from, to)| Mapping { kind, from, to })
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn two_mappings_provide_the_same_file() {
+        let a = folder("same-file", "a", &["shared.txt"]);
+        let b = folder("same-file", "b", &["shared.txt", "b.txt"]);
+        let mount = |from: &Path| {
+            (
+                MappingKind::Mount,
+                MappingFrom::Folder(from.to_owned()),
+                MappingTo::Folder("/game".into()),
+            )
+        };
+        let mappings = mappings(vec![mount(&a), mount(&b)]);
+
+        let conflicts = find_conflicts(&mappings, &Contents::default(), false).unwrap();
+        assert_eq!(conflicts.len(), 2);
+        // `a` is mounted over `b`, and doesn't have `b.txt`, which it therefore hides.
+        assert_eq!(conflicts[0].path, Path::new("/game/b.txt"));
+        assert_eq!(conflicts[0].winner, a.join("b.txt"));
+        assert_eq!(conflicts[0].winning_mapping, None);
+        assert_eq!(conflicts[0].overridden[0].path, b.join("b.txt"));
+        assert_eq!(conflicts[1].path, Path::new("/game/shared.txt"));
+        assert_eq!(conflicts[1].winner, a.join("shared.txt"));
+        assert_eq!(conflicts[1].winning_mapping, Some(0));
+        assert_eq!(conflicts[1].overridden.len(), 1);
+        assert_eq!(conflicts[1].overridden[0].mapping, 1);
+        assert_eq!(conflicts[1].overridden[0].path, b.join("shared.txt"));
+        fs::remove_dir_all(a.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn later_redirects_are_overridden() {
+        let a = folder("redirects", "a", &["x.txt", "y.txt"]);
+        let b = folder("redirects", "b", &["x.txt"]);
+        let redirect = |from: &str, to: PathBuf| {
+            (
+                MappingKind::Redirect,
+                MappingFrom::File(Path::new("/game").join(from)),
+                MappingTo::File(to),
+            )
+        };
+        let mappings = mappings(vec![
+            redirect("x.txt", b.join("x.txt")),
+            redirect("x.txt", a.join("x.txt")),
+            redirect("y.txt", a.join("y.txt")),
+        ]);
+
+        let conflicts = find_conflicts(&mappings, &Contents::default(), false).unwrap();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, Path::new("/game/x.txt"));
+        assert_eq!(conflicts[0].winner, b.join("x.txt"));
+        assert_eq!(conflicts[0].winning_mapping, Some(0));
+        assert_eq!(conflicts[0].overridden.len(), 1);
+        assert_eq!(conflicts[0].overridden[0].mapping, 1);
+
+        fs::write(b.join("x.txt"), "a/x.txt").unwrap();
+        let conflicts = find_conflicts(&mappings, &Contents::default(), true).unwrap();
+        assert!(conflicts.is_empty());
+        fs::remove_dir_all(a.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,216 @@
+//! Contents which the mappings make up, rather than point to: the members of archives, and patched and merged files.
+//!
+//! `map_path` decides where a path leads, and `Contents` makes sure that something real is there, by extracting and
+//! generating files into the content cache.
+
+use std::{
+    borrow::Cow,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    archive::{ArchiveError, Archives},
+    cache::ContentCache,
+    merge, patch,
+    protocol::{Mapping, Mappings},
+};
+
+#[derive(Default)]
+pub struct Contents {
+    archives: Archives,
+    cache: Arc<ContentCache>,
+}
+
+impl Contents {
+    /// Prepare the contents used by `mappings`, which are put in `cache`. Reports the archives which couldn't be
+    /// indexed.
+    pub fn index<'a>(
+        mappings: impl IntoIterator<Item = &'a Mapping>,
+        cache: ContentCache,
+    ) -> (Self, Vec<ArchiveError>) {
+        let cache = Arc::new(cache);
+        let (archives, errors) = Archives::index(mappings, cache.clone());
+        (Self { archives, cache }, errors)
+    }
+
+    /// A real path with the contents of the virtual `path`, which `mappings` map to `mapped`. Members of archives are
+    /// extracted, patches applied and fragments merged if that hasn't been done already.
+    ///
+    /// Fails if a patch doesn't fit the file or a fragment can't be merged into it. Callers should then report the
+    /// error and fall back to `unpatched_path`, rather than to `path` itself.
+    pub fn real_path<'a>(
+        &self,
+        path: &Path,
+        mapped: Cow<'a, Path>,
+        mappings: &Mappings,
+    ) -> io::Result<Cow<'a, Path>> {
+        let mut real = self.unpatched_path(path, mapped)?;
+        if let Some(patched) = patch::apply_patches(path, &real, mappings, &self.cache)? {
+            real = patched.into();
+        }
+        if let Some(merged) = merge::apply_merges(path, &real, mappings, &self.cache)? {
+            real = merged.into();
+        }
+        Ok(real)
+    }
+
+    /// Like `real_path`, except that no patches are applied and no fragments merged.
+    pub fn unpatched_path<'a>(
+        &self,
+        path: &Path,
+        mapped: Cow<'a, Path>,
+    ) -> io::Result<Cow<'a, Path>> {
+        // Only mapped paths lead into archives, which keeps the archives themselves from looking like folders.
+        if mapped != path {
+            self.source_path(mapped)
+        } else {
+            Ok(mapped)
+        }
+    }
+
+    /// A real path with the contents of `source`, a path which a mapping leads to, before any patches or merges.
+    /// Members of archives are extracted if that hasn't been done already.
+    pub fn source_path<'a>(&self, source: Cow<'a, Path>) -> io::Result<Cow<'a, Path>> {
+        Ok(match self.archives.extract(&source)? {
+            Some(extracted) => extracted.into(),
+            None => source,
+        })
+    }
+
+    /// The paths of the files in the archive folder `path`, relative to it.
+    pub fn archive_files(&self, path: &Path) -> Vec<PathBuf> {
+        self.archives.files(path)
+    }
+
+    /// Like `real_path`, except that the contents of archive members may be missing. This is enough for listing
+    /// folders and reading metadata, and spares extracting anything.
+    pub fn outline_path<'a>(
+        &self,
+        path: &Path,
+        mapped: Cow<'a, Path>,
+        mappings: &Mappings,
+    ) -> io::Result<Cow<'a, Path>> {
+        // The size of a patched or merged file isn't known before it's been generated. If it can't be, the file is
+        // opened unpatched, which is reported when that happens.
+        if patch::has_patches(path, mappings) || merge::has_merges(path, mappings) {
+            if let Ok(real) = self.real_path(path, mapped.clone(), mappings) {
+                return Ok(real);
+            }
+        }
+        if mapped != path {
+            if let Some(outline) = self.archives.outline(&mapped)? {
+                return Ok(outline.into());
+            }
+        }
+        Ok(mapped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        io::{Cursor, Write},
+        process,
+    };
+
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+    use crate::{
+        cache::CacheConfig,
+        protocol::{MappingFrom, MappingKind, MappingTo},
+        vfs,
+    };
+
+    #[test]
+    fn archive_prefix_is_stripped() {
+        let dir = env::temp_dir().join(format!("asbestos-contents-prefix-{}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        zip.start_file("Data/sub/b.txt", FileOptions::default())
+            .unwrap();
+        zip.write_all(b"b").unwrap();
+        zip.start_file("readme.txt", FileOptions::default())
+            .unwrap();
+        let archive = dir.join("mod.zip");
+        fs::write(&archive, zip.finish().unwrap().into_inner()).unwrap();
+
+        let game = dir.join("game");
+        let mappings = Mappings::new(vec![Mapping {
+            kind: MappingKind::Mount,
+            from: MappingFrom::Archive {
+                path: archive.clone(),
+                prefix: Some("Data".into()),
+            },
+            to: MappingTo::Folder(game.clone()),
+        }]);
+        let cache = ContentCache::new(&CacheConfig {
+            dir: Some(dir.join("cache")),
+            max_size: None,
+        });
+        let (contents, errors) = Contents::index(mappings.iter(), cache);
+        assert!(errors.is_empty());
+
+        let path = game.join("sub/b.txt");
+        let mapped = vfs::map_path(Cow::Borrowed(&path), &mappings, None).unwrap();
+        assert_eq!(mapped, archive.join("Data/sub/b.txt"));
+        let real = contents.real_path(&path, mapped, &mappings).unwrap();
+        assert_eq!(fs::read(real).unwrap(), b"b");
+
+        // Members outside of the prefix don't appear in the folder.
+        let names = vfs::read_dir(&game, &mappings, &contents).unwrap();
+        assert_eq!(names.into_iter().collect::<Vec<_>>(), ["sub"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn patches_which_dont_fit_leave_the_mapped_file() {
+        let dir = env::temp_dir().join(format!("asbestos-contents-unpatched-{}", process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let (virtual_path, real) = (dir.join("game/a.txt"), dir.join("mod/a.txt"));
+        fs::create_dir_all(real.parent().unwrap()).unwrap();
+        fs::write(&real, b"a").unwrap();
+        fs::write(dir.join("a.txt.txt"), b"0: 62").unwrap();
+
+        let mappings = Mappings::new(vec![
+            Mapping {
+                kind: MappingKind::Redirect,
+                from: MappingFrom::File(virtual_path.clone()),
+                to: MappingTo::File(real.clone()),
+            },
+            Mapping {
+                kind: MappingKind::Patch {
+                    sha256: "0".repeat(64),
+                    format: None,
+                },
+                from: MappingFrom::File(virtual_path.clone()),
+                to: MappingTo::File(dir.join("a.txt.txt")),
+            },
+        ]);
+        let cache = ContentCache::new(&CacheConfig {
+            dir: Some(dir.join("cache")),
+            max_size: None,
+        });
+        let (contents, _) = Contents::index(mappings.iter(), cache);
+
+        let mapped = vfs::map_path(Cow::Borrowed(&virtual_path), &mappings, None).unwrap();
+        let err = contents
+            .real_path(&virtual_path, mapped.clone(), &mappings)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let unpatched = contents.unpatched_path(&virtual_path, mapped).unwrap();
+        assert_eq!(unpatched, real);
+        // Its metadata comes from the file as it is, which is what opening it falls back to.
+        let outline = vfs::map_path(Cow::Borrowed(&virtual_path), &mappings, None).unwrap();
+        let outline = contents
+            .outline_path(&virtual_path, outline, &mappings)
+            .unwrap();
+        assert_eq!(outline, real);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,16 @@
+#[cfg(windows)]
 pub use named_pipe;
 
+pub mod archive;
+pub mod cache;
+pub mod conflicts;
+pub mod contents;
+pub mod merge;
+pub mod patch;
+pub mod profiles;
 pub mod protocol;
+pub mod rules;
+pub mod vfs;
 
 mod protocol_macros;
 
@@ -0,0 +1,497 @@
+//! Merging configuration files as they are opened.
+//!
+//! A `Merge` mapping merges the fragment in `to` over whatever file `from` resolves to through the other mappings, so
+//! that several mods can each change a few keys of the same file. Fragments are merged in the order of their
+//! mappings, so a later fragment takes precedence over an earlier one, and every fragment over the original. Whatever
+//! no fragment mentions is kept as it is. The merged file is put in the content cache the first time it's needed.
+//!
+//! Files may be UTF-8 or, with a byte order mark, UTF-16. The merged file is encoded like the original.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    error::Error,
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    cache::ContentCache,
+    protocol::{MappingFrom, MappingKind, MappingTo, Mappings, MergeFormat},
+};
+
+/// Merge the fragments `mappings` has for the virtual `path`, whose contents are at `real`, over it. Returns the path
+/// of the merged file, or `None` if there are no fragments for `path`. A missing original is merged into as if it was
+/// empty, which lets fragments create a file.
+pub fn apply_merges(
+    path: &Path,
+    real: &Path,
+    mappings: &Mappings,
+    cache: &ContentCache,
+) -> io::Result<Option<PathBuf>> {
+    let fragments: Vec<_> = fragments(path, mappings).collect();
+    if fragments.is_empty() {
+        return Ok(None);
+    }
+
+    // Identifying the files by their metadata spares reading them every time the file is opened.
+    let mut hasher = DefaultHasher::new();
+    match fs::metadata(real) {
+        Ok(metadata) => version(real, &metadata).hash(&mut hasher),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    for (fragment, format) in &fragments {
+        version(fragment, &fs::metadata(fragment)?).hash(&mut hasher);
+        format.hash(&mut hasher);
+    }
+    let key = format!("merge\0{}\0{:016x}", path.display(), hasher.finish());
+
+    cache
+        .get_or_insert(&key, |out| {
+            let (mut merged, encoding) = match fs::read(real) {
+                Ok(original) => decode(&original),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    (String::new(), Encoding::Utf8)
+                }
+                Err(err) => return Err(err),
+            };
+            for (fragment, format) in &fragments {
+                let (text, _) = decode(&fs::read(fragment)?);
+                merged = match format {
+                    MergeFormat::Ini => Ok(merge_ini(&merged, &text)),
+                    MergeFormat::Json => merge_json(&merged, &text),
+                    MergeFormat::Toml => merge_toml(&merged, &text),
+                }
+                .map_err(|reason| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        MergeError {
+                            original: real.to_owned(),
+                            fragment: fragment.to_path_buf(),
+                            reason,
+                        },
+                    )
+                })?;
+            }
+            out.write_all(&encode(&merged, encoding))
+        })
+        .map(Some)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Utf8,
+    /// UTF-8 with a byte order mark, which Notepad likes to add.
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+const UTF16_LE_BOM: &[u8] = b"\xff\xfe";
+const UTF16_BE_BOM: &[u8] = b"\xfe\xff";
+
+/// The text of a file, told apart by its byte order mark. Anything without one is taken to be UTF-8, and whatever
+/// isn't valid is replaced rather than refused, since it's usually in a comment or a value no fragment touches.
+fn decode(bytes: &[u8]) -> (String, Encoding) {
+    fn utf16(bytes: &[u8], unit: fn([u8; 2]) -> u16) -> String {
+        let units: Vec<_> = bytes
+            .chunks_exact(2)
+            .map(|pair| unit([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    }
+
+    if let Some(text) = bytes.strip_prefix(UTF8_BOM) {
+        (
+            String::from_utf8_lossy(text).into_owned(),
+            Encoding::Utf8Bom,
+        )
+    } else if let Some(text) = bytes.strip_prefix(UTF16_LE_BOM) {
+        (utf16(text, u16::from_le_bytes), Encoding::Utf16Le)
+    } else if let Some(text) = bytes.strip_prefix(UTF16_BE_BOM) {
+        (utf16(text, u16::from_be_bytes), Encoding::Utf16Be)
+    } else {
+        (String::from_utf8_lossy(bytes).into_owned(), Encoding::Utf8)
+    }
+}
+
+fn encode(text: &str, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Utf8 => text.as_bytes().to_vec(),
+        Encoding::Utf8Bom => [UTF8_BOM, text.as_bytes()].concat(),
+        Encoding::Utf16Le => UTF16_LE_BOM
+            .iter()
+            .copied()
+            .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+            .collect(),
+        Encoding::Utf16Be => UTF16_BE_BOM
+            .iter()
+            .copied()
+            .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+            .collect(),
+    }
+}
+
+/// Whether `mappings` have any fragments to merge over the virtual `path`.
+pub fn has_merges(path: &Path, mappings: &Mappings) -> bool {
+    fragments(path, mappings).next().is_some()
+}
+
+/// The fragments for `path`, along with their format.
+fn fragments<'a>(
+    path: &'a Path,
+    mappings: &'a Mappings,
+) -> impl Iterator<Item = (&'a Path, MergeFormat)> {
+    mappings.iter().filter_map(
+        move |mapping| match (&mapping.kind, &mapping.from, &mapping.to) {
+            (MappingKind::Merge { format }, MappingFrom::File(from), MappingTo::File(fragment))
+                if from == path =>
+            {
+                let format = format.unwrap_or_else(|| MergeFormat::from_extension(fragment));
+                Some((fragment.as_path(), format))
+            }
+            _ => None,
+        },
+    )
+}
+
+fn version<'a>(path: &'a Path, metadata: &fs::Metadata) -> impl Hash + 'a {
+    (path, metadata.len(), metadata.modified().ok())
+}
+
+impl MergeFormat {
+    /// `json` and `toml` files are what their extension says, and anything else is taken to be an INI file.
+    fn from_extension(fragment: &Path) -> Self {
+        match fragment
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => Self::Json,
+            Some(extension) if extension.eq_ignore_ascii_case("toml") => Self::Toml,
+            _ => Self::Ini,
+        }
+    }
+}
+
+/// Merge an INI fragment over `original`, changing as little of it as possible: keys which already exist are replaced
+/// where they are, new keys are added to the end of their section, and new sections to the end of the file. Section
+/// and key names are compared case-insensitively, like Windows does. A key which appears more than once, in one
+/// section or in several sections of the same name, is replaced everywhere, since programs differ in which one they
+/// read.
+fn merge_ini(original: &str, fragment: &str) -> String {
+    let newline = if original.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut sections = parse_ini(original);
+    let original_sections = sections.len();
+
+    // The section the fragment is in, by its name and where its new keys go.
+    let mut name = None;
+    let mut section = 0;
+    for line in fragment.lines() {
+        match ini_line(line) {
+            IniLine::Section(header) => {
+                name = Some(header);
+                section = match sections.iter().position(|section| section.is(header)) {
+                    Some(section) => section,
+                    None => {
+                        sections.push(IniSection {
+                            name: Some(header.to_owned()),
+                            lines: vec![line.trim().to_owned()],
+                        });
+                        sections.len() - 1
+                    }
+                };
+            }
+            IniLine::Key(key) => {
+                let mut replaced = false;
+                // Keys before the first header can only be in the first section, the only one without a name.
+                let same_sections = sections.iter_mut().filter(|other| match name {
+                    Some(name) => other.is(name),
+                    None => other.name.is_none(),
+                });
+                for same_section in same_sections {
+                    for existing in &mut same_section.lines {
+                        if matches!(ini_line(existing), IniLine::Key(k) if k.eq_ignore_ascii_case(key))
+                        {
+                            *existing = line.trim().to_owned();
+                            replaced = true;
+                        }
+                    }
+                }
+                if !replaced {
+                    // After the last key rather than at the very end, which keeps the blank lines between sections
+                    // where they are.
+                    let lines = &mut sections[section].lines;
+                    let end = lines
+                        .iter()
+                        .rposition(|line| !line.trim().is_empty())
+                        .map_or(lines.len(), |last| last + 1);
+                    lines.insert(end, line.trim().to_owned());
+                }
+            }
+            IniLine::Other => {}
+        }
+    }
+
+    let mut lines: Vec<&str> = Vec::new();
+    for (i, section) in sections.iter().enumerate() {
+        // Sections added by the fragment are set apart from the one before them.
+        let previous_blank = lines.last().is_none_or(|line| line.trim().is_empty());
+        if i >= original_sections && !previous_blank {
+            lines.push("");
+        }
+        lines.extend(section.lines.iter().map(String::as_str));
+    }
+    let mut merged = lines.join(newline);
+    // A file without a final newline is kept that way.
+    if original.is_empty() || original.ends_with('\n') {
+        merged.push_str(newline);
+    }
+    merged
+}
+
+/// The lines of an INI file, split into sections. The first section holds whatever comes before the first header.
+fn parse_ini(text: &str) -> Vec<IniSection> {
+    let mut sections = vec![IniSection {
+        name: None,
+        lines: Vec::new(),
+    }];
+    for line in text.lines() {
+        if let IniLine::Section(name) = ini_line(line) {
+            sections.push(IniSection {
+                name: Some(name.to_owned()),
+                lines: Vec::new(),
+            });
+        }
+        sections.last_mut().unwrap().lines.push(line.to_owned());
+    }
+    sections
+}
+
+fn ini_line(line: &str) -> IniLine<'_> {
+    let line = line.trim();
+    if line.starts_with('[') && line.ends_with(']') {
+        IniLine::Section(line[1..line.len() - 1].trim())
+    } else if line.starts_with(';') || line.starts_with('#') {
+        IniLine::Other
+    } else {
+        match line.find('=') {
+            Some(equals) if equals > 0 => IniLine::Key(line[..equals].trim()),
+            _ => IniLine::Other,
+        }
+    }
+}
+
+enum IniLine<'a> {
+    Section(&'a str),
+    Key(&'a str),
+    /// Blank lines, comments, and anything else which isn't understood, which is kept as it is.
+    Other,
+}
+
+struct IniSection {
+    name: Option<String>,
+    lines: Vec<String>,
+}
+
+impl IniSection {
+    fn is(&self, name: &str) -> bool {
+        self.name
+            .as_deref()
+            .is_some_and(|own| own.eq_ignore_ascii_case(name))
+    }
+}
+
+/// Merge a JSON fragment over `original`: objects are merged key by key, and anything else is replaced.
+fn merge_json(original: &str, fragment: &str) -> Result<String, String> {
+    let mut merged = if original.trim().is_empty() {
+        serde_json::Value::Object(Default::default())
+    } else {
+        serde_json::from_str(original)
+            .map_err(|err| format!("the original isn't valid JSON: {}", err))?
+    };
+    let fragment = serde_json::from_str(fragment).map_err(|err| err.to_string())?;
+    merge_json_value(&mut merged, fragment);
+    serde_json::to_string_pretty(&merged).map_err(|err| err.to_string())
+}
+
+fn merge_json_value(merged: &mut serde_json::Value, fragment: serde_json::Value) {
+    match (merged, fragment) {
+        (serde_json::Value::Object(merged), serde_json::Value::Object(fragment)) => {
+            for (key, value) in fragment {
+                match merged.get_mut(&key) {
+                    Some(existing) => merge_json_value(existing, value),
+                    None => {
+                        merged.insert(key, value);
+                    }
+                }
+            }
+        }
+        (merged, fragment) => *merged = fragment,
+    }
+}
+
+/// Merge a TOML fragment over `original`: tables are merged key by key, and anything else is replaced.
+fn merge_toml(original: &str, fragment: &str) -> Result<String, String> {
+    let mut merged: toml::Value = toml::from_str(original)
+        .map_err(|err| format!("the original isn't valid TOML: {}", err))?;
+    let fragment = toml::from_str(fragment).map_err(|err| err.to_string())?;
+    merge_toml_value(&mut merged, fragment);
+    toml::to_string(&merged).map_err(|err| err.to_string())
+}
+
+fn merge_toml_value(merged: &mut toml::Value, fragment: toml::Value) {
+    match (merged, fragment) {
+        (toml::Value::Table(merged), toml::Value::Table(fragment)) => {
+            for (key, value) in fragment {
+                match merged.get_mut(&key) {
+                    Some(existing) => merge_toml_value(existing, value),
+                    None => {
+                        merged.insert(key, value);
+                    }
+                }
+            }
+        }
+        (merged, fragment) => *merged = fragment,
+    }
+}
+
+/// A fragment couldn't be merged.
+#[derive(Debug)]
+pub struct MergeError {
+    pub original: PathBuf,
+    pub fragment: PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Could not merge {} into {}: {}",
+            self.fragment.display(),
+            self.original.display(),
+            self.reason
+        )
+    }
+}
+
+impl Error for MergeError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ini_replaces_keys_where_they_are() {
+        let original = "; Settings\n[Display]\nWidth=800\nheight=600\n";
+        assert_eq!(
+            merge_ini(original, "[display]\nHeight=1080\n"),
+            "; Settings\n[Display]\nWidth=800\nHeight=1080\n"
+        );
+    }
+
+    #[test]
+    fn ini_replaces_every_duplicate() {
+        let original =
+            "Mode=1\n[Display]\nWidth=800\nwidth=640\n[Sound]\nWidth=1\n[DISPLAY]\nWidth=320\n";
+        assert_eq!(
+            merge_ini(original, "[Display]\nWidth=1920\n"),
+            "Mode=1\n[Display]\nWidth=1920\nWidth=1920\n[Sound]\nWidth=1\n[DISPLAY]\nWidth=1920\n"
+        );
+        // Keys before the first header only match the keys there.
+        assert_eq!(
+            merge_ini(original, "Width=0\nMode=2\n"),
+            "Mode=2\nWidth=0\n[Display]\nWidth=800\nwidth=640\n[Sound]\nWidth=1\n[DISPLAY]\nWidth=320\n"
+        );
+    }
+
+    #[test]
+    fn ini_keeps_the_final_newline() {
+        assert_eq!(merge_ini("[A]\nx=1", "[A]\nx=2\n"), "[A]\nx=2");
+        assert_eq!(merge_ini("[A]\nx=1", "[A]\ny=2\n"), "[A]\nx=1\ny=2");
+        assert_eq!(
+            merge_ini("[A]\r\nx=1\r\n", "[B]\ny=2"),
+            "[A]\r\nx=1\r\n\r\n[B]\r\ny=2\r\n"
+        );
+        assert_eq!(merge_ini("", "[B]\ny=2"), "[B]\ny=2\n");
+    }
+
+    #[test]
+    fn ini_appends_keys_to_their_section() {
+        let original = "[Display]\nWidth=800\n\n[Sound]\nVolume=5\n";
+        assert_eq!(
+            merge_ini(original, "[Display]\nFullscreen=1\n"),
+            "[Display]\nWidth=800\nFullscreen=1\n\n[Sound]\nVolume=5\n"
+        );
+    }
+
+    #[test]
+    fn ini_appends_new_sections() {
+        let original = "[Display]\nWidth=800\n";
+        assert_eq!(
+            merge_ini(original, "[Mods]\nEnabled=1\n"),
+            "[Display]\nWidth=800\n\n[Mods]\nEnabled=1\n"
+        );
+    }
+
+    #[test]
+    fn ini_keeps_crlf() {
+        let original = "[Display]\r\nWidth=800\r\n";
+        assert_eq!(
+            merge_ini(original, "[Display]\nWidth=1920\nHeight=1080\n"),
+            "[Display]\r\nWidth=1920\r\nHeight=1080\r\n"
+        );
+    }
+
+    #[test]
+    fn ini_with_bom() {
+        let (original, encoding) = decode(b"\xef\xbb\xbf[Display]\r\nWidth=800\r\n");
+        assert_eq!(encoding, Encoding::Utf8Bom);
+        let (fragment, _) = decode(b"\xef\xbb\xbf[Display]\nWidth=1920\n");
+        let merged = merge_ini(&original, &fragment);
+        assert_eq!(
+            encode(&merged, encoding),
+            b"\xef\xbb\xbf[Display]\r\nWidth=1920\r\n"
+        );
+    }
+
+    #[test]
+    fn utf16() {
+        let bytes = encode("[Display]\nWidth=800\n", Encoding::Utf16Le);
+        assert_eq!(&bytes[..4], b"\xff\xfe[\x00");
+        assert_eq!(
+            decode(&bytes),
+            ("[Display]\nWidth=800\n".to_owned(), Encoding::Utf16Le)
+        );
+        let bytes = encode("é", Encoding::Utf16Be);
+        assert_eq!(bytes, b"\xfe\xff\x00\xe9");
+        assert_eq!(decode(&bytes), ("é".to_owned(), Encoding::Utf16Be));
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        assert_eq!(
+            decode(b"a=\xff\n"),
+            ("a=\u{fffd}\n".to_owned(), Encoding::Utf8)
+        );
+    }
+
+    #[test]
+    fn json_keeps_the_order_of_keys() {
+        let merged = merge_json(
+            r#"{"b": 1, "a": {"d": 2, "c": 3}}"#,
+            r#"{"a": {"c": 4, "e": 5}}"#,
+        );
+        assert_eq!(
+            merged.unwrap(),
+            "{\n  \"b\": 1,\n  \"a\": {\n    \"d\": 2,\n    \"c\": 4,\n    \"e\": 5\n  }\n}"
+        );
+    }
+}
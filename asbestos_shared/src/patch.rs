@@ -0,0 +1,516 @@
+//! Patching files as they are opened.
+//!
+//! A `Patch` mapping applies the patch in `to` to whatever file `from` resolves to through the other mappings. The
+//! patched file is put in the content cache the first time it's needed, after checking that the original is the file
+//! the patch was made for. Several patches for the same file are applied in the order of their mappings.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::TryInto,
+    error::Error,
+    fmt, fs,
+    hash::{Hash, Hasher},
+    io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    cache::ContentCache,
+    protocol::{MappingFrom, MappingKind, MappingTo, Mappings, PatchFormat},
+};
+
+/// Apply the patches `mappings` has for the virtual `path`, whose contents are at `real`. Returns the path of the
+/// patched file, or `None` if there are no patches for `path`.
+pub fn apply_patches(
+    path: &Path,
+    real: &Path,
+    mappings: &Mappings,
+    cache: &ContentCache,
+) -> io::Result<Option<PathBuf>> {
+    let mut patched = None;
+    for (patch, sha256, format) in patches(path, mappings) {
+        let original = patched.as_deref().unwrap_or(real);
+        let format = format
+            .or_else(|| PatchFormat::from_extension(patch))
+            .ok_or_else(|| {
+                invalid(PatchError::UnknownFormat {
+                    patch: patch.to_owned(),
+                })
+            })?;
+        patched = Some(apply_patch(original, patch, format, sha256, cache)?);
+    }
+    Ok(patched)
+}
+
+/// Whether `mappings` have any patches for the virtual `path`.
+pub fn has_patches(path: &Path, mappings: &Mappings) -> bool {
+    patches(path, mappings).next().is_some()
+}
+
+/// The patches for `path`, along with the hash of the file each of them was made for and their format.
+fn patches<'a>(
+    path: &'a Path,
+    mappings: &'a Mappings,
+) -> impl Iterator<Item = (&'a Path, &'a str, Option<PatchFormat>)> {
+    mappings.iter().filter_map(
+        move |mapping| match (&mapping.kind, &mapping.from, &mapping.to) {
+            (
+                MappingKind::Patch { sha256, format },
+                MappingFrom::File(from),
+                MappingTo::File(patch),
+            ) if from == path => Some((patch.as_path(), sha256.as_str(), *format)),
+            _ => None,
+        },
+    )
+}
+
+fn apply_patch(
+    original: &Path,
+    patch: &Path,
+    format: PatchFormat,
+    sha256: &str,
+    cache: &ContentCache,
+) -> io::Result<PathBuf> {
+    // Identifying the files by their metadata spares hashing the original every time it's opened. The hash is only
+    // checked when the patched file isn't in the cache yet.
+    let mut hasher = DefaultHasher::new();
+    for path in [original, patch].iter() {
+        let metadata = fs::metadata(path)?;
+        path.hash(&mut hasher);
+        metadata.len().hash(&mut hasher);
+        metadata.modified().ok().hash(&mut hasher);
+    }
+    let key = format!("patch\0{:016x}\0{}", hasher.finish(), sha256);
+
+    cache.get_or_insert(&key, |out| {
+        let original_bytes = fs::read(original)?;
+        let actual = format!("{:x}", Sha256::digest(&original_bytes));
+        if !actual.eq_ignore_ascii_case(sha256) {
+            return Err(invalid(PatchError::OriginalMismatch {
+                original: original.to_owned(),
+                patch: patch.to_owned(),
+                expected: sha256.to_owned(),
+                actual,
+            }));
+        }
+        let patch_bytes = fs::read(patch)?;
+        let patched = match format {
+            PatchFormat::Ips => apply_ips(original_bytes, &patch_bytes),
+            PatchFormat::Bps => apply_bps(&original_bytes, &patch_bytes),
+            PatchFormat::Offsets => apply_offsets(original_bytes, &patch_bytes),
+        }
+        .map_err(|reason| {
+            invalid(PatchError::Malformed {
+                patch: patch.to_owned(),
+                reason,
+            })
+        })?;
+        out.write_all(&patched)
+    })
+}
+
+impl PatchFormat {
+    /// `ips` and `bps` files are what their extension says, and `txt` files are offset lists. Other formats, like
+    /// xdelta, aren't supported.
+    fn from_extension(patch: &Path) -> Option<Self> {
+        match patch.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("ips") => Some(Self::Ips),
+            Some(extension) if extension.eq_ignore_ascii_case("bps") => Some(Self::Bps),
+            Some(extension) if extension.eq_ignore_ascii_case("txt") => Some(Self::Offsets),
+            _ => None,
+        }
+    }
+}
+
+/// Apply an IPS patch: records of bytes to write at 24-bit offsets, optionally followed by the length to truncate the
+/// file to.
+fn apply_ips(mut file: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut reader = Reader::new(patch);
+    if reader.bytes(5)? != b"PATCH" {
+        return Err("missing IPS header");
+    }
+    loop {
+        let offset = reader.bytes(3)?;
+        if offset == b"EOF" {
+            break;
+        }
+        let offset = be(offset);
+        let len = be(reader.bytes(2)?);
+        if len == 0 {
+            // Run-length encoded: a byte repeated some number of times.
+            let len = be(reader.bytes(2)?);
+            let value = reader.bytes(1)?[0];
+            write_at(&mut file, offset, &vec![value; len]);
+        } else {
+            write_at(&mut file, offset, reader.bytes(len)?);
+        }
+    }
+    if let Ok(len) = reader.bytes(3) {
+        file.truncate(be(len));
+    }
+    Ok(file)
+}
+
+/// Apply a BPS patch, which describes the patched file as a series of reads and copies from the original, the patch
+/// and what has been written so far. Checksums of all three are included, and checked.
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if patch.len() < 16 || &patch[..4] != b"BPS1" {
+        return Err("missing BPS header");
+    }
+    let (body, footer) = patch.split_at(patch.len() - 12);
+    let checksum = |bytes: &[u8]| u32::from_le_bytes(bytes.try_into().unwrap());
+    // The checksum of the patch covers everything but itself, including the other two.
+    if crc32fast::hash(&patch[..patch.len() - 4]) != checksum(&footer[8..]) {
+        return Err("the patch is corrupt");
+    }
+    if crc32fast::hash(source) != checksum(&footer[..4]) {
+        return Err("the source checksum doesn't match");
+    }
+
+    let mut reader = Reader::new(&body[4..]);
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+    if source_size != source.len() {
+        return Err("the source size doesn't match");
+    }
+
+    // The stated size comes from the patch, so it isn't trusted with more memory than the files themselves take.
+    let mut target = Vec::with_capacity(target_size.min(source.len() + patch.len()));
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    while !reader.is_empty() {
+        let action = reader.number()?;
+        let len = (action >> 2) + 1;
+        // Target copies could otherwise make the file grow without end.
+        if len > target_size - target.len() {
+            return Err("the patched file is larger than the target size");
+        }
+        match action & 3 {
+            // Source read: the same bytes as the original at the same offset.
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(
+                    source
+                        .get(start..start + len)
+                        .ok_or("source read out of bounds")?,
+                );
+            }
+            // Target read: bytes from the patch.
+            1 => target.extend_from_slice(reader.bytes(len)?),
+            // Source copy: bytes from elsewhere in the original.
+            2 => {
+                source_offset = relative(source_offset, reader.number()?)?;
+                let bytes = source
+                    .get(source_offset..)
+                    .and_then(|rest| rest.get(..len))
+                    .ok_or("source copy out of bounds")?;
+                target.extend_from_slice(bytes);
+                source_offset += len;
+            }
+            // Target copy: bytes from what has been written so far, which may overlap what is being written.
+            _ => {
+                target_offset = relative(target_offset, reader.number()?)?;
+                for _ in 0..len {
+                    let byte = *target
+                        .get(target_offset)
+                        .ok_or("target copy out of bounds")?;
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size || crc32fast::hash(&target) != checksum(&footer[4..8]) {
+        return Err("the patched file doesn't match the target checksum");
+    }
+    Ok(target)
+}
+
+/// Apply an offset list: one `<offset>: <bytes>` per line, where the offset is decimal or hexadecimal with `0x`, and
+/// the bytes are hexadecimal, optionally separated by spaces. Lines starting with `#` are ignored.
+fn apply_offsets(mut file: Vec<u8>, patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let patch = std::str::from_utf8(patch).map_err(|_| "the offset list isn't UTF-8")?;
+    for line in patch.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let offset = parts.next().unwrap().trim();
+        let offset = match offset.strip_prefix("0x") {
+            Some(hex) => usize::from_str_radix(hex, 16),
+            None => offset.parse(),
+        }
+        .map_err(|_| "invalid offset")?;
+        let digits: Vec<u8> = parts
+            .next()
+            .ok_or("missing ':' after offset")?
+            .bytes()
+            .filter(|b| !b.is_ascii_whitespace())
+            .collect();
+        if !digits.len().is_multiple_of(2) {
+            return Err("odd number of hexadecimal digits");
+        }
+        let bytes = digits
+            .chunks(2)
+            .map(|pair| {
+                std::str::from_utf8(pair)
+                    .ok()
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or("invalid hexadecimal byte")
+            })
+            .collect::<Result<Vec<u8>, _>>()?;
+        write_at(&mut file, offset, &bytes);
+    }
+    Ok(file)
+}
+
+/// Overwrite the bytes at `offset`, growing the file with zeros if it's too short.
+fn write_at(file: &mut Vec<u8>, offset: usize, bytes: &[u8]) {
+    if file.len() < offset + bytes.len() {
+        file.resize(offset + bytes.len(), 0);
+    }
+    file[offset..offset + bytes.len()].copy_from_slice(bytes);
+}
+
+/// A big-endian number.
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, b| n << 8 | *b as usize)
+}
+
+/// Move an offset by a BPS signed number, whose lowest bit is the sign.
+fn relative(offset: usize, number: usize) -> Result<usize, &'static str> {
+    let delta = number >> 1;
+    if number & 1 == 0 {
+        offset.checked_add(delta)
+    } else {
+        offset.checked_sub(delta)
+    }
+    .ok_or("relative offset out of bounds")
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        if self.bytes.len() < len {
+            return Err("unexpected end of patch");
+        }
+        let (bytes, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(bytes)
+    }
+
+    /// A BPS variable-length number.
+    fn number(&mut self) -> Result<usize, &'static str> {
+        let mut number = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.bytes(1)?[0] as usize;
+            number = (byte & 0x7f)
+                .checked_mul(shift)
+                .and_then(|n| n.checked_add(number))
+                .ok_or("number too large")?;
+            if byte & 0x80 != 0 {
+                return Ok(number);
+            }
+            shift = shift.checked_mul(128).ok_or("number too large")?;
+            number = number.checked_add(shift).ok_or("number too large")?;
+        }
+    }
+}
+
+fn invalid(err: PatchError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[derive(Debug)]
+pub enum PatchError {
+    /// The original file isn't the one the patch was made for.
+    OriginalMismatch {
+        original: PathBuf,
+        patch: PathBuf,
+        expected: String,
+        actual: String,
+    },
+    Malformed {
+        patch: PathBuf,
+        reason: &'static str,
+    },
+    /// The format of the patch isn't given, and can't be told from its extension.
+    UnknownFormat { patch: PathBuf },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::OriginalMismatch {
+                original,
+                patch,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Not applying {} to {}: it was made for a file with the SHA-256 hash {}, but the file's is {}",
+                patch.display(),
+                original.display(),
+                expected,
+                actual
+            ),
+            Self::Malformed { patch, reason } => {
+                write!(f, "Could not apply {}: {}", patch.display(), reason)
+            }
+            Self::UnknownFormat { patch } => write!(
+                f,
+                "Could not apply {}: its format is neither given nor one of ips, bps or txt",
+                patch.display()
+            ),
+        }
+    }
+}
+
+impl Error for PatchError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ips() {
+        let mut patch = b"PATCH".to_vec();
+        // Two bytes at 1, then `z` three times at 5.
+        patch.extend_from_slice(b"\x00\x00\x01\x00\x02XY");
+        patch.extend_from_slice(b"\x00\x00\x05\x00\x00\x00\x03z");
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(
+            apply_ips(b"abcdefgh".to_vec(), &patch).unwrap(),
+            b"aXYdezzz"
+        );
+
+        // Truncated to 4 bytes.
+        patch.extend_from_slice(b"\x00\x00\x04");
+        assert_eq!(apply_ips(b"abcdefgh".to_vec(), &patch).unwrap(), b"aXYd");
+    }
+
+    /// A BPS patch with the given actions, checksums included.
+    #[test]
+    fn bps_target_size_is_enforced() {
+        let source = b"a";
+        // A target copy of a terabyte, from a file which is meant to be two bytes long.
+        let mut actions = action(1, 1);
+        actions.push(b'a');
+        actions.extend(action(3, 1 << 40));
+        actions.extend(number(0));
+        let patch = bps_patch(source, b"aa", &actions);
+        assert_eq!(
+            apply_bps(source, &patch),
+            Err("the patched file is larger than the target size")
+        );
+
+        // A stated size which is too large to allocate only fails the checksum.
+        let mut actions = action(1, 1);
+        actions.push(b'a');
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(1 << 60));
+        patch.extend(number(0));
+        patch.extend(actions);
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(b"a").to_le_bytes());
+        let checksum = crc32fast::hash(&patch);
+        patch.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            apply_bps(source, &patch),
+            Err("the patched file doesn't match the target checksum")
+        );
+    }
+
+    fn bps_patch(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(number(source.len()));
+        patch.extend(number(target.len()));
+        patch.extend(number(0));
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let checksum = crc32fast::hash(&patch);
+        patch.extend_from_slice(&checksum.to_le_bytes());
+        patch
+    }
+
+    fn number(mut n: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let low = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                bytes.push(0x80 | low);
+                return bytes;
+            }
+            bytes.push(low);
+            n -= 1;
+        }
+    }
+
+    fn action(kind: usize, len: usize) -> Vec<u8> {
+        number((len - 1) << 2 | kind)
+    }
+
+    #[test]
+    fn bps() {
+        let source = b"hello world";
+        let target = b"hello there, helloworld";
+        let mut actions = Vec::new();
+        // "hello " from the same place in the source.
+        actions.extend(action(0, 6));
+        // "there, " from the patch.
+        actions.extend(action(1, 7));
+        actions.extend_from_slice(b"there, ");
+        // "hello" from the start of the target.
+        actions.extend(action(3, 5));
+        actions.extend(number(0));
+        // "world" from 6 bytes into the source.
+        actions.extend(action(2, 5));
+        actions.extend(number(6 << 1));
+        let mut patch = bps_patch(source, target, &actions);
+        assert_eq!(apply_bps(source, &patch).unwrap(), target);
+
+        let last = patch.len() - 13;
+        patch[last] ^= 1;
+        assert_eq!(apply_bps(source, &patch), Err("the patch is corrupt"));
+    }
+
+    #[test]
+    fn offsets() {
+        let patch = b"# A comment\n0x2: FF 00\n4:ab\n\n8: 01";
+        assert_eq!(
+            apply_offsets(b"abcdef".to_vec(), patch).unwrap(),
+            b"ab\xff\x00\xabf\x00\x00\x01"
+        );
+        assert!(apply_offsets(b"abcdef".to_vec(), b"2: f").is_err());
+    }
+
+    #[test]
+    fn format_from_extension() {
+        let format = |patch: &str| PatchFormat::from_extension(Path::new(patch));
+        assert!(matches!(format("a.IPS"), Some(PatchFormat::Ips)));
+        assert!(matches!(format("a.bps"), Some(PatchFormat::Bps)));
+        assert!(matches!(format("a.txt"), Some(PatchFormat::Offsets)));
+        assert!(format("a.xdelta").is_none());
+        assert!(format("a").is_none());
+    }
+}
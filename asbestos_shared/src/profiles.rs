@@ -0,0 +1,140 @@
+//! Giving different processes in a session different mappings.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    protocol::{Mapping, Mappings},
+    rules::Rule,
+};
+
+/// The contents of a mappings file.
+///
+/// Every process receives the `mappings` of the first profile which applies to it, or the top-level `mappings` if
+/// none do. A file without any profiles is therefore equivalent to a plain `Mappings`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MappingProfiles {
+    #[serde(default)]
+    pub mappings: Vec<Mapping>,
+    #[serde(default)]
+    pub profiles: Vec<MappingProfile>,
+}
+
+impl MappingProfiles {
+    /// Pick the mappings for a process, along with the name of the profile they came from.
+    pub fn select(
+        &self,
+        executable: &str,
+        command_line: &str,
+        depth: u32,
+    ) -> (Option<&str>, Mappings) {
+        match self
+            .profiles
+            .iter()
+            .find(|profile| profile.applies_to(executable, command_line, depth))
+        {
+            Some(profile) => (Some(&profile.name), Mappings::new(profile.mappings.clone())),
+            None => (None, Mappings::new(self.mappings.clone())),
+        }
+    }
+}
+
+impl From<Mappings> for MappingProfiles {
+    fn from(from: Mappings) -> Self {
+        Self {
+            mappings: from.into_vec(),
+            profiles: Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MappingProfile {
+    pub name: String,
+    /// The profile applies to processes matching any of these rules. An empty list matches every process.
+    #[serde(default)]
+    pub executables: Vec<Rule>,
+    /// The profile only applies to processes at this depth, where the process the session started out with has a
+    /// depth of 0.
+    #[serde(default)]
+    pub depth: Option<u32>,
+    pub mappings: Vec<Mapping>,
+}
+
+impl MappingProfile {
+    pub fn applies_to(&self, executable: &str, command_line: &str, depth: u32) -> bool {
+        (self.depth.is_none() || self.depth == Some(depth))
+            && (self.executables.is_empty()
+                || self
+                    .executables
+                    .iter()
+                    .any(|rule| rule.matches(executable, command_line)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::protocol::{MappingFrom, Mappings};
+
+    use super::MappingProfiles;
+
+    const PROFILES: &str = r#"{
+        "mappings": [
+            { "kind": "Redirect", "from": { "file": "/game/default.ini" }, "to": { "file": "/mods/default.ini" } }
+        ],
+        "profiles": [
+            {
+                "name": "editor",
+                "executables": [{ "kind": "command_line", "regex": "--editor\\b" }],
+                "mappings": [
+                    { "kind": "Redirect", "from": { "file": "/game/editor.ini" }, "to": { "file": "/mods/editor.ini" } }
+                ]
+            },
+            {
+                "name": "launched",
+                "executables": [{ "kind": "name", "pattern": "game*.exe" }],
+                "depth": 1,
+                "mappings": []
+            }
+        ]
+    }"#;
+
+    fn redirected_from(mappings: &Mappings) -> Vec<&Path> {
+        mappings
+            .iter()
+            .filter_map(|mapping| match &mapping.from {
+                MappingFrom::File(from) => Some(from.as_path()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn select() {
+        let profiles: MappingProfiles = serde_json::from_str(PROFILES).unwrap();
+
+        let (name, mappings) = profiles.select("game.exe", "game.exe --editor", 0);
+        assert_eq!(name, Some("editor"));
+        assert_eq!(redirected_from(&mappings), [Path::new("/game/editor.ini")]);
+
+        // The first profile which applies wins.
+        let (name, _) = profiles.select("game.exe", "game.exe --editor", 1);
+        assert_eq!(name, Some("editor"));
+
+        let (name, mappings) = profiles.select("game.exe", "game.exe", 1);
+        assert_eq!(name, Some("launched"));
+        assert!(mappings.iter().next().is_none());
+
+        let (name, mappings) = profiles.select("game.exe", "game.exe", 0);
+        assert_eq!(name, None);
+        assert_eq!(redirected_from(&mappings), [Path::new("/game/default.ini")]);
+    }
+
+    #[test]
+    fn invalid_regex_fails_to_load() {
+        let profiles = PROFILES.replace("--editor\\\\b", "(--editor");
+        assert_ne!(profiles, PROFILES);
+        assert!(serde_json::from_str::<MappingProfiles>(&profiles).is_err());
+    }
+}
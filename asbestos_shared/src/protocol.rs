@@ -4,12 +4,16 @@ use std::{
     fmt,
     io::{self, Read, Write},
     path::PathBuf,
+    str::FromStr,
+    sync::OnceLock,
 };
 
 use bincode::{deserialize, deserialize_from, serialize, serialize_into};
 use serde::{Deserialize, Serialize};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::wrapper_enum;
+use crate::{cache::CacheConfig, rules::SubprocessRules, vfs::MappingIndex, wrapper_enum};
 
 pub struct Connection<R: Read, W: Write> {
     rx: R,
@@ -54,17 +58,123 @@ impl<R: Read, W: Write> Connection<R, W> {
     }
 
     pub fn write_message<T: Into<Message>>(&mut self, value: T) -> Result<(), ProtocolError> {
+        self.write_message_ref(&value.into())
+    }
+
+    /// Like `write_message`, but leaves the message with the caller, who may want to send it again later.
+    pub fn write_message_ref(&mut self, message: &Message) -> Result<(), ProtocolError> {
+        if !self.connected() {
+            return Err(ProtocolError::Disconnected);
+        }
+
+        let container = serialize(message)?;
+        serialize_into(&mut self.tx, &container)?;
+        Ok(())
+    }
+
+    /// Split the connection into a half which can only be read from and a half which can only be written to, so
+    /// that they may be used from different threads.
+    pub fn split(self) -> (Connection<R, io::Sink>, Connection<io::Empty, W>) {
+        (
+            Connection {
+                rx: self.rx,
+                tx: io::sink(),
+                state: self.state,
+            },
+            Connection {
+                rx: io::empty(),
+                tx: self.tx,
+                state: self.state,
+            },
+        )
+    }
+}
+
+/// An asynchronous counterpart to `Connection`, which speaks the same wire format.
+#[cfg(feature = "tokio")]
+pub struct AsyncConnection<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> {
+    rx: R,
+    tx: W,
+    state: ConnectionState,
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncRead + Unpin, W: AsyncWrite + Unpin> AsyncConnection<R, W> {
+    pub fn new(rx: R, tx: W) -> Self {
+        Self {
+            rx,
+            tx,
+            state: ConnectionState::Connected,
+        }
+    }
+
+    pub fn connected(&self) -> bool {
+        matches!(self.state, ConnectionState::Connected)
+    }
+
+    pub async fn read_message(&mut self) -> Result<Message, ProtocolError> {
+        if !self.connected() {
+            return Err(ProtocolError::Disconnected);
+        }
+
+        // `Connection` wraps every message in a `Vec<u8>`, which bincode prefixes with its length as a `u64`.
+        let res = async {
+            let len = self.rx.read_u64_le().await?;
+            let mut container = vec![0; len as usize];
+            self.rx.read_exact(&mut container).await?;
+            Ok::<_, io::Error>(container)
+        }
+        .await;
+        let container = match res {
+            Ok(value) => Ok(value),
+            Err(err) => match err.kind() {
+                io::ErrorKind::UnexpectedEof => {
+                    self.state = ConnectionState::Disconnected;
+                    Err(ProtocolError::ConnectionLost)
+                }
+                _ => Err(err.into()),
+            },
+        }?;
+        let value = deserialize(&container)?;
+        Ok(value)
+    }
+
+    pub async fn write_message<T: Into<Message>>(&mut self, value: T) -> Result<(), ProtocolError> {
         if !self.connected() {
             return Err(ProtocolError::Disconnected);
         }
 
         let message: Message = value.into();
         let container = serialize(&message)?;
-        serialize_into(&mut self.tx, &container)?;
+        self.tx.write_all(&serialize(&container)?).await?;
+        self.tx.flush().await?;
         Ok(())
     }
+
+    /// Split the connection into a half which can only be read from and a half which can only be written to, so
+    /// that they may be used from different tasks.
+    pub fn split(
+        self,
+    ) -> (
+        AsyncConnection<R, tokio::io::Sink>,
+        AsyncConnection<tokio::io::Empty, W>,
+    ) {
+        (
+            AsyncConnection {
+                rx: self.rx,
+                tx: tokio::io::sink(),
+                state: self.state,
+            },
+            AsyncConnection {
+                rx: tokio::io::empty(),
+                tx: self.tx,
+                state: self.state,
+            },
+        )
+    }
 }
 
+#[derive(Clone, Copy)]
 enum ConnectionState {
     Connected,
     Disconnected,
@@ -101,12 +211,19 @@ wrapper_enum! {
     #[derive(Debug, Deserialize, Serialize)]
     pub enum Message {
         StartupInfo(StartupInfo),
+        /// Asks the payload to disable its hooks and unload itself. The payload answers with `ProcessDetach`.
+        Detach,
         LogMessage(LogMessage),
         /// The payload has finished its initalization routine.
         Initialized,
         /// The payload encountered an error in its initialization routine.
         InitializationFailed(String),
         ProcessSpawned(ProcessSpawned),
+        ProcessInfo(ProcessInfo),
+        TraceEvent(TraceEvent),
+        HookProfile(HookProfile),
+        /// The payload panicked. What happens next is decided by `StartupInfo::panic_policy`.
+        Panic(PanicReport),
         /// The payload was unloaded from the target, either because it was manually unloaded, or because the process
         /// terminated.
         ProcessDetach,
@@ -117,22 +234,105 @@ wrapper_enum! {
 pub struct StartupInfo {
     pub main_thread_suspended: bool,
     pub dont_hook_subprocesses: bool,
+    /// Decides which subprocesses are hooked, unless `dont_hook_subprocesses` is set.
+    pub subprocess_rules: SubprocessRules,
+    /// How many processes separate this process from the process the session started out with.
+    pub depth: u32,
     pub show_console: bool,
+    /// Record a `TraceEvent` around every call made to a hooked function.
+    pub trace_hooks: bool,
+    /// Measure the time spent inside hooks and periodically send a `HookProfile`.
+    pub profile_hooks: bool,
+    /// What the payload does after panicking.
+    pub panic_policy: PanicPolicy,
     pub mappings: Mappings,
+    /// Where contents which don't exist on disk, such as the members of archives, are put to be opened.
+    pub cache: CacheConfig,
     pub tid: u32,
 }
 
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PanicPolicy {
+    /// Disable every hook and let the process continue without them.
+    #[default]
+    DisableHooks,
+    /// Terminate the process.
+    Terminate,
+    /// Stop the panicking thread with a console window showing the panic, so that a debugger can be attached.
+    Freeze,
+}
+
+impl FromStr for PanicPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disable-hooks" => Ok(Self::DisableHooks),
+            "terminate" => Ok(Self::Terminate),
+            "freeze" => Ok(Self::Freeze),
+            _ => Err(format!(r#"Unknown panic policy "{}""#, s)),
+        }
+    }
+}
+
+impl fmt::Display for PanicPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DisableHooks => write!(f, "disable-hooks"),
+            Self::Terminate => write!(f, "terminate"),
+            Self::Freeze => write!(f, "freeze"),
+        }
+    }
+}
+
 // TODO: Validate mappings. eg. `from` should always be a directory unless `kind` is `Redirect`, in which case `from`
 //       and `to` should point to the same kind of file system resource.
-#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+/// The mappings a path goes through, in order.
+///
+/// The list can't be changed once made, since an index of it is built the first time a path is mapped. This is why
+/// the mappings are no longer a public field: a list is made with `Mappings::new` or `From<Vec<Mapping>>`, read with
+/// `as_slice` or `iter`, and taken apart with `into_vec`.
+#[derive(Clone, Default, Deserialize, Serialize)]
 pub struct Mappings {
-    pub mappings: Vec<Mapping>,
+    pub(crate) mappings: Vec<Mapping>,
+    #[serde(skip)]
+    pub(crate) index: OnceLock<MappingIndex>,
 }
 
 impl Mappings {
+    pub fn new(mappings: Vec<Mapping>) -> Self {
+        Self {
+            mappings,
+            index: OnceLock::new(),
+        }
+    }
+
+    pub fn as_slice(&self) -> &[Mapping] {
+        &self.mappings
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &Mapping> {
         self.mappings.iter()
     }
+
+    pub fn into_vec(self) -> Vec<Mapping> {
+        self.mappings
+    }
+}
+
+impl From<Vec<Mapping>> for Mappings {
+    fn from(mappings: Vec<Mapping>) -> Self {
+        Self::new(mappings)
+    }
+}
+
+impl fmt::Debug for Mappings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Mappings")
+            .field("mappings", &self.mappings)
+            .finish()
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -147,6 +347,12 @@ pub struct Mapping {
 pub enum MappingFrom {
     File(PathBuf),
     Folder(PathBuf),
+    /// The members of a zip archive, or only those below `prefix` inside it. Only valid for `Mount`.
+    Archive {
+        path: PathBuf,
+        #[serde(default)]
+        prefix: Option<PathBuf>,
+    },
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -162,6 +368,36 @@ pub enum MappingKind {
     Redirect,
     /// Virtually add a file or folder to a folder.
     Mount,
+    /// Apply the patch in `to` to the file `from`, which must have the given SHA-256 hash before patching.
+    Patch {
+        sha256: String,
+        /// Decided by the extension of the patch unless given: `ips`, `bps`, or `txt` for an offset list.
+        #[serde(default)]
+        format: Option<PatchFormat>,
+    },
+    /// Merge the configuration fragment in `to` over the file `from`, keeping whatever the fragment doesn't change.
+    Merge {
+        /// Decided by the extension of the fragment unless given: `json`, `toml`, or INI otherwise.
+        #[serde(default)]
+        format: Option<MergeFormat>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+    /// Lines of `<offset>: <hexadecimal bytes>`.
+    Offsets,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeFormat {
+    Ini,
+    Json,
+    Toml,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -182,8 +418,115 @@ pub enum LogLevel {
     Trace,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct ProcessSpawned {
     pub pid: u32,
     pub tid: u32,
+    /// The application name passed to `CreateProcess`, or the first token of `command_line` if there was none.
+    pub executable: String,
+    pub command_line: String,
+    /// Whether the payload suspended the main thread of the subprocess so that it can be hooked. Whoever decides not
+    /// to hook it after all must resume it.
+    pub suspended: bool,
+}
+
+/// Describes the process the payload was injected into.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ProcessInfo {
+    pub executable: PathBuf,
+    pub command_line: String,
+}
+
+/// A call made to the real function behind a hook.
+///
+/// Timestamps are in microseconds since the Unix epoch so that events from different processes can be laid out on
+/// the same timeline.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TraceEvent {
+    pub name: Cow<'static, str>,
+    pub tid: u32,
+    pub begin: u64,
+    pub end: u64,
+    /// Usually the path the hooked function was called with.
+    pub detail: String,
+}
+
+/// A panic inside the payload.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PanicReport {
+    pub message: String,
+    /// The source location of the panic, as `file:line:column`.
+    pub location: Option<String>,
+    pub thread: Option<String>,
+    pub backtrace: String,
+    pub policy: PanicPolicy,
+}
+
+/// Time spent in each hook since the payload was initialized.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HookProfile {
+    pub hooks: Vec<HookTimings>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct HookTimings {
+    pub name: Cow<'static, str>,
+    /// Everything the hook does, including resolving the path and calling the real function.
+    pub total: Histogram,
+    /// Resolving the path the hook was called with.
+    pub resolve: Histogram,
+    /// The call to the real function.
+    pub call: Histogram,
+}
+
+impl HookTimings {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name: name.into(),
+            total: Histogram::default(),
+            resolve: Histogram::default(),
+            call: Histogram::default(),
+        }
+    }
+}
+
+/// A histogram of durations with logarithmically sized buckets.
+///
+/// Bucket `n` counts durations of at least `2^n` and less than `2^(n + 1)` nanoseconds.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Histogram {
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_ns: u64,
+    pub max_ns: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, ns: u64) {
+        let bucket = 63 - ns.max(1).leading_zeros() as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum_ns = self.sum_ns.saturating_add(ns);
+        self.max_ns = self.max_ns.max(ns);
+    }
+
+    pub fn mean_ns(&self) -> u64 {
+        self.sum_ns.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// An upper bound for the `p`th percentile, where `p` is in the range `0.0..=1.0`.
+    pub fn percentile_ns(&self, p: f64) -> u64 {
+        let target = (self.count as f64 * p).ceil() as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target && seen != 0 {
+                return (2u64.saturating_pow(bucket as u32 + 1) - 1).min(self.max_ns);
+            }
+        }
+        self.max_ns
+    }
 }
@@ -0,0 +1,256 @@
+//! Deciding which subprocesses the payload is injected into.
+
+use std::{fmt, path::PathBuf};
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Which subprocesses of the hooked processes are hooked as well.
+///
+/// A subprocess is hooked if it doesn't exceed `max_depth`, doesn't match any of the `exclude` rules, and matches at
+/// least one of the `include` rules. An empty list of `include` rules matches every subprocess.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct SubprocessRules {
+    #[serde(default)]
+    pub include: Vec<Rule>,
+    #[serde(default)]
+    pub exclude: Vec<Rule>,
+    /// The deepest subprocess to hook, where the process the session started out with has a depth of 0.
+    #[serde(default)]
+    pub max_depth: Option<u32>,
+}
+
+impl SubprocessRules {
+    /// Decide whether to hook a subprocess which is about to be, or has just been, created.
+    ///
+    /// `executable` is the application name passed to `CreateProcess`, or the first token of `command_line` if there
+    /// was none.
+    pub fn evaluate(&self, executable: &str, command_line: &str, depth: u32) -> Verdict {
+        if let Some(max_depth) = self.max_depth {
+            if depth > max_depth {
+                return Verdict::skip(format!(
+                    "its depth of {} exceeds the maximum depth of {}",
+                    depth, max_depth
+                ));
+            }
+        }
+        if let Some(rule) = self
+            .exclude
+            .iter()
+            .find(|rule| rule.matches(executable, command_line))
+        {
+            return Verdict::skip(format!("it matches the exclude rule {}", rule));
+        }
+        if self.include.is_empty() {
+            return Verdict::hook("no include rules have been specified".to_owned());
+        }
+        match self
+            .include
+            .iter()
+            .find(|rule| rule.matches(executable, command_line))
+        {
+            Some(rule) => Verdict::hook(format!("it matches the include rule {}", rule)),
+            None => Verdict::skip("it matches none of the include rules".to_owned()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Rule {
+    /// Matches the file name of the executable against a glob, ignoring case. `*` matches any number of characters,
+    /// and `?` matches a single character.
+    Name { pattern: String },
+    /// Matches executables inside the given directory, ignoring case.
+    PathPrefix { path: PathBuf },
+    /// Matches the whole command line against a regular expression, which is compiled when the rule is deserialized.
+    CommandLine {
+        #[serde(with = "serde_regex")]
+        regex: Regex,
+    },
+}
+
+impl Rule {
+    pub fn matches(&self, executable: &str, command_line: &str) -> bool {
+        match self {
+            Self::Name { pattern } => glob_matches(pattern, file_name(executable)),
+            Self::PathPrefix { path } => {
+                let prefix = normalize(&path.to_string_lossy());
+                let executable = normalize(executable);
+                executable.starts_with(&prefix)
+                    && (prefix.ends_with('\\')
+                        || executable[prefix.len()..].starts_with('\\')
+                        || executable.len() == prefix.len())
+            }
+            Self::CommandLine { regex } => regex.is_match(command_line),
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Name { pattern } => write!(f, r#"name "{}""#, pattern),
+            Self::PathPrefix { path } => write!(f, r#"path prefix "{}""#, path.display()),
+            Self::CommandLine { regex } => write!(f, r#"command line "{}""#, regex),
+        }
+    }
+}
+
+/// Regular expressions are (de)serialized as their source.
+mod serde_regex {
+    use regex::Regex;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(regex: &Regex, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(regex.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Regex::new(&source).map_err(de::Error::custom)
+    }
+}
+
+/// Whether to hook a subprocess, and why.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Verdict {
+    pub hook: bool,
+    pub reason: String,
+}
+
+impl Verdict {
+    fn hook(reason: String) -> Self {
+        Self { hook: true, reason }
+    }
+
+    fn skip(reason: String) -> Self {
+        Self {
+            hook: false,
+            reason,
+        }
+    }
+}
+
+/// The executable named by a command line, which is either quoted or ends at the first whitespace.
+pub fn executable_from_command_line(command_line: &str) -> &str {
+    let command_line = command_line.trim_start();
+    match command_line.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next().unwrap_or(quoted),
+        None => command_line
+            .split(char::is_whitespace)
+            .next()
+            .unwrap_or(command_line),
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit(&['\\', '/'][..]).next().unwrap_or(path)
+}
+
+fn normalize(path: &str) -> String {
+    path.replace('/', "\\").to_lowercase()
+}
+
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` in the pattern, and the position in the text it was tried at.
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, tried)) => {
+                    p = star + 1;
+                    t = tried + 1;
+                    backtrack = Some((star, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use regex::Regex;
+
+    use super::{glob_matches, Rule, SubprocessRules};
+
+    #[test]
+    fn glob() {
+        assert!(glob_matches("*.exe", "Game.EXE"));
+        assert!(glob_matches("launcher?.exe", "launcher2.exe"));
+        assert!(glob_matches("*launch*er*", "GameLauncher.exe"));
+        assert!(!glob_matches("launcher?.exe", "launcher.exe"));
+        assert!(!glob_matches("*.exe", "game.exe.bak"));
+
+        let rule = Rule::Name {
+            pattern: "game*.exe".to_owned(),
+        };
+        assert!(rule.matches(r"C:\Games\GameX64.exe", ""));
+        assert!(rule.matches("/games/game.exe", ""));
+        assert!(!rule.matches(r"C:\Games\game.exe\launcher.exe", ""));
+    }
+
+    #[test]
+    fn path_prefix() {
+        let rule = Rule::PathPrefix {
+            path: PathBuf::from(r"C:\Games\Skyrim"),
+        };
+        assert!(rule.matches(r"c:\games\skyrim\skse.exe", ""));
+        assert!(rule.matches("C:/Games/Skyrim/bin/game.exe", ""));
+        assert!(rule.matches(r"C:\Games\Skyrim", ""));
+        assert!(!rule.matches(r"C:\Games\Skyrim Special Edition\game.exe", ""));
+
+        let rule = Rule::PathPrefix {
+            path: PathBuf::from(r"C:\Games\"),
+        };
+        assert!(rule.matches(r"C:\Games\game.exe", ""));
+    }
+
+    #[test]
+    fn command_line() {
+        let rule = Rule::CommandLine {
+            regex: Regex::new("--mode=(editor|server)").unwrap(),
+        };
+        assert!(rule.matches("game.exe", "game.exe --mode=server"));
+        assert!(!rule.matches("game.exe", "game.exe --mode=client"));
+    }
+
+    #[test]
+    fn regex_is_compiled_when_loaded() {
+        let rules: SubprocessRules = serde_json::from_str(
+            r#"{ "exclude": [{ "kind": "command_line", "regex": "crash.?handler" }] }"#,
+        )
+        .unwrap();
+        assert!(
+            !rules
+                .evaluate("game.exe", "game.exe --crashhandler", 1)
+                .hook
+        );
+        assert!(rules.evaluate("game.exe", "game.exe", 1).hook);
+        assert_eq!(
+            serde_json::to_string(&rules.exclude[0]).unwrap(),
+            r#"{"kind":"command_line","regex":"crash.?handler"}"#
+        );
+
+        let invalid = serde_json::from_str::<SubprocessRules>(
+            r#"{ "include": [{ "kind": "command_line", "regex": "(unclosed" }] }"#,
+        );
+        assert!(invalid.is_err());
+    }
+}
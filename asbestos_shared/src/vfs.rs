@@ -0,0 +1,406 @@
+//! Applying `Mappings` to paths, which every backend does the same way.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    ffi::OsString,
+    fmt,
+    fmt::Write,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    contents::Contents,
+    protocol::{Mapping, MappingFrom, MappingKind, MappingTo, Mappings},
+};
+
+/// Turn a 'virtual' path into a real one by applying every mapping in order.
+///
+/// `path` should be absolute and free of `.` and `..` components, since paths are compared component-wise. If
+/// `trace` is given, the path after each mapping which may have changed it is appended to it, one line per mapping.
+pub fn map_path<'a>(
+    path: Cow<'a, Path>,
+    mappings: &Mappings,
+    mut trace: Option<&mut String>,
+) -> Result<Cow<'a, Path>, InvalidMapping> {
+    let index = mappings.index();
+    let mut current_path = path;
+    let mut others = index.others.iter().copied().peekable();
+    let mut next = 0;
+
+    loop {
+        // Redirects from one file only apply to that file, so only those for the current path are looked at.
+        let redirect = index
+            .redirects
+            .get(current_path.as_ref())
+            .and_then(|redirects| redirects[redirects.partition_point(|&i| i < next)..].first())
+            .copied();
+        let i = match (redirect, others.peek().copied()) {
+            (Some(redirect), Some(other)) => redirect.min(other),
+            (Some(i), None) | (None, Some(i)) => i,
+            (None, None) => break,
+        };
+        if others.peek() == Some(&i) {
+            others.next();
+        }
+        next = i + 1;
+
+        current_path = apply_mapping(&mappings.mappings[i], current_path)?;
+
+        if let Some(trace) = trace.as_mut() {
+            write!(
+                trace,
+                r#"{}current_path = "{}""#,
+                "\n",
+                current_path.display()
+            )
+            .ok();
+        }
+    }
+
+    Ok(current_path)
+}
+
+fn apply_mapping<'a>(
+    mapping: &Mapping,
+    mut current_path: Cow<'a, Path>,
+) -> Result<Cow<'a, Path>, InvalidMapping> {
+    match mapping.kind {
+        MappingKind::Redirect => match (&mapping.from, &mapping.to) {
+            (MappingFrom::File(from), MappingTo::File(to)) => {
+                if &current_path == from {
+                    current_path = to.to_owned().into();
+                }
+            }
+            (MappingFrom::File(from), MappingTo::Folder(to)) => {
+                if current_path == *from {
+                    if let Some(name) = current_path.file_name() {
+                        current_path = to.join(name).into();
+                    } else {
+                        return Err(InvalidMapping);
+                    }
+                }
+            }
+            (MappingFrom::Folder(from), MappingTo::Folder(to)) => {
+                if current_path.ancestors().any(|anc| anc == from) {
+                    let relative = current_path.strip_prefix(from).unwrap();
+                    current_path = to.join(relative).into();
+                }
+            }
+            (MappingFrom::Folder(_), MappingTo::File(_)) | (MappingFrom::Archive { .. }, _) => {
+                return Err(InvalidMapping);
+            }
+        },
+        MappingKind::Mount => match (&mapping.from, &mapping.to) {
+            (MappingFrom::File(from), MappingTo::Folder(to)) => {
+                if current_path.file_name() == from.file_name() && current_path.parent() == Some(to)
+                {
+                    current_path = from.to_owned().into();
+                }
+            }
+            (MappingFrom::Folder(from), MappingTo::Folder(to)) => {
+                if current_path.ancestors().any(|anc| anc == to) {
+                    let relative = current_path.strip_prefix(to).unwrap();
+                    current_path = from.join(relative).into();
+                }
+            }
+            // The members of the archive appear below its own path, which `Contents` gives a real counterpart.
+            (MappingFrom::Archive { path, prefix }, MappingTo::Folder(to)) => {
+                if current_path.ancestors().any(|anc| anc == to) {
+                    let relative = current_path.strip_prefix(to).unwrap();
+                    let mut member = path.clone();
+                    if let Some(prefix) = prefix {
+                        member.push(prefix);
+                    }
+                    current_path = member.join(relative).into();
+                }
+            }
+            (MappingFrom::File(_), MappingTo::File(_))
+            | (MappingFrom::Folder(_), MappingTo::File(_))
+            | (MappingFrom::Archive { .. }, MappingTo::File(_)) => {
+                return Err(InvalidMapping);
+            }
+        },
+        // Patches and merges are applied by `Contents` to whatever the path ends up mapped to.
+        MappingKind::Patch { .. } | MappingKind::Merge { .. } => {
+            match (&mapping.from, &mapping.to) {
+                (MappingFrom::File(_), MappingTo::File(_)) => {}
+                _ => return Err(InvalidMapping),
+            }
+        }
+    }
+
+    Ok(current_path)
+}
+
+/// The mappings which `map_path` has to look at for every path, and the redirects from one file by the file, by their
+/// position in `Mappings`. A mod list compiles into a redirect per file, which would otherwise make mapping a path
+/// take as long as there are files.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MappingIndex {
+    redirects: HashMap<PathBuf, Vec<usize>>,
+    others: Vec<usize>,
+}
+
+impl Mappings {
+    pub(crate) fn index(&self) -> &MappingIndex {
+        self.index.get_or_init(|| {
+            let mut index = MappingIndex::default();
+            for (i, mapping) in self.mappings.iter().enumerate() {
+                match (&mapping.kind, &mapping.from) {
+                    (MappingKind::Redirect, MappingFrom::File(from)) => {
+                        index.redirects.entry(from.clone()).or_default().push(i)
+                    }
+                    _ => index.others.push(i),
+                }
+            }
+            index
+        })
+    }
+}
+
+/// The virtual path at which a mapping makes a file or folder appear, or `None` if the mapping is invalid or only
+/// changes the contents of a file.
+pub fn virtual_path(mapping: &Mapping) -> Option<PathBuf> {
+    match (&mapping.kind, &mapping.from, &mapping.to) {
+        (MappingKind::Redirect, MappingFrom::File(from), _)
+        | (MappingKind::Redirect, MappingFrom::Folder(from), MappingTo::Folder(_)) => {
+            Some(from.clone())
+        }
+        (MappingKind::Mount, MappingFrom::File(from), MappingTo::Folder(to)) => {
+            Some(to.join(from.file_name()?))
+        }
+        (MappingKind::Mount, MappingFrom::Folder(_), MappingTo::Folder(to))
+        | (MappingKind::Mount, MappingFrom::Archive { .. }, MappingTo::Folder(to)) => {
+            Some(to.clone())
+        }
+        _ => None,
+    }
+}
+
+/// The names of the entries in the virtual folder `dir`: those of the real folder it's mapped to, if any, and those
+/// which the mappings make appear in it.
+pub fn read_dir(
+    dir: &Path,
+    mappings: &Mappings,
+    contents: &Contents,
+) -> io::Result<BTreeSet<OsString>> {
+    let mapped = map_path(Cow::Borrowed(dir), mappings, None)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    let real = contents.outline_path(dir, mapped, mappings)?;
+    let mut names = BTreeSet::new();
+    match fs::read_dir(&real) {
+        Ok(entries) => {
+            for entry in entries {
+                names.insert(entry?.file_name());
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    for path in mappings.iter().filter_map(virtual_path) {
+        if let Ok(relative) = path.strip_prefix(dir) {
+            if let Some(Component::Normal(name)) = relative.components().next() {
+                names.insert(name.to_owned());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Whether the mappings make something appear below the virtual path `path`, which therefore has to be shown as a
+/// folder even if nothing exists there on disk.
+pub fn contains_mapped_paths(path: &Path, mappings: &Mappings) -> bool {
+    mappings
+        .iter()
+        .filter_map(virtual_path)
+        .any(|mapped| mapped != path && mapped.starts_with(path))
+}
+
+/// A mapping combines a file with a folder in a way which doesn't make sense for its kind.
+#[derive(Debug)]
+pub struct InvalidMapping;
+
+impl fmt::Display for InvalidMapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid VFS mapping")
+    }
+}
+
+impl Error for InvalidMapping {}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        path::{Path, PathBuf},
+    };
+
+    use crate::protocol::{Mapping, MappingFrom, MappingKind, MappingTo, Mappings};
+
+    use super::map_path;
+
+    fn mappings(mappings: Vec<(MappingKind, MappingFrom, MappingTo)>) -> Mappings {
+        Mappings::new(
+            mappings
+                .into_iter()
+                .map(|(kind, from, to)| Mapping { kind, from, to })
+                .collect(),
+        )
+    }
+
+    fn mapped(path: &str, mappings: &Mappings) -> PathBuf {
+        map_path(Cow::Borrowed(Path::new(path)), mappings, None)
+            .unwrap()
+            .into_owned()
+    }
+
+    #[test]
+    fn redirect() {
+        let mappings = mappings(vec![
+            (
+                MappingKind::Redirect,
+                MappingFrom::File("/game/a.ini".into()),
+                MappingTo::File("/mods/b.ini".into()),
+            ),
+            (
+                MappingKind::Redirect,
+                MappingFrom::File("/game/c.ini".into()),
+                MappingTo::Folder("/mods/ini".into()),
+            ),
+            (
+                MappingKind::Redirect,
+                MappingFrom::Folder("/game/Saves".into()),
+                MappingTo::Folder("/home/saves".into()),
+            ),
+        ]);
+        assert_eq!(mapped("/game/a.ini", &mappings), Path::new("/mods/b.ini"));
+        assert_eq!(
+            mapped("/game/c.ini", &mappings),
+            Path::new("/mods/ini/c.ini")
+        );
+        assert_eq!(mapped("/game/Saves", &mappings), Path::new("/home/saves"));
+        assert_eq!(
+            mapped("/game/Saves/1/a.sav", &mappings),
+            Path::new("/home/saves/1/a.sav")
+        );
+        // Paths are compared component-wise.
+        assert_eq!(
+            mapped("/game/Saves2/a.sav", &mappings),
+            Path::new("/game/Saves2/a.sav")
+        );
+        assert_eq!(
+            mapped("/game/a.ini.bak", &mappings),
+            Path::new("/game/a.ini.bak")
+        );
+    }
+
+    #[test]
+    fn mount() {
+        let mappings = mappings(vec![
+            (
+                MappingKind::Mount,
+                MappingFrom::File("/mods/b/b.esp".into()),
+                MappingTo::Folder("/game/Data".into()),
+            ),
+            (
+                MappingKind::Mount,
+                MappingFrom::Folder("/mods/a".into()),
+                MappingTo::Folder("/game/Data".into()),
+            ),
+            (
+                MappingKind::Mount,
+                MappingFrom::Archive {
+                    path: "/mods/c.zip".into(),
+                    prefix: Some("Data".into()),
+                },
+                MappingTo::Folder("/game/Textures".into()),
+            ),
+        ]);
+        assert_eq!(
+            mapped("/game/Data/a.esp", &mappings),
+            Path::new("/mods/a/a.esp")
+        );
+        // The first mapping which applies wins, since the path it produces doesn't match the others.
+        assert_eq!(
+            mapped("/game/Data/b.esp", &mappings),
+            Path::new("/mods/b/b.esp")
+        );
+        assert_eq!(
+            mapped("/game/Textures/sky.dds", &mappings),
+            Path::new("/mods/c.zip/Data/sky.dds")
+        );
+        assert_eq!(
+            mapped("/game/game.exe", &mappings),
+            Path::new("/game/game.exe")
+        );
+    }
+
+    #[test]
+    fn mappings_apply_in_order() {
+        // The second mapping sees the path the first one produced.
+        let mappings = mappings(vec![
+            (
+                MappingKind::Redirect,
+                MappingFrom::File("/game/a.ini".into()),
+                MappingTo::File("/staging/a.ini".into()),
+            ),
+            (
+                MappingKind::Redirect,
+                MappingFrom::Folder("/staging".into()),
+                MappingTo::Folder("/mods".into()),
+            ),
+        ]);
+        assert_eq!(mapped("/game/a.ini", &mappings), Path::new("/mods/a.ini"));
+
+        let mut trace = String::new();
+        map_path(
+            Cow::Borrowed(Path::new("/game/a.ini")),
+            &mappings,
+            Some(&mut trace),
+        )
+        .unwrap();
+        assert_eq!(
+            trace,
+            "\ncurrent_path = \"/staging/a.ini\"\ncurrent_path = \"/mods/a.ini\""
+        );
+    }
+
+    #[test]
+    fn redirects_from_files_apply_in_order() {
+        let redirect = |from: &str, to: &str| {
+            (
+                MappingKind::Redirect,
+                MappingFrom::File(from.into()),
+                MappingTo::File(to.into()),
+            )
+        };
+        let mappings = mappings(vec![
+            redirect("/b", "/c"),
+            redirect("/a", "/b"),
+            (
+                MappingKind::Redirect,
+                MappingFrom::Folder("/x".into()),
+                MappingTo::Folder("/y".into()),
+            ),
+            redirect("/b", "/x/b"),
+            redirect("/y/b", "/d"),
+        ]);
+        // Only the redirects which come after the one producing a path apply to it.
+        assert_eq!(mapped("/a", &mappings), Path::new("/x/b"));
+        assert_eq!(mapped("/b", &mappings), Path::new("/c"));
+        assert_eq!(mapped("/x/b", &mappings), Path::new("/d"));
+    }
+
+    #[test]
+    fn invalid_mapping() {
+        let mappings = mappings(vec![(
+            MappingKind::Redirect,
+            MappingFrom::Folder("/game/Data".into()),
+            MappingTo::File("/mods/a.esp".into()),
+        )]);
+        assert!(map_path(Cow::Borrowed(Path::new("/game")), &mappings, None).is_err());
+    }
+}